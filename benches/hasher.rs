@@ -0,0 +1,255 @@
+//! Compares ways of hashing `prefix + nonce` in the solver's hot loop.
+//!
+//! Run with `cargo bench --bench hasher`.
+//!
+//! Baseline (x86_64 with SHA-NI, sha2 0.10.9, target-cpu=native), Melem/s:
+//!
+//! | strategy         | 32 char prefix | 64 char prefix | 128 char prefix |
+//! |------------------|----------------|----------------|-----------------|
+//! | `finalize_reset` | 11.2           | 9.3            | 5.5             |
+//! | `fresh_hasher`   | 8.9            | 7.7            | 5.7             |
+//! | `primed_clone`   | 10.5           | 10.3           | 9.5             |
+//! | `midstate`       | 13.7           | 12.5           | 12.4            |
+//!
+//! The solver uses `midstate` (`Sha256Midstate`): every full block of the
+//! prefix is compressed once, and each attempt compresses only the final
+//! block(s) without the `Digest` buffering that `primed_clone` still pays for.
+//! It wins even for prefixes shorter than a block, so there is no fallback.
+//! If another strategy overtakes it on a newer `sha2`, switch the solver over.
+//!
+//! With `--features simd`, `midstate_x8` hashes eight nonces per AVX2
+//! compression, and `search_difficulty_5` times a whole search both ways (same
+//! machine, Melem/s and ms per search):
+//!
+//! | strategy      | SHA backend | 32 char | 64 char | 128 char | difficulty 5 |
+//! |---------------|-------------|---------|---------|----------|--------------|
+//! | `midstate`    | SHA-NI      | 13.8    | 13.3    | 12.9     | 128          |
+//! | `midstate`    | software    | 4.0     | 4.2     | 3.8      | 412          |
+//! | `midstate_x8` | AVX2        | 13.7    | 13.4    | 11.6     | 127          |
+//!
+//! (`software` is `sha2`'s `force-soft` feature, standing in for CPUs without
+//! SHA extensions.) Eight lanes are about 3.4x the software scalar path but no
+//! faster than SHA-NI, so the solver only uses them without SHA extensions.
+//!
+//! Set `COUNT_LEADING_ZEROS=1` to also print how many `midstate` hashes have at
+//! least N leading zero nibbles. SHA-256 output is uniform, so about 1/16^N of
+//! them should; a clear deviation points at a bug in the block construction or
+//! the difficulty check.
+
+use anubis_solver::Sha256Midstate;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sha2::{Digest, Sha256};
+
+/// Old-format challenges are a 64 char hex digest, new-format `randomData`
+/// is 64 random bytes hex encoded (128 chars). 32 chars covers forks with
+/// prefixes shorter than one block.
+const PREFIXES: &[(&str, usize)] = &[("32", 32), ("64", 64), ("128", 128)];
+const BATCH: u64 = 1024;
+/// Hashes counted for the leading zero histogram; enough for N <= 4 to have
+/// a few hundred samples.
+const HISTOGRAM_HASHES: u64 = 1 << 24;
+
+fn prefix(len: usize) -> Vec<u8> {
+    b"0123456789abcdef"
+        .iter()
+        .cycle()
+        .take(len)
+        .copied()
+        .collect()
+}
+
+fn bench_strategies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash_prefix_nonce");
+    group.throughput(Throughput::Elements(BATCH));
+
+    for &(name, len) in PREFIXES {
+        let data = prefix(len);
+
+        group.bench_with_input(
+            BenchmarkId::new("finalize_reset", name),
+            &data,
+            |b, data| {
+                let mut hasher = Sha256::new();
+                let mut buffer = Vec::with_capacity(data.len() + 20);
+                buffer.extend_from_slice(data);
+                let mut itoa_buf = itoa::Buffer::new();
+                let mut nonce = 0u64;
+                b.iter(|| {
+                    for _ in 0..BATCH {
+                        buffer.truncate(data.len());
+                        buffer.extend_from_slice(itoa_buf.format(nonce).as_bytes());
+                        hasher.update(&buffer);
+                        black_box(hasher.finalize_reset());
+                        nonce += 1;
+                    }
+                });
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("fresh_hasher", name), &data, |b, data| {
+            let mut buffer = Vec::with_capacity(data.len() + 20);
+            buffer.extend_from_slice(data);
+            let mut itoa_buf = itoa::Buffer::new();
+            let mut nonce = 0u64;
+            b.iter(|| {
+                for _ in 0..BATCH {
+                    buffer.truncate(data.len());
+                    buffer.extend_from_slice(itoa_buf.format(nonce).as_bytes());
+                    black_box(Sha256::digest(&buffer));
+                    nonce += 1;
+                }
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("primed_clone", name), &data, |b, data| {
+            let primed = Sha256::new_with_prefix(data);
+            let mut itoa_buf = itoa::Buffer::new();
+            let mut nonce = 0u64;
+            b.iter(|| {
+                for _ in 0..BATCH {
+                    let mut hasher = primed.clone();
+                    hasher.update(itoa_buf.format(nonce).as_bytes());
+                    black_box(hasher.finalize());
+                    nonce += 1;
+                }
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("midstate", name), &data, |b, data| {
+            let midstate = Sha256Midstate::new(data);
+            let mut itoa_buf = itoa::Buffer::new();
+            let mut nonce = 0u64;
+            b.iter(|| {
+                for _ in 0..BATCH {
+                    black_box(midstate.hash(itoa_buf.format(nonce).as_bytes()));
+                    nonce += 1;
+                }
+            });
+        });
+
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        group.bench_with_input(BenchmarkId::new("midstate_x8", name), &data, |b, data| {
+            use anubis_solver::simd::LANES;
+            let midstate = Sha256Midstate::new(data);
+            let mut itoa_bufs: [itoa::Buffer; LANES] = Default::default();
+            let mut nonce = 0u64;
+            b.iter(|| {
+                for _ in 0..BATCH / LANES as u64 {
+                    let mut lanes = itoa_bufs.iter_mut().zip(nonce..);
+                    let suffixes = [(); LANES].map(|_| {
+                        let (buf, nonce) = lanes.next().unwrap();
+                        buf.format(nonce).as_bytes()
+                    });
+                    black_box(midstate.hash_x8(suffixes));
+                    nonce += LANES as u64;
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+/// Counts leading zero nibbles the way `check_difficulty_fast` reads them.
+fn leading_zero_nibbles(hash: &[u8]) -> usize {
+    let mut zeros = 0;
+    for &byte in hash {
+        if byte == 0 {
+            zeros += 2;
+            continue;
+        }
+        if byte >> 4 == 0 {
+            zeros += 1;
+        }
+        break;
+    }
+    zeros
+}
+
+fn leading_zero_histogram(_: &mut Criterion) {
+    if std::env::var_os("COUNT_LEADING_ZEROS").is_none() {
+        return;
+    }
+
+    let midstate = Sha256Midstate::new(&prefix(128));
+    let mut itoa_buf = itoa::Buffer::new();
+    let mut counts = [0u64; 65];
+    for nonce in 0..HISTOGRAM_HASHES {
+        let hash = midstate.hash(itoa_buf.format(nonce).as_bytes());
+        counts[leading_zero_nibbles(&hash)] += 1;
+    }
+
+    println!(
+        "Leading zero nibbles over {} midstate hashes:",
+        HISTOGRAM_HASHES
+    );
+    println!(
+        "{:>4} {:>10} {:>12} {:>12}",
+        ">=N", "hashes", "observed", "expected"
+    );
+    for n in 1..counts.len() {
+        let at_least: u64 = counts[n..].iter().sum();
+        if at_least == 0 {
+            break;
+        }
+        println!(
+            "{:>4} {:>10} {:>12.3e} {:>12.3e}",
+            n,
+            at_least,
+            at_least as f64 / HISTOGRAM_HASHES as f64,
+            16f64.powi(-(n as i32))
+        );
+    }
+}
+
+/// Searches for the first nonce with 5 leading zero nibbles after a 64 char
+/// prefix, about 1.7M hashes, one and eight at a time.
+fn bench_search(c: &mut Criterion) {
+    let midstate = Sha256Midstate::new(&prefix(64));
+    let mut group = c.benchmark_group("search_difficulty_5");
+    group.sample_size(10);
+
+    group.bench_function("midstate", |b| {
+        let mut itoa_buf = itoa::Buffer::new();
+        b.iter(|| {
+            (0u64..)
+                .find(|&nonce| {
+                    leading_zero_nibbles(&midstate.hash(itoa_buf.format(nonce).as_bytes())) >= 5
+                })
+                .unwrap()
+        });
+    });
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    group.bench_function("midstate_x8", |b| {
+        use anubis_solver::simd::LANES;
+        let mut itoa_bufs: [itoa::Buffer; LANES] = Default::default();
+        b.iter(|| {
+            (0u64..)
+                .step_by(LANES)
+                .find_map(|first| {
+                    let mut lanes = itoa_bufs.iter_mut().zip(first..);
+                    let suffixes = [(); LANES].map(|_| {
+                        let (buf, nonce) = lanes.next().unwrap();
+                        buf.format(nonce).as_bytes()
+                    });
+                    midstate
+                        .hash_x8(suffixes)
+                        .iter()
+                        .position(|hash| leading_zero_nibbles(hash) >= 5)
+                        .map(|lane| first + lane as u64)
+                })
+                .unwrap()
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_strategies,
+    bench_search,
+    leading_zero_histogram
+);
+criterion_main!(benches);
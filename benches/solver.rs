@@ -0,0 +1,151 @@
+//! Regression baseline for the solver itself, next to `hasher.rs`'s
+//! comparison of hashing strategies.
+//!
+//! Run with `cargo bench --bench solver`, and again on a branch to compare:
+//! criterion reports the change against the previous run.
+//!
+//! - `search_loop`: hashes/s of the parallel solver's batch loop on one
+//!   thread, over a fixed number of nonces of a challenge no nonce solves,
+//!   with the default midstate and through `solve_challenge_native_with_hasher`
+//!   with `Sha2Hasher` and (with `--features openssl`) `OpensslHasher`.
+//! - `solve_sequential`: whole `solve_challenge_sequential` solves at
+//!   difficulties 3 to 5. The challenge is fixed, so each solve hashes the
+//!   same nonces every run.
+//! - `check_difficulty`: the leading zero check alone, at even and odd
+//!   difficulties (odd ones also look at half a byte).
+//!
+//! Baseline (x86_64 with SHA-NI, one core):
+//!
+//! | benchmark            | result       |
+//! |----------------------|--------------|
+//! | `search_loop`        | 10.4 Melem/s |
+//! | `solve_sequential/3` | 0.36 ms      |
+//! | `solve_sequential/4` | 9.9 ms       |
+//! | `solve_sequential/5` | 63 ms        |
+//! | `check_difficulty/1` | 450 Melem/s  |
+//! | `check_difficulty/2` | 600 Melem/s  |
+//! | `check_difficulty/5` | 770 Melem/s  |
+//! | `check_difficulty/6` | 840 Melem/s  |
+//!
+//! `search_loop` by backend, from one run on the same machine:
+//!
+//! | benchmark        | Melem/s |
+//! |------------------|---------|
+//! | `one_thread`     | 11.8    |
+//! | `sha2_hasher`    | 8.5     |
+//! | `openssl_hasher` | 7.2     |
+//!
+//! A `Hasher` rehashes the whole prefix per nonce, so on this machine both
+//! backends trail the midstate. OpenSSL is meant for CPUs where `sha2` has no
+//! hardware path, such as aarch64 (see `sha_hardware_accelerated`); compare
+//! `openssl_hasher` with `one_thread` there before switching.
+//!
+//! Lower difficulties measure slower because more hashes pass, which makes
+//! the branch on the result harder to predict.
+
+use anubis_solver::{
+    solve_challenge_native_with_hasher, solve_challenge_native_with_options,
+    solve_challenge_sequential, AnubisChallenge, DifficultyUnit, Sha256Midstate, Sha2Hasher,
+    SolveOptions,
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::Arc;
+
+/// 64 hex chars, the length of upstream's `randomData` digests.
+const CHALLENGE: &str = "5f1c0e8a3b9d47f2a6c4e1b07d3f9a2c8e6b4d1f0a7c3e5b9d2f4a6c8e0b1d3f";
+const SEARCH_NONCES: u64 = 1 << 20;
+const CHECKED_HASHES: usize = 1024;
+
+fn challenge(difficulty: usize) -> AnubisChallenge {
+    serde_json::from_str(&format!(
+        r#"{{"challenge":"{}","rules":{{"difficulty":{}}}}}"#,
+        CHALLENGE, difficulty
+    ))
+    .unwrap()
+}
+
+fn bench_search_loop(c: &mut Criterion) {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let options = SolveOptions {
+        thread_pool: Some(Arc::new(pool)),
+        max_attempts: Some(SEARCH_NONCES),
+        ..SolveOptions::default()
+    };
+    let challenge = challenge(64);
+
+    let mut group = c.benchmark_group("search_loop");
+    group.throughput(Throughput::Elements(SEARCH_NONCES));
+    group.sample_size(20);
+    group.bench_function("one_thread", |b| {
+        b.iter(|| {
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap_err()
+        })
+    });
+    group.bench_function("sha2_hasher", |b| {
+        b.iter(|| {
+            solve_challenge_native_with_hasher::<Sha2Hasher, fn(u64)>(&challenge, None, &options)
+                .unwrap_err()
+        })
+    });
+    #[cfg(feature = "openssl")]
+    group.bench_function("openssl_hasher", |b| {
+        b.iter(|| {
+            solve_challenge_native_with_hasher::<anubis_solver::OpensslHasher, fn(u64)>(
+                &challenge, None, &options,
+            )
+            .unwrap_err()
+        })
+    });
+    group.finish();
+}
+
+fn bench_solve_sequential(c: &mut Criterion) {
+    let mut group = c.benchmark_group("solve_sequential");
+    group.sample_size(10);
+    for difficulty in [3, 4, 5] {
+        let challenge = challenge(difficulty);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(difficulty),
+            &challenge,
+            |b, challenge| b.iter(|| solve_challenge_sequential(black_box(challenge)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bench_check_difficulty(c: &mut Criterion) {
+    let midstate = Sha256Midstate::new(CHALLENGE.as_bytes());
+    let mut itoa_buf = itoa::Buffer::new();
+    let hashes: Vec<[u8; 32]> = (0..CHECKED_HASHES as u64)
+        .map(|nonce| midstate.hash(itoa_buf.format(nonce).as_bytes()))
+        .collect();
+
+    let mut group = c.benchmark_group("check_difficulty");
+    group.throughput(Throughput::Elements(CHECKED_HASHES as u64));
+    for difficulty in [1, 2, 3, 4, 5, 6] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(difficulty),
+            &difficulty,
+            |b, &difficulty| {
+                b.iter(|| {
+                    hashes
+                        .iter()
+                        .filter(|hash| DifficultyUnit::Nibbles.check(black_box(*hash), difficulty))
+                        .count()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_search_loop,
+    bench_solve_sequential,
+    bench_check_difficulty
+);
+criterion_main!(benches);
@@ -1,225 +1,4127 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
 use bytes::Bytes;
 use dashmap::DashMap;
-use http_body_util::Full;
+use futures_util::future::BoxFuture;
+use futures_util::TryStreamExt;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited, StreamBody};
+use hyper::body::Frame;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use tokio::net::TcpListener;
 
+use anubis_solver::browser_headers::navigation_headers;
+use anubis_solver::{
+    build_submission_url_with_options, cpu_has_sha_extensions, expected_solve_time,
+    is_duplicate_submission, measure_hashrate, sha_hardware_accelerated,
+    solve_challenge_with_options, supported_algorithms,
+    try_parse_challenge_from_html_with_selectors, verify_solution_with_options, AnswerFormat,
+    AnubisChallenge, ChallengeSelectors, ConcatOrder, DifficultyHistory, NonceFormat, SolveOptions,
+    SolverResult, SubmissionOptions,
+};
 use fake_user_agent::get_chrome_rua;
+use reqwest::cookie::CookieStore;
 use reqwest::header::HeaderMap;
-use anubis_solver::{build_submission_url, parse_challenge_from_html, solve_challenge};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
-type CookieJarCache = Arc<DashMap<String, Arc<reqwest::cookie::Jar>>>;
+type SessionCache = Arc<DashMap<String, HostSession>>;
+type ProxyBody = UnsyncBoxBody<Bytes, Box<dyn std::error::Error + Send + Sync>>;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let port: u16 = std::env::var("PORT")
-        .unwrap_or_else(|_| "8192".into())
-        .parse()
-        .unwrap_or(8192);
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    println!("Anubis proxy listening on http://{}", addr);
-    println!("Usage: GET /proxy/<host>/<path>");
+/// Limits on upstream requests.
+#[derive(Debug, Clone, Copy)]
+struct UpstreamTimeouts {
+    /// For establishing a connection, proxy handshake included.
+    connect: Duration,
+    /// For the response and its body, from sending the request. Event streams
+    /// are only bounded until their headers arrive.
+    read: Duration,
+}
 
-    let jars: CookieJarCache = Arc::new(DashMap::new());
+impl Default for UpstreamTimeouts {
+    fn default() -> Self {
+        UpstreamTimeouts {
+            connect: DEFAULT_UPSTREAM_TIMEOUT,
+            read: DEFAULT_UPSTREAM_TIMEOUT,
+        }
+    }
+}
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        let jars = jars.clone();
+/// Caps how many challenges are solved at once (`MAX_CONCURRENT_SOLVES`), so
+/// simultaneous solves don't starve each other and the event loop. Solves
+/// past the limit queue for a permit.
+struct SolveLimit {
+    permits: Arc<tokio::sync::Semaphore>,
+    limit: usize,
+    /// How long a solve may queue before the client gets a 503
+    /// (`SOLVE_QUEUE_TIMEOUT`); unbounded if unset.
+    queue_timeout: Option<Duration>,
+}
 
-        tokio::spawn(async move {
-            if let Err(e) = http1::Builder::new()
-                .serve_connection(io, service_fn(|req| handle_request(req, jars.clone())))
+impl SolveLimit {
+    fn new(limit: usize, queue_timeout: Option<Duration>) -> Self {
+        SolveLimit {
+            permits: Arc::new(tokio::sync::Semaphore::new(limit)),
+            limit,
+            queue_timeout,
+        }
+    }
+
+    /// Waits for a permit to solve `host`'s challenge, held by the blocking
+    /// task that solves it until it is done.
+    async fn acquire(
+        &self,
+        host: &str,
+    ) -> Result<tokio::sync::OwnedSemaphorePermit, SolveQueueTimeout> {
+        if self.permits.available_permits() == 0 {
+            info!(
+                "Queueing solve for {}: {}/{} solves in flight",
+                host, self.limit, self.limit
+            );
+        }
+        let acquire = self.permits.clone().acquire_owned();
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire)
                 .await
-            {
-                eprintln!("Connection error: {}", e);
-            }
-        });
+                .map_err(|_| SolveQueueTimeout(timeout))?,
+            None => acquire.await,
+        }
+        .expect("the solve semaphore is never closed");
+        info!(
+            "Solving for {}: {}/{} solves in flight",
+            host,
+            self.limit - self.permits.available_permits(),
+            self.limit
+        );
+        Ok(permit)
     }
 }
 
-async fn handle_request(
-    req: Request<hyper::body::Incoming>,
-    jars: CookieJarCache,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let path = req.uri().path();
+/// A solve waited longer than `SOLVE_QUEUE_TIMEOUT` for a permit; the client
+/// gets a 503.
+#[derive(Debug)]
+struct SolveQueueTimeout(Duration);
 
-    if path == "/health" {
-        return Ok(Response::new(Full::new(Bytes::from("ok"))));
+impl std::fmt::Display for SolveQueueTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no solve slot free after {:?}, MAX_CONCURRENT_SOLVES reached",
+            self.0
+        )
     }
+}
 
-    if !path.starts_with("/proxy/") {
-        return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Full::new(Bytes::from(
-                "Usage: /proxy/<host>/<path>\nExample: /proxy/clew.se/search?q=test",
-            )))
-            .unwrap());
+impl std::error::Error for SolveQueueTimeout {}
+
+/// Upstream response body, either read in full or relayed as it arrives.
+enum UpstreamBody {
+    Buffered(Vec<u8>),
+    /// `text/event-stream` responses never finish, so they are streamed
+    /// to the client without a timeout for as long as upstream keeps them open.
+    Streaming(reqwest::Response),
+}
+
+type UpstreamResponse = (StatusCode, HeaderMap, UpstreamBody);
+
+/// Per-host state kept across requests. The client owns the host's cookie jar,
+/// and its connection pool lets later fetches, submissions and refetches skip
+/// the TCP and TLS handshakes.
+#[derive(Clone)]
+struct HostSession {
+    client: reqwest::Client,
+    /// Shared with `client`, for the HTTP/1.1-only clients WebSocket upgrades need.
+    jar: Arc<HostJar>,
+    /// Challenge difficulties served to this host's session, to spot escalation.
+    difficulties: Arc<std::sync::Mutex<DifficultyHistory>>,
+    /// Set once a challenge for this host has been passed, so later
+    /// challenge-free responses are known to ride on the solved session.
+    passed: Arc<AtomicBool>,
+    /// Sent with every request for this host, so the session keeps one
+    /// browser identity.
+    user_agent: Arc<str>,
+    /// Algorithm of the last challenge this host served.
+    algorithm: Arc<std::sync::Mutex<Option<String>>>,
+    /// Set while the host is proxied over HTTPS. Its cookies are saved and
+    /// restored against a URL of the same scheme, so `Secure` cookies stay
+    /// with HTTPS upstreams and plain-HTTP ones keep being sent.
+    https: Arc<AtomicBool>,
+}
+
+impl HostSession {
+    fn scheme(&self) -> &'static str {
+        if self.https.load(Ordering::Relaxed) {
+            "https"
+        } else {
+            "http"
+        }
     }
 
-    let rest = &path[7..];
-    let (host, target_path) = match rest.find('/') {
-        Some(i) => (&rest[..i], &rest[i..]),
-        None => (rest, "/"),
-    };
+    fn state(&self) -> HostState {
+        HostState {
+            scheme: self.scheme().to_string(),
+            cookies: self.jar.live_cookies(),
+            user_agent: self.user_agent.to_string(),
+            difficulty: self.difficulties.lock().unwrap().clone(),
+            algorithm: self.algorithm.lock().unwrap().clone(),
+            passed: self.passed.load(Ordering::Relaxed),
+        }
+    }
+}
 
-    if host.is_empty() {
-        return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Full::new(Bytes::from("Missing host in path")))
-            .unwrap());
+/// A host's cookie jar. Also keeps the attributes of every cookie set in it,
+/// which reqwest's `Jar` only ever hands back as a `Cookie` header, so
+/// `COOKIE_DIR` can save them with their expiry.
+#[derive(Debug, Default)]
+struct HostJar {
+    jar: reqwest::cookie::Jar,
+    /// Cookies by domain, path and name, as last set.
+    stored: std::sync::Mutex<BTreeMap<(String, String, String), StoredCookie>>,
+}
+
+impl HostJar {
+    fn add_cookie_str(&self, cookie: &str, url: &reqwest::Url) {
+        self.record(cookie, url);
+        self.jar.add_cookie_str(cookie, url);
     }
 
-    let query = req
-        .uri()
-        .query()
-        .map(|q| format!("?{}", q))
-        .unwrap_or_default();
-    let target_url = format!("https://{}{}{}", host, target_path, query);
+    fn record(&self, set_cookie: &str, url: &reqwest::Url) {
+        let Ok(cookie) = cookie::Cookie::parse(set_cookie) else {
+            return;
+        };
+        let now = unix_now();
+        // Max-Age wins over Expires, as in browsers.
+        let expires = match (cookie.max_age(), cookie.expires_datetime()) {
+            (Some(max_age), _) => Some(now.saturating_add_signed(max_age.whole_seconds())),
+            (None, Some(at)) => Some(at.unix_timestamp().max(0) as u64),
+            (None, None) => None,
+        };
+        let stored = StoredCookie {
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+            domain: cookie
+                .domain()
+                .map(|domain| domain.trim_start_matches('.').to_ascii_lowercase()),
+            path: cookie
+                .path()
+                .map_or_else(|| default_cookie_path(url), str::to_string),
+            expires,
+        };
+        let key = (
+            stored
+                .domain
+                .clone()
+                .unwrap_or_else(|| url.host_str().unwrap_or_default().to_string()),
+            stored.path.clone(),
+            stored.name.clone(),
+        );
 
-    println!("Proxying: {} {}", req.method(), target_url);
+        let mut cookies = self.stored.lock().unwrap();
+        // Servers delete cookies by setting them already expired.
+        if stored.expired(now) {
+            cookies.remove(&key);
+        } else {
+            cookies.insert(key, stored);
+        }
+    }
 
-    match proxy_request(req.method().clone(), &target_url, host, jars).await {
-        Ok((status, headers, body)) => {
-            let mut builder = Response::builder().status(status);
-            for (key, value) in headers {
-                if let Some(name) = key {
-                    let name_str = name.as_str().to_lowercase();
-                    if name_str != "transfer-encoding" && name_str != "connection" {
-                        builder = builder.header(name, value);
-                    }
-                }
+    /// Cookies that haven't expired yet.
+    fn live_cookies(&self) -> Vec<StoredCookie> {
+        let now = unix_now();
+        self.stored
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|cookie| !cookie.expired(now))
+            .cloned()
+            .collect()
+    }
+}
+
+impl CookieStore for HostJar {
+    fn set_cookies(
+        &self,
+        cookie_headers: &mut dyn Iterator<Item = &reqwest::header::HeaderValue>,
+        url: &reqwest::Url,
+    ) {
+        let cookie_headers: Vec<_> = cookie_headers.collect();
+        for header in &cookie_headers {
+            if let Ok(set_cookie) = header.to_str() {
+                self.record(set_cookie, url);
             }
-            Ok(builder.body(Full::new(Bytes::from(body))).unwrap())
         }
-        Err(e) => {
-            eprintln!("Proxy error: {}", e);
-            Ok(Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!("Proxy error: {}", e))))
-                .unwrap())
+        self.jar.set_cookies(&mut cookie_headers.into_iter(), url);
+    }
+
+    fn cookies(&self, url: &reqwest::Url) -> Option<reqwest::header::HeaderValue> {
+        self.jar.cookies(url)
+    }
+}
+
+/// The path a cookie without a `Path` attribute applies to: the request
+/// path up to its last `/` (RFC 6265, section 5.1.4).
+fn default_cookie_path(url: &reqwest::Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(end) => url.path()[..end].to_string(),
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// A cookie as `COOKIE_DIR` and `SNAPSHOT_FILE` save it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct StoredCookie {
+    name: String,
+    value: String,
+    /// The `Domain` attribute; `None` for cookies only sent to the host that
+    /// set them.
+    domain: Option<String>,
+    path: String,
+    /// Unix time the cookie expires at; `None` for session cookies.
+    expires: Option<u64>,
+}
+
+impl StoredCookie {
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|at| at <= now)
+    }
+
+    /// As a `Set-Cookie` value, to add back to a jar.
+    fn to_set_cookie(&self, now: u64) -> String {
+        let mut set_cookie = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if let Some(domain) = &self.domain {
+            set_cookie.push_str(&format!("; Domain={}", domain));
         }
+        if let Some(at) = self.expires {
+            set_cookie.push_str(&format!("; Max-Age={}", at.saturating_sub(now)));
+        }
+        set_cookie
     }
 }
 
-fn get_or_create_jar(jars: &CookieJarCache, host: &str) -> Arc<reqwest::cookie::Jar> {
-    jars.entry(host.to_string())
-        .or_insert_with(|| Arc::new(reqwest::cookie::Jar::default()))
-        .clone()
+/// What `COOKIE_DIR` keeps for one host.
+#[derive(Debug, Serialize, Deserialize)]
+struct CookieFile {
+    host: String,
+    /// The scheme the host was proxied over; files from before it was
+    /// recorded are HTTPS.
+    #[serde(default = "default_scheme")]
+    scheme: String,
+    cookies: Vec<StoredCookie>,
 }
 
-async fn proxy_request(
-    _method: Method,
-    url: &str,
+fn default_scheme() -> String {
+    "https".to_string()
+}
+
+/// `host`'s file in `dir`. Hosts can carry a port, so anything but letters,
+/// digits, dots and dashes becomes `_`; the file itself records the host.
+fn cookie_file(dir: &std::path::Path, host: &str) -> std::path::PathBuf {
+    let name: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{}.json", name))
+}
+
+/// Saves `host`'s live cookies to its file in `dir`, replacing what an
+/// earlier solve saved.
+async fn save_cookies(
+    dir: &std::path::Path,
     host: &str,
-    jars: CookieJarCache,
-) -> Result<(StatusCode, HeaderMap, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
-    let user_agent = get_chrome_rua();
-    let jar = get_or_create_jar(&jars, host);
-
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .cookie_provider(jar.clone())
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-
-    let resp = client
-        .get(url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await?;
-    let status = resp.status();
-    let headers = resp.headers().clone();
-    let body = resp.bytes().await?.to_vec();
+    session: &HostSession,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let file = CookieFile {
+        host: host.to_string(),
+        scheme: session.scheme().to_string(),
+        cookies: session.jar.live_cookies(),
+    };
+    tokio::fs::create_dir_all(dir).await?;
+    write_atomically(&cookie_file(dir, host), &serde_json::to_vec_pretty(&file)?).await
+}
 
-    let html = String::from_utf8_lossy(&body);
-    if let Some(parsed) = parse_challenge_from_html(&html) {
-        println!(
-            "Detected Anubis {} challenge for {} (algorithm={}, difficulty={})",
-            parsed.version,
-            host,
-            parsed.challenge.algorithm(),
-            parsed.challenge.rules.difficulty
+/// Reads every host's file in `dir`, without the cookies that have expired
+/// since. A missing directory has no files.
+async fn load_cookie_dir(
+    dir: &std::path::Path,
+) -> Result<Vec<CookieFile>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e.into()),
+    };
+    let now = unix_now();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        match serde_json::from_slice::<CookieFile>(&tokio::fs::read(&path).await?) {
+            Ok(mut file) => {
+                file.cookies.retain(|cookie| !cookie.expired(now));
+                files.push(file);
+            }
+            Err(e) => warn!("Ignoring unreadable cookie file {}: {}", path.display(), e),
+        }
+    }
+    Ok(files)
+}
+
+/// Loads `COOKIE_DIR` into `sessions`, so hosts solved before a restart
+/// start out with their cookies.
+async fn restore_cookies(
+    sessions: &SessionCache,
+    config: &ProxyConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(dir) = &config.cookie_dir else {
+        return Ok(());
+    };
+    let files = load_cookie_dir(dir).await?;
+    let now = unix_now();
+    let mut restored = 0;
+    for file in files {
+        if file.cookies.is_empty() {
+            continue;
+        }
+        let Some(url) = host_url(&file.scheme, &file.host) else {
+            continue;
+        };
+        let session = get_or_create_session(sessions, &file.host, config)?;
+        session
+            .https
+            .store(file.scheme == "https", Ordering::Relaxed);
+        for cookie in &file.cookies {
+            session.jar.add_cookie_str(&cookie.to_set_cookie(now), &url);
+        }
+        // Cookies are only saved once a challenge was passed.
+        session.passed.store(true, Ordering::Relaxed);
+        restored += 1;
+    }
+    if restored > 0 {
+        info!(
+            "Restored cookies for {} hosts from {}",
+            restored,
+            dir.display()
         );
-        return solve_and_retry(&client, url, host, &user_agent, parsed).await;
     }
+    Ok(())
+}
+
+/// Records that `host`'s challenge was passed, and saves its cookies to
+/// `COOKIE_DIR`. A failed save is only logged.
+async fn mark_passed(session: &HostSession, host: &str, config: &ProxyConfig) {
+    session.passed.store(true, Ordering::Relaxed);
+    if let Some(dir) = &config.cookie_dir {
+        if let Err(e) = save_cookies(dir, host, session).await {
+            error!("Failed to save cookies for {}: {}", host, e);
+        }
+    }
+}
 
-    Ok((status, headers, body))
+/// What `SNAPSHOT_FILE` keeps of a host's session across restarts.
+#[derive(Debug, Serialize, Deserialize)]
+struct HostState {
+    /// The scheme the host was proxied over, as in [`CookieFile`].
+    #[serde(default = "default_scheme")]
+    scheme: String,
+    /// The jar's live cookies, in the same form as `COOKIE_DIR`. Snapshots
+    /// from before cookies were kept this way restore without them.
+    #[serde(default)]
+    cookies: Vec<StoredCookie>,
+    user_agent: String,
+    difficulty: DifficultyHistory,
+    algorithm: Option<String>,
+    passed: bool,
 }
 
-async fn solve_and_retry(
-    client: &reqwest::Client,
-    original_url: &str,
-    host: &str,
-    user_agent: &str,
-    parsed: anubis_solver::ParsedChallenge,
-) -> Result<(StatusCode, HeaderMap, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
-    let challenge = &parsed.challenge;
-    let start_time = Instant::now();
+/// The URL a host's cookies are restored to.
+fn host_url(scheme: &str, host: &str) -> Option<reqwest::Url> {
+    format!("{}://{}/", scheme, host).parse().ok()
+}
+
+/// Where and how often host sessions are snapshotted (`SNAPSHOT_FILE`,
+/// `SNAPSHOT_INTERVAL`).
+struct SnapshotConfig {
+    path: std::path::PathBuf,
+    interval: Duration,
+}
 
-    let result = solve_challenge::<fn(u64)>(challenge, None)?;
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
-    if let Some(min_wait) = challenge.min_wait() {
-        let elapsed = start_time.elapsed();
-        if elapsed < min_wait {
-            tokio::time::sleep(min_wait - elapsed).await;
+fn snapshot_sessions(sessions: &SessionCache) -> BTreeMap<String, HostState> {
+    sessions
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().state()))
+        .collect()
+}
+
+/// Writes `contents` next to `path` and renames it into place, so a crash
+/// mid-write leaves the previous file intact.
+async fn write_atomically(
+    path: &std::path::Path,
+    contents: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    tokio::fs::write(&temp, contents).await?;
+    tokio::fs::rename(&temp, path).await?;
+    Ok(())
+}
+
+async fn write_snapshot(
+    path: &std::path::Path,
+    states: &BTreeMap<String, HostState>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    write_atomically(path, &serde_json::to_vec_pretty(states)?).await
+}
+
+/// Reads a snapshot written by [`write_snapshot`], without the cookies that
+/// have expired since. A missing file is empty.
+async fn load_snapshot(
+    path: &std::path::Path,
+) -> Result<BTreeMap<String, HostState>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut states: BTreeMap<String, HostState> = match tokio::fs::read(path).await {
+        Ok(json) => serde_json::from_slice(&json)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let now = unix_now();
+    for state in states.values_mut() {
+        state.cookies.retain(|cookie| !cookie.expired(now));
+    }
+    Ok(states)
+}
+
+fn restore_sessions(
+    sessions: &SessionCache,
+    states: BTreeMap<String, HostState>,
+    config: &ProxyConfig,
+) -> Result<(), reqwest::Error> {
+    let now = unix_now();
+    for (host, state) in states {
+        let jar = Arc::new(HostJar::default());
+        if let Some(url) = host_url(&state.scheme, &host) {
+            for cookie in &state.cookies {
+                jar.add_cookie_str(&cookie.to_set_cookie(now), &url);
+            }
         }
+        let session = HostSession {
+            client: build_client(config, jar.clone())?,
+            jar,
+            difficulties: Arc::new(std::sync::Mutex::new(state.difficulty)),
+            passed: Arc::new(AtomicBool::new(state.passed)),
+            user_agent: state.user_agent.into(),
+            algorithm: Arc::new(std::sync::Mutex::new(state.algorithm)),
+            https: Arc::new(AtomicBool::new(state.scheme == "https")),
+        };
+        sessions.insert(host, session);
     }
+    Ok(())
+}
 
-    let elapsed_time = start_time.elapsed();
-    println!(
-        "Solved {} challenge in {:?}",
-        challenge.algorithm(),
-        elapsed_time
-    );
+/// Restores `SNAPSHOT_FILE` into `sessions` and keeps it updated. Writes run
+/// on their own task, so they never hold up requests.
+async fn start_snapshots(sessions: &SessionCache, config: &Arc<ProxyConfig>) {
+    let Some(snapshot) = &config.snapshot else {
+        return;
+    };
+    match load_snapshot(&snapshot.path).await {
+        Ok(states) => {
+            let count = states.len();
+            match restore_sessions(sessions, states, config) {
+                Ok(()) if count > 0 => info!(
+                    "Restored {} host sessions from {}",
+                    count,
+                    snapshot.path.display()
+                ),
+                Ok(()) => {}
+                Err(e) => error!("Failed to restore host sessions: {}", e),
+            }
+        }
+        Err(e) => warn!(
+            "Ignoring unreadable snapshot {}: {}",
+            snapshot.path.display(),
+            e
+        ),
+    }
 
-    let submit_url = build_submission_url(
-        "https",
-        host,
-        challenge,
-        &result,
-        original_url,
-        elapsed_time.as_millis(),
-    );
+    let sessions = sessions.clone();
+    let path = snapshot.path.clone();
+    let mut ticks = tokio::time::interval(snapshot.interval);
+    tokio::spawn(async move {
+        // The first tick is immediate, and there is nothing new to write yet.
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let states = snapshot_sessions(&sessions);
+            if let Err(e) = write_snapshot(&path, &states).await {
+                error!("Failed to write snapshot {}: {}", path.display(), e);
+            }
+        }
+    });
+}
 
-    let submit_resp = client
-        .get(&submit_url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await?;
+/// Writes every passed host's cookies to `COOKIE_DIR` and a last snapshot,
+/// so cookies a session picked up since its solve or the last snapshot tick
+/// survive a restart.
+async fn persist_sessions(sessions: &SessionCache, config: &ProxyConfig) {
+    if let Some(dir) = &config.cookie_dir {
+        // Collected first, so no map entry stays locked across a write.
+        let passed: Vec<(String, HostSession)> = sessions
+            .iter()
+            .filter(|entry| entry.passed.load(Ordering::Relaxed))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (host, session) in passed {
+            if let Err(e) = save_cookies(dir, &host, &session).await {
+                error!("Failed to save cookies for {}: {}", host, e);
+            }
+        }
+    }
+    if let Some(snapshot) = &config.snapshot {
+        if let Err(e) = write_snapshot(&snapshot.path, &snapshot_sessions(sessions)).await {
+            error!(
+                "Failed to write snapshot {}: {}",
+                snapshot.path.display(),
+                e
+            );
+        }
+    }
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests being served, counted so shutdown can wait for them.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: tokio::sync::Notify,
+}
+
+impl InFlight {
+    fn start(self: &Arc<Self>) -> InFlightRequest {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no request is in flight.
+    async fn drained(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            // Registered before the count is read, so a request finishing in
+            // between still wakes it.
+            idle.as_mut().enable();
+            if self.count() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// One request counted by [`InFlight`] until dropped.
+struct InFlightRequest(Arc<InFlight>);
+
+impl InFlightRequest {
+    /// Keeps the request counted until `body` has been sent or dropped, so
+    /// shutdown doesn't cut off a response that is still streaming.
+    fn until_sent(self, body: ProxyBody) -> ProxyBody {
+        body.map_err(move |e| {
+            let _request = &self;
+            e
+        })
+        .boxed_unsync()
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Resolves on SIGINT or, on Unix, SIGTERM. A handler that can't be
+/// installed is logged and never fires.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => info!("Received SIGINT"),
+        () = terminate => info!("Received SIGTERM"),
+    }
+}
 
-    let submit_status = submit_resp.status();
-    let submit_headers = submit_resp.headers().clone();
+/// How a proxied response was obtained, sent to the client as `X-Anubis-Status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnubisStatus {
+    /// A challenge was served and solved for this request.
+    Solved,
+    /// No challenge was served, thanks to a session solved earlier.
+    CachedSession,
+    /// No challenge was served and none has been solved for this host.
+    NoChallenge,
+}
+
+impl AnubisStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnubisStatus::Solved => "solved",
+            AnubisStatus::CachedSession => "cached-session",
+            AnubisStatus::NoChallenge => "no-challenge",
+        }
+    }
+}
+
+/// Upstream headers that pin the page to its real origin and can stop it
+/// from rendering when viewed through the proxy.
+const SECURITY_HEADERS: &[&str] = &[
+    "content-security-policy",
+    "content-security-policy-report-only",
+    "x-frame-options",
+];
+
+/// Proxy settings read from the environment at startup.
+#[derive(Default)]
+struct ProxyConfig {
+    /// Drop `SECURITY_HEADERS` from proxied responses (`STRIP_SECURITY_HEADERS`).
+    strip_security_headers: bool,
+    /// Log the status and headers of every submission response
+    /// (`DUMP_RESPONSE_HEADERS`).
+    dump_response_headers: bool,
+    /// After a rejected PoW submission, probe other nonce and answer formats
+    /// and log the one the server accepts (`AUTODETECT`).
+    autodetect: bool,
+    /// Submit as soon as a challenge is solved instead of waiting out its
+    /// `min_wait` (`SKIP_MIN_WAIT`), for servers that don't enforce it.
+    skip_min_wait: bool,
+    /// Route upstream fetches and submissions through this proxy (`UPSTREAM_PROXY`),
+    /// e.g. `socks5h://127.0.0.1:9050` for Tor.
+    upstream_proxy: Option<reqwest::Proxy>,
+    /// Source address for upstream connections (`OUTGOING_ADDRESS`).
+    local_address: Option<std::net::IpAddr>,
+    /// Solver settings, e.g. the nonce format (`NONCE_FORMAT`) and a dedicated
+    /// solver thread pool (`SOLVER_THREADS`).
+    solve_options: SolveOptions,
+    /// Algorithm for challenges that don't name one (`DEFAULT_ALGORITHM`).
+    default_algorithm: Option<String>,
+    /// Where challenges live in the page (`CHALLENGE_SELECTOR`, `VERSION_SELECTOR`).
+    selectors: ChallengeSelectors,
+    /// How solutions are submitted, e.g. the answer format (`ANSWER_FORMAT`)
+    /// and extra parameters (`SUBMIT_PARAMS`, `SUBMIT_PARAMS_OVERRIDE`).
+    submission: SubmissionOptions,
+    /// Most client connections served at once (`MAX_CONNECTIONS`); unlimited
+    /// if unset.
+    max_connections: Option<usize>,
+    /// Upstream connect and read limits (`CONNECT_TIMEOUT`, `READ_TIMEOUT`).
+    timeouts: UpstreamTimeouts,
+    /// Solves run at once; unlimited if unset.
+    solve_limit: Option<SolveLimit>,
+    /// Notified after every solve (`SOLVE_WEBHOOK_URL`).
+    solve_webhook: Option<SolveWebhook>,
+    /// Submits solutions; [`GetSubmission`] if unset.
+    submission_backend: Option<Box<dyn SubmissionBackend>>,
+    /// Submission statuses that mean the challenge was passed (`EXPECT_STATUS`).
+    pass_statuses: PassStatuses,
+    /// Persist host sessions across restarts (`SNAPSHOT_FILE`).
+    snapshot: Option<SnapshotConfig>,
+    /// Save each host's cookies here after every passed challenge, and load
+    /// them at startup (`COOKIE_DIR`).
+    cookie_dir: Option<std::path::PathBuf>,
+    /// Counters served at `/metrics`.
+    metrics: Metrics,
+    /// Scheme for `/proxy/` paths that don't name one (`UPSTREAM_SCHEME`);
+    /// `https` if unset.
+    upstream_scheme: Option<&'static str>,
+    /// Scheme for submissions (`SUBMIT_SCHEME`); the page's if unset.
+    submit_scheme: Option<&'static str>,
+    /// How long shutdown waits for in-flight requests (`SHUTDOWN_TIMEOUT`).
+    shutdown_timeout: Duration,
+    /// The last `/health?deep=1` result.
+    deep_health: DeepHealth,
+}
+
+/// Parses an `http` or `https` scheme setting.
+fn parse_scheme(var: &str, value: &str) -> Result<&'static str, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "http" => Ok("http"),
+        "https" => Ok("https"),
+        _ => Err(format!(
+            "Invalid {} {:?}: expected http or https",
+            var, value
+        )),
+    }
+}
+
+/// Splits what follows `/proxy/` into the upstream scheme, host and path. The
+/// scheme may lead, as in `/proxy/http://host/path`; otherwise it is
+/// `default_scheme`.
+fn split_target<'a>(
+    rest: &'a str,
+    default_scheme: &'static str,
+) -> (&'static str, &'a str, &'a str) {
+    let (scheme, rest) = if let Some(rest) = rest.strip_prefix("http://") {
+        ("http", rest)
+    } else if let Some(rest) = rest.strip_prefix("https://") {
+        ("https", rest)
+    } else {
+        (default_scheme, rest)
+    };
+    match rest.find('/') {
+        Some(i) => (scheme, &rest[..i], &rest[i..]),
+        None => (scheme, rest, "/"),
+    }
+}
+
+/// Upper bounds, in seconds, of the `/metrics` solve duration buckets.
+const SOLVE_DURATION_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
 
-    if submit_status != reqwest::StatusCode::FOUND {
-        let body = submit_resp.bytes().await?.to_vec();
-        eprintln!(
-            "Challenge submission returned {} instead of 302 (server-side issue)",
-            submit_status
+/// Counters for `/metrics`, in the Prometheus text format.
+#[derive(Default)]
+struct Metrics {
+    /// Requests to `/proxy/`.
+    requests: AtomicU64,
+    challenges_detected: AtomicU64,
+    /// Solves that produced a solution, whether or not it was accepted.
+    challenges_solved: AtomicU64,
+    solve_failures: AtomicU64,
+    /// Solves by [`SOLVE_DURATION_BUCKETS`] bucket, the last for longer ones.
+    solve_buckets: [AtomicU64; SOLVE_DURATION_BUCKETS.len() + 1],
+    solve_micros: AtomicU64,
+}
+
+impl Metrics {
+    fn observe_solve(&self, duration: Duration) {
+        self.challenges_solved.fetch_add(1, Ordering::Relaxed);
+        let secs = duration.as_secs_f64();
+        let bucket = SOLVE_DURATION_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(SOLVE_DURATION_BUCKETS.len());
+        self.solve_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.solve_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, sessions: usize) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "anubis_proxy_requests_total",
+            "counter",
+            "Requests proxied through /proxy/.",
+            load(&self.requests),
+        );
+        metric(
+            "anubis_proxy_challenges_detected_total",
+            "counter",
+            "Anubis challenges found in upstream responses.",
+            load(&self.challenges_detected),
+        );
+        metric(
+            "anubis_proxy_challenges_solved_total",
+            "counter",
+            "Challenges solved, whether or not the server accepted the solution.",
+            load(&self.challenges_solved),
+        );
+        metric(
+            "anubis_proxy_solve_failures_total",
+            "counter",
+            "Challenges the solver failed on.",
+            load(&self.solve_failures),
         );
-        return Ok((submit_status, submit_headers, body));
+        metric(
+            "anubis_proxy_sessions",
+            "gauge",
+            "Hosts with a cached session and cookie jar.",
+            sessions as u64,
+        );
+
+        let name = "anubis_proxy_solve_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent solving challenges.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (bound, bucket) in SOLVE_DURATION_BUCKETS.iter().zip(&self.solve_buckets) {
+            count += load(bucket);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        count += load(&self.solve_buckets[SOLVE_DURATION_BUCKETS.len()]);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            load(&self.solve_micros) as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+        out
     }
+}
 
-    println!("Challenge passed, fetching content...");
+/// Submission response statuses that mean the challenge was passed. Upstream
+/// Anubis redirects with a 302; some forks answer 200 or 303.
+struct PassStatuses(Vec<StatusCode>);
 
-    let resp = client
-        .get(original_url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await?;
-    let status = resp.status();
-    let headers = resp.headers().clone();
-    let body = resp.bytes().await?.to_vec();
+impl Default for PassStatuses {
+    fn default() -> Self {
+        PassStatuses(vec![StatusCode::FOUND])
+    }
+}
+
+impl std::str::FromStr for PassStatuses {
+    type Err = String;
+
+    /// Parses a comma separated list of status codes, e.g. `302,303`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let statuses = s
+            .split(',')
+            .map(|code| {
+                code.trim()
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .ok_or_else(|| format!("invalid status code {:?}", code.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PassStatuses(statuses))
+    }
+}
+
+impl std::fmt::Display for PassStatuses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let codes: Vec<_> = self.0.iter().map(|status| status.as_str()).collect();
+        f.write_str(&codes.join("/"))
+    }
+}
+
+/// Receives a JSON POST for every solved challenge, e.g. to refresh a cache
+/// or feed a dashboard.
+struct SolveWebhook {
+    url: reqwest::Url,
+    /// Separate from the per-host clients: no cookies and no upstream proxy.
+    client: reqwest::Client,
+}
+
+impl SolveWebhook {
+    fn new(url: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url)
+            .map_err(|e| format!("Invalid SOLVE_WEBHOOK_URL {:?}: {}", url, e))?;
+        let client = reqwest::Client::builder()
+            .timeout(DEFAULT_UPSTREAM_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Failed to build webhook client: {}", e))?;
+        Ok(SolveWebhook { url, client })
+    }
+
+    /// Posts `payload` in the background; failures are only logged, so they
+    /// never affect the proxied response.
+    fn notify(&self, payload: serde_json::Value) {
+        let request = self.client.post(self.url.clone()).json(&payload);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(resp) if !resp.status().is_success() => {
+                    error!("Solve webhook returned {}", resp.status())
+                }
+                Ok(_) => {}
+                Err(e) => error!("Solve webhook failed: {}", e),
+            }
+        });
+    }
+}
+
+impl ProxyConfig {
+    fn from_env() -> Result<Self, String> {
+        let upstream_proxy = match std::env::var("UPSTREAM_PROXY") {
+            Ok(url) if !url.is_empty() => Some(
+                reqwest::Proxy::all(&url)
+                    .map_err(|e| format!("Invalid UPSTREAM_PROXY {:?}: {}", url, e))?,
+            ),
+            _ => None,
+        };
+
+        let local_address = match std::env::var("OUTGOING_ADDRESS") {
+            Ok(addr) if !addr.is_empty() => Some(
+                addr.parse()
+                    .map_err(|e| format!("Invalid OUTGOING_ADDRESS {:?}: {}", addr, e))?,
+            ),
+            _ => None,
+        };
+
+        let mut solve_options = SolveOptions::default();
+        if let Ok(format) = std::env::var("NONCE_FORMAT") {
+            solve_options.nonce_format = format
+                .parse()
+                .map_err(|e| format!("Invalid NONCE_FORMAT: {}", e))?;
+        }
+        if let Ok(order) = std::env::var("CONCAT_ORDER") {
+            solve_options.concat_order = order
+                .parse()
+                .map_err(|e| format!("Invalid CONCAT_ORDER: {}", e))?;
+        }
+        if let Ok(threads) = std::env::var("SOLVER_THREADS") {
+            let threads: usize = threads
+                .parse()
+                .map_err(|e| format!("Invalid SOLVER_THREADS {:?}: {}", threads, e))?;
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("anubis-solver-{}", i))
+                .build()
+                .map_err(|e| format!("Failed to start solver threads: {}", e))?;
+            solve_options.thread_pool = Some(Arc::new(pool));
+        }
+        solve_options.pin_threads = env_flag("PIN_SOLVER_THREADS");
+        if let Ok(secs) = std::env::var("SOLVE_MAX_TIME") {
+            let secs: u64 = secs
+                .parse()
+                .map_err(|e| format!("Invalid SOLVE_MAX_TIME {:?}: {}", secs, e))?;
+            solve_options.max_time = Some(Duration::from_secs(secs));
+        }
+        if let Ok(start) = std::env::var("NONCE_START") {
+            solve_options.start = start
+                .parse()
+                .map_err(|e| format!("Invalid NONCE_START: {}", e))?;
+        }
+        if let Ok(steps) = std::env::var("NORMALIZE_CHALLENGE") {
+            solve_options.normalization = steps
+                .parse()
+                .map_err(|e| format!("Invalid NORMALIZE_CHALLENGE: {}", e))?;
+        }
+
+        let default_algorithm = std::env::var("DEFAULT_ALGORITHM")
+            .ok()
+            .filter(|algorithm| !algorithm.is_empty());
+        if let Some(algorithm) = &default_algorithm {
+            algorithm
+                .parse::<anubis_solver::AnubisAlgorithm>()
+                .map_err(|e| format!("Invalid DEFAULT_ALGORITHM: {}", e))?;
+        }
+
+        let challenge_selector = std::env::var("CHALLENGE_SELECTOR")
+            .unwrap_or_else(|_| ChallengeSelectors::CHALLENGE.to_string());
+        let version_selector = std::env::var("VERSION_SELECTOR")
+            .unwrap_or_else(|_| ChallengeSelectors::VERSION.to_string());
+        let selectors = ChallengeSelectors::new(&challenge_selector, &version_selector)
+            .map_err(|e| format!("Invalid CHALLENGE_SELECTOR or VERSION_SELECTOR: {}", e))?;
 
-    Ok((status, headers, body))
+        let mut submission = SubmissionOptions::default();
+        if let Ok(format) = std::env::var("ANSWER_FORMAT") {
+            submission.answer_format = format
+                .parse()
+                .map_err(|e| format!("Invalid ANSWER_FORMAT: {}", e))?;
+        }
+        if let Ok(params) = std::env::var("SUBMIT_PARAMS") {
+            submission.extra_params = parse_submit_params(&params)
+                .map_err(|e| format!("Invalid SUBMIT_PARAMS: {}", e))?;
+        }
+        submission.override_params = env_flag("SUBMIT_PARAMS_OVERRIDE");
+
+        let max_connections = match std::env::var("MAX_CONNECTIONS") {
+            Ok(limit) => match limit.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!(
+                        "Invalid MAX_CONNECTIONS {:?}: expected a positive number",
+                        limit
+                    ))
+                }
+                Ok(limit) => Some(limit),
+            },
+            Err(_) => None,
+        };
+
+        let max_solves = match std::env::var("MAX_CONCURRENT_SOLVES") {
+            Ok(limit) => match limit.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!(
+                        "Invalid MAX_CONCURRENT_SOLVES {:?}: expected a positive number",
+                        limit
+                    ))
+                }
+                Ok(limit) => limit,
+            },
+            Err(_) => std::thread::available_parallelism().map_or(1, usize::from),
+        };
+        let solve_queue_timeout = match std::env::var("SOLVE_QUEUE_TIMEOUT") {
+            Ok(secs) => {
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|e| format!("Invalid SOLVE_QUEUE_TIMEOUT {:?}: {}", secs, e))?;
+                Some(Duration::from_secs(secs))
+            }
+            Err(_) => None,
+        };
+
+        let shutdown_timeout = match std::env::var("SHUTDOWN_TIMEOUT") {
+            Ok(secs) => Duration::from_secs(
+                secs.parse()
+                    .map_err(|e| format!("Invalid SHUTDOWN_TIMEOUT {:?}: {}", secs, e))?,
+            ),
+            Err(_) => DEFAULT_SHUTDOWN_TIMEOUT,
+        };
+
+        let mut timeouts = UpstreamTimeouts::default();
+        for (var, timeout) in [
+            ("CONNECT_TIMEOUT", &mut timeouts.connect),
+            ("READ_TIMEOUT", &mut timeouts.read),
+        ] {
+            if let Ok(secs) = std::env::var(var) {
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|e| format!("Invalid {} {:?}: {}", var, secs, e))?;
+                *timeout = Duration::from_secs(secs);
+            }
+        }
+
+        let solve_webhook = match std::env::var("SOLVE_WEBHOOK_URL") {
+            Ok(url) if !url.is_empty() => Some(SolveWebhook::new(&url)?),
+            _ => None,
+        };
+
+        let pass_statuses = match std::env::var("EXPECT_STATUS") {
+            Ok(codes) => codes
+                .parse()
+                .map_err(|e| format!("Invalid EXPECT_STATUS: {}", e))?,
+            Err(_) => PassStatuses::default(),
+        };
+
+        let snapshot = match std::env::var("SNAPSHOT_FILE") {
+            Ok(path) if !path.is_empty() => {
+                let interval = match std::env::var("SNAPSHOT_INTERVAL") {
+                    Ok(secs) => match secs.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(format!(
+                            "Invalid SNAPSHOT_INTERVAL {:?}: expected a positive number of seconds",
+                            secs
+                        ))
+                        }
+                        Ok(secs) => Duration::from_secs(secs),
+                    },
+                    Err(_) => DEFAULT_SNAPSHOT_INTERVAL,
+                };
+                Some(SnapshotConfig {
+                    path: path.into(),
+                    interval,
+                })
+            }
+            _ => None,
+        };
+
+        Ok(ProxyConfig {
+            strip_security_headers: env_flag("STRIP_SECURITY_HEADERS"),
+            dump_response_headers: env_flag("DUMP_RESPONSE_HEADERS"),
+            skip_min_wait: env_flag("SKIP_MIN_WAIT"),
+            autodetect: env_flag("AUTODETECT"),
+            upstream_proxy,
+            local_address,
+            solve_options,
+            default_algorithm,
+            selectors,
+            submission,
+            max_connections,
+            timeouts,
+            solve_limit: Some(SolveLimit::new(max_solves, solve_queue_timeout)),
+            solve_webhook,
+            submission_backend: None,
+            pass_statuses,
+            snapshot,
+            cookie_dir: std::env::var_os("COOKIE_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(Into::into),
+            metrics: Metrics::default(),
+            upstream_scheme: match std::env::var("UPSTREAM_SCHEME") {
+                Ok(scheme) if !scheme.is_empty() => Some(parse_scheme("UPSTREAM_SCHEME", &scheme)?),
+                _ => None,
+            },
+            submit_scheme: match std::env::var("SUBMIT_SCHEME") {
+                Ok(scheme) if !scheme.is_empty() => Some(parse_scheme("SUBMIT_SCHEME", &scheme)?),
+                _ => None,
+            },
+            shutdown_timeout,
+            deep_health: DeepHealth::default(),
+        })
+    }
+}
+
+/// Describes the SHA-256 backend for the startup log and `/info`.
+fn sha_backend() -> &'static str {
+    match (sha_hardware_accelerated(), cpu_has_sha_extensions()) {
+        (true, _) => "hardware",
+        (false, true) => {
+            "software (CPU has SHA extensions, but sha2 doesn't use them on this target)"
+        }
+        (false, false) => "software (no SHA extensions detected)",
+    }
+}
+
+/// Expected solve time past which PoW solves on software SHA-256 are warned
+/// about before they start.
+const SLOW_SOLVE_WARNING: Duration = Duration::from_secs(30);
+
+/// Warns before a PoW solve that software SHA-256 will likely take longer than
+/// [`SLOW_SOLVE_WARNING`], so a long solve isn't mistaken for a hang. The
+/// hashrate is measured once, on the first solve that needs it, which blocks
+/// for about 50ms, so call this from the solve's blocking task.
+fn warn_if_solve_is_slow(host: &str, challenge: &AnubisChallenge, options: &SolveOptions) {
+    static HASHRATE: std::sync::OnceLock<f64> = std::sync::OnceLock::new();
+    if sha_hardware_accelerated() || challenge.min_wait().is_some() {
+        return;
+    }
+    let threads = options
+        .thread_pool
+        .as_ref()
+        .map_or_else(rayon::current_num_threads, |pool| {
+            pool.current_num_threads()
+        });
+    let hashrate =
+        *HASHRATE.get_or_init(|| measure_hashrate(Duration::from_millis(50))) * threads as f64;
+    let expected = expected_solve_time(challenge.rules.difficulty, hashrate);
+    if expected > SLOW_SOLVE_WARNING {
+        warn!(
+            "Difficulty {} challenge for {} is expected to take about {:?} at {:.1} MH/s: no hardware SHA-256 on this machine",
+            challenge.rules.difficulty,
+            host,
+            expected,
+            hashrate / 1e6
+        );
+    }
+}
+
+/// Parses `SUBMIT_PARAMS`: comma separated `key=value` pairs, kept in order.
+fn parse_submit_params(text: &str) -> Result<Vec<(String, String)>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.to_string()))
+            }
+            _ => Err(format!("expected key=value, got {:?}", pair)),
+        })
+        .collect()
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+const DEFAULT_PORT: u16 = 8192;
+
+/// Resolves the address to listen on from `--bind <ADDR>` and `--port <PORT>`
+/// (also as `--bind=ADDR`), falling back to the `BIND_ADDRESS` and `PORT`
+/// environment values, then to `0.0.0.0:8192`. An unparsable `PORT` falls
+/// back to the default, as it always has; flags must be valid.
+fn listen_address(
+    args: impl IntoIterator<Item = String>,
+    bind_env: Option<String>,
+    port_env: Option<String>,
+) -> Result<SocketAddr, String> {
+    let mut bind = None;
+    let mut port = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let slot = match flag.as_str() {
+            "--bind" => &mut bind,
+            "--port" => &mut port,
+            _ => return Err(format!("unknown argument {:?}", flag)),
+        };
+        let value = inline
+            .or_else(|| args.next())
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        *slot = Some((flag, value));
+    }
+
+    let ip = match bind {
+        Some((flag, ip)) => ip
+            .parse()
+            .map_err(|e| format!("Invalid {} {:?}: {}", flag, ip, e))?,
+        None => match bind_env.filter(|ip| !ip.is_empty()) {
+            Some(ip) => ip
+                .parse()
+                .map_err(|e| format!("Invalid BIND_ADDRESS {:?}: {}", ip, e))?,
+            None => std::net::IpAddr::from([0, 0, 0, 0]),
+        },
+    };
+    let port = match port {
+        Some((flag, port)) => port
+            .parse()
+            .map_err(|e| format!("Invalid {} {:?}: {}", flag, port, e))?,
+        None => port_env
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_PORT),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Logs to stdout at `RUST_LOG`'s levels (`info` if unset), as JSON lines
+/// with `LOG_FORMAT=json`.
+fn init_logging() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stdout()));
+    if std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    init_logging();
+    let addr = match listen_address(
+        std::env::args().skip(1),
+        std::env::var("BIND_ADDRESS").ok(),
+        std::env::var("PORT").ok(),
+    ) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: anubis-proxy [--bind <ADDR>] [--port <PORT>]");
+            std::process::exit(2);
+        }
+    };
+    let listener = TcpListener::bind(addr).await?;
+    info!("Anubis proxy listening on http://{}", addr);
+    info!("Usage: GET /proxy/[http://]<host>/<path>");
+    let backend = sha_backend();
+    if sha_hardware_accelerated() {
+        info!("SHA-256 backend: {}", backend);
+    } else {
+        warn!("SHA-256 backend: {}, PoW solves will be slower", backend);
+    }
+
+    // Printed rather than returned, so the message isn't Debug-quoted.
+    let config = match ProxyConfig::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if config.skip_min_wait {
+        info!("Submitting without waiting out challenge min_wait");
+    }
+    if config.strip_security_headers {
+        info!("Stripping upstream CSP and X-Frame-Options headers");
+    }
+    if config.upstream_proxy.is_some() {
+        info!("Routing upstream connections through UPSTREAM_PROXY");
+    }
+    if let Some(addr) = config.local_address {
+        info!("Binding upstream connections to {}", addr);
+    }
+
+    if let Some(limit) = config.max_connections {
+        info!("Serving at most {} connections at once", limit);
+    }
+    if let Some(limit) = &config.solve_limit {
+        info!("Solving at most {} challenges at once", limit.limit);
+    }
+
+    serve(listener, config, shutdown_signal()).await?;
+    // Everything worth keeping is on disk by now. Exiting here keeps a solve
+    // that outlived the drain timeout from holding up the runtime's shutdown.
+    std::process::exit(0)
+}
+
+/// Accepts and serves client connections until `shutdown` resolves.
+///
+/// With `max_connections` set, a permit is taken before each accept and held
+/// until the connection closes, so excess clients wait in the listen backlog
+/// instead of each getting a task.
+///
+/// On shutdown, no more connections are accepted and open ones finish their
+/// current request and close. Once those requests are done, or after
+/// `shutdown_timeout`, host sessions are saved and this returns.
+async fn serve(
+    listener: TcpListener,
+    config: Arc<ProxyConfig>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sessions: SessionCache = Arc::new(DashMap::new());
+    start_snapshots(&sessions, &config).await;
+    if let Err(e) = restore_cookies(&sessions, &config).await {
+        error!("Failed to restore cookies: {}", e);
+    }
+    let limit = config
+        .max_connections
+        .map(|limit| Arc::new(tokio::sync::Semaphore::new(limit)));
+
+    let in_flight = Arc::new(InFlight::default());
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let mut shutdown = std::pin::pin!(shutdown);
+
+    loop {
+        let next = async {
+            let permit = match &limit {
+                Some(limit) => Some(limit.clone().acquire_owned().await?),
+                None => None,
+            };
+            let (stream, _) = listener.accept().await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((stream, permit))
+        };
+        let (stream, permit) = tokio::select! {
+            next = next => next?,
+            () = &mut shutdown => break,
+        };
+        let io = TokioIo::new(stream);
+        let sessions = sessions.clone();
+        let config = config.clone();
+        let in_flight = in_flight.clone();
+        let mut stopped = stopped.clone();
+
+        tokio::spawn(async move {
+            let connection = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(|req| {
+                        let request = in_flight.start();
+                        let response = handle_request(req, sessions.clone(), config.clone());
+                        async move {
+                            Ok::<_, hyper::Error>(
+                                response.await?.map(|body| request.until_sent(body)),
+                            )
+                        }
+                    }),
+                )
+                .with_upgrades();
+            let mut connection = std::pin::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = stopped.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                error!("Connection error: {}", e);
+            }
+            drop(permit);
+        });
+    }
+
+    drop(listener);
+    info!(
+        "Shutting down: draining {} in-flight requests",
+        in_flight.count()
+    );
+    stop.send_replace(true);
+    if tokio::time::timeout(config.shutdown_timeout, in_flight.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Gave up on {} in-flight requests after {:?}",
+            in_flight.count(),
+            config.shutdown_timeout
+        );
+    }
+    persist_sessions(&sessions, &config).await;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request<hyper::body::Incoming>,
+    sessions: SessionCache,
+    config: Arc<ProxyConfig>,
+) -> Result<Response<ProxyBody>, hyper::Error> {
+    let path = req.uri().path();
+
+    if path == "/health" {
+        let deep = req
+            .uri()
+            .query()
+            .is_some_and(|q| q.split('&').any(|pair| pair == "deep=1"));
+        if !deep {
+            return Ok(Response::new(full("ok")));
+        }
+        return Ok(
+            match config.deep_health.check(&config.solve_options).await {
+                Ok(()) => Response::new(full("ok")),
+                Err(e) => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(full(format!("solver check failed: {}", e)))
+                    .unwrap(),
+            },
+        );
+    }
+
+    if path == "/info" {
+        let info = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "arch": std::env::consts::ARCH,
+            "cpu_sha_extensions": cpu_has_sha_extensions(),
+            "sha_hardware_accelerated": sha_hardware_accelerated(),
+            "sha_backend": sha_backend(),
+            "algorithms": supported_algorithms(),
+        });
+        return Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(full(info.to_string()))
+            .unwrap());
+    }
+
+    if path == "/metrics" {
+        return Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(full(config.metrics.render(sessions.len())))
+            .unwrap());
+    }
+
+    if path == "/solve" {
+        if req.method() != Method::POST {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(full("POST a JSON array of challenges"))
+                .unwrap());
+        }
+        let body = match Limited::new(req.into_body(), MAX_SOLVE_BODY)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) => {
+                let status = if e.is::<LengthLimitError>() {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                return Ok(Response::builder()
+                    .status(status)
+                    .body(full(e.to_string()))
+                    .unwrap());
+            }
+        };
+        // Solving blocks, so keep it off the async workers.
+        let (status, body) = tokio::task::spawn_blocking(move || solve_batch(&body, &config))
+            .await
+            .unwrap_or_else(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("solver panicked: {}", e),
+                )
+            });
+        return Ok(Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(full(body))
+            .unwrap());
+    }
+
+    if !path.starts_with("/proxy/") {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(
+                "Usage: /proxy/[http://]<host>/<path>\nExample: /proxy/clew.se/search?q=test",
+            ))
+            .unwrap());
+    }
+
+    let (scheme, host, target_path) =
+        split_target(&path[7..], config.upstream_scheme.unwrap_or("https"));
+
+    if host.is_empty() {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("Missing host in path"))
+            .unwrap());
+    }
+
+    config.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{}", q))
+        .unwrap_or_default();
+    let target_url = format!("{}://{}{}{}", scheme, host, target_path, query);
+
+    if is_websocket_upgrade(req.headers()) {
+        debug!("Proxying WebSocket: {}", target_url);
+        let host = host.to_string();
+        return Ok(
+            match proxy_websocket(req, &target_url, &host, sessions, &config).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    error!("WebSocket proxy error: {}", e);
+                    Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(full(format!("Proxy error: {}", e)))
+                        .unwrap()
+                }
+            },
+        );
+    }
+
+    debug!("Proxying: {} {}", req.method(), target_url);
+
+    let host = host.to_string();
+    let method = req.method().clone();
+    let content_type = req.headers().get(hyper::header::CONTENT_TYPE).cloned();
+    let content_encoding = req.headers().get(hyper::header::CONTENT_ENCODING).cloned();
+    let body = match Limited::new(req.into_body(), MAX_PROXY_BODY)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            let status = if e.is::<LengthLimitError>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            return Ok(Response::builder()
+                .status(status)
+                .body(full(e.to_string()))
+                .unwrap());
+        }
+    };
+    let request = ClientRequest {
+        method,
+        content_type,
+        content_encoding,
+        body,
+    };
+
+    match proxy_request(&request, &target_url, &host, sessions, &config).await {
+        Ok(((status, headers, body), anubis_status)) => {
+            let mut builder = Response::builder()
+                .status(status)
+                .header("x-anubis-status", anubis_status.as_str());
+            for (key, value) in headers {
+                if let Some(name) = key {
+                    let name_str = name.as_str().to_lowercase();
+                    if name_str == "transfer-encoding" || name_str == "connection" {
+                        continue;
+                    }
+                    if config.strip_security_headers
+                        && SECURITY_HEADERS.contains(&name_str.as_str())
+                    {
+                        continue;
+                    }
+                    builder = builder.header(name, value);
+                }
+            }
+            let body = match body {
+                UpstreamBody::Buffered(bytes) => full(bytes),
+                UpstreamBody::Streaming(resp) => {
+                    debug!("Streaming event-stream response from {}", host);
+                    stream_body(resp)
+                }
+            };
+            Ok(builder.body(body).unwrap())
+        }
+        Err(e) => {
+            error!("Proxy error: {}", e);
+            let status = if e.is::<SolveQueueTimeout>() {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::BAD_GATEWAY
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(full(format!("Proxy error: {}", e)))
+                .unwrap())
+        }
+    }
+}
+
+/// Most bytes of a proxied request body. Bodies are buffered so they can be
+/// sent again after a challenge. Compressed bodies are passed through as they
+/// are, so this limits their compressed size.
+const MAX_PROXY_BODY: usize = 16 << 20;
+/// Most bytes accepted by `/solve`.
+const MAX_SOLVE_BODY: usize = 1 << 20;
+/// Most challenges in one `/solve` batch.
+const MAX_SOLVE_BATCH: usize = 64;
+/// Most expected hashes for one `/solve` batch, about 20s on one core.
+const MAX_SOLVE_WORK: u64 = 1 << 28;
+
+/// Solves a `/solve` batch: a JSON array of challenges, in the page's JSON
+/// format, answered with an array of `{"result": ...}` or `{"error": ...}` in
+/// the same order. One challenge is solved at a time, each on the solver pool,
+/// so a batch never uses more threads than a single solve.
+fn solve_batch(body: &[u8], config: &ProxyConfig) -> (StatusCode, String) {
+    let challenges: Vec<serde_json::Value> = match serde_json::from_slice(body) {
+        Ok(challenges) => challenges,
+        Err(e) => return (
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({ "error": format!("expected a JSON array of challenges: {}", e) })
+                .to_string(),
+        ),
+    };
+    if challenges.len() > MAX_SOLVE_BATCH {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            serde_json::json!({
+                "error": format!("at most {} challenges per batch", MAX_SOLVE_BATCH)
+            })
+            .to_string(),
+        );
+    }
+
+    let challenges: Vec<Result<AnubisChallenge, String>> = challenges
+        .into_iter()
+        .map(|value| {
+            let mut challenge: AnubisChallenge =
+                serde_json::from_value(value).map_err(|e| format!("malformed challenge: {}", e))?;
+            if let Some(algorithm) = &config.default_algorithm {
+                challenge.set_default_algorithm(algorithm);
+            }
+            Ok(challenge)
+        })
+        .collect();
+
+    // A PoW challenge takes 16^difficulty hashes on average.
+    let work = challenges
+        .iter()
+        .flatten()
+        .filter(|challenge| !matches!(challenge.algorithm(), "preact" | "metarefresh"))
+        .fold(0u64, |work, challenge| {
+            work.saturating_add(16u64.saturating_pow(challenge.rules.difficulty as u32))
+        });
+    if work > MAX_SOLVE_WORK {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            serde_json::json!({
+                "error": format!("batch needs about {} hashes, limit is {}", work, MAX_SOLVE_WORK)
+            })
+            .to_string(),
+        );
+    }
+
+    let results: Vec<serde_json::Value> = challenges
+        .into_iter()
+        .map(|challenge| {
+            match challenge.and_then(|challenge| {
+                solve_challenge_with_options::<fn(u64)>(&challenge, None, &config.solve_options)
+            }) {
+                Ok(result) => serde_json::json!({ "result": result }),
+                Err(e) => serde_json::json!({ "error": e }),
+            }
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        serde_json::Value::Array(results).to_string(),
+    )
+}
+
+/// Solves and verifies a small synthetic challenge with the configured solver
+/// settings, so `/health?deep=1` fails on an instance that can't pass challenges.
+fn deep_health_check(options: &SolveOptions) -> Result<(), String> {
+    let challenge: AnubisChallenge = serde_json::from_str(
+        r#"{"challenge":"anubis-proxy-health-check","rules":{"difficulty":4,"algorithm":"fast"}}"#,
+    )
+    .map_err(|e| e.to_string())?;
+    let result = solve_challenge_with_options::<fn(u64)>(&challenge, None, options)?;
+    verify_solution_with_options(&challenge, &result, options)
+}
+
+/// How long a `/health?deep=1` result is reused, so frequent probes run at
+/// most one solve per interval.
+const DEEP_HEALTH_CACHE: Duration = Duration::from_secs(10);
+
+/// The last [`deep_health_check`] and when it ran. Probes share one lock, so
+/// concurrent ones wait for a single solve instead of each running their own.
+#[derive(Default)]
+struct DeepHealth(tokio::sync::Mutex<Option<(Instant, Result<(), String>)>>);
+
+impl DeepHealth {
+    async fn check(&self, options: &SolveOptions) -> Result<(), String> {
+        let mut last = self.0.lock().await;
+        if let Some((checked, result)) = &*last {
+            if checked.elapsed() < DEEP_HEALTH_CACHE {
+                return result.clone();
+            }
+        }
+        // Solving blocks, so keep it off the async workers.
+        let options = options.clone();
+        let result = tokio::task::spawn_blocking(move || deep_health_check(&options))
+            .await
+            .unwrap_or_else(|e| Err(format!("solver panicked: {}", e)));
+        if let Err(e) = &result {
+            error!("Deep health check failed: {}", e);
+        }
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+/// Handshake headers relayed from the client's upgrade request to upstream.
+/// `Origin` is left out, since it names the proxy rather than the site.
+const WEBSOCKET_REQUEST_HEADERS: &[&str] = &[
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
+
+fn is_websocket_upgrade(headers: &hyper::HeaderMap) -> bool {
+    let has_token = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+    has_token(hyper::header::CONNECTION, "upgrade")
+        && has_token(hyper::header::UPGRADE, "websocket")
+}
+
+/// Passes a WebSocket connection through once the host's session is valid.
+/// A plain GET of the target runs the usual challenge solving first, so the
+/// upgrade request goes out with the session cookie.
+async fn proxy_websocket(
+    mut req: Request<hyper::body::Incoming>,
+    url: &str,
+    host: &str,
+    sessions: SessionCache,
+    config: &ProxyConfig,
+) -> Result<Response<ProxyBody>, Box<dyn std::error::Error + Send + Sync>> {
+    proxy_request(&ClientRequest::get(), url, host, sessions.clone(), config).await?;
+
+    let session = get_or_create_session(&sessions, host, config)?;
+    // Upgrades only exist in HTTP/1.1, so don't let ALPN pick HTTP/2.
+    let client = client_builder(config, session.jar).http1_only().build()?;
+    let upstream = open_upstream_websocket(
+        &client,
+        url,
+        &session.user_agent,
+        req.headers(),
+        config.timeouts.read,
+    )
+    .await?;
+
+    let status = upstream.status();
+    let mut builder = Response::builder().status(status);
+    for (name, value) in upstream.headers() {
+        builder = builder.header(name, value);
+    }
+    if status != reqwest::StatusCode::SWITCHING_PROTOCOLS {
+        let body = tokio::time::timeout(config.timeouts.read, upstream.bytes()).await??;
+        return Ok(builder.body(full(body)).unwrap());
+    }
+
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let host = host.to_string();
+    tokio::spawn(async move {
+        let relayed = async {
+            let mut upstream = upstream.upgrade().await?;
+            let mut client = TokioIo::new(client_upgrade.await?);
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+        match relayed.await {
+            Ok(()) => debug!("WebSocket to {} closed", host),
+            Err(e) => error!("WebSocket to {} failed: {}", host, e),
+        }
+    });
+
+    Ok(builder.body(full(Bytes::new())).unwrap())
+}
+
+/// Sends the upgrade request upstream with the session's user agent and
+/// browser headers, relaying the client's handshake headers. Anubis ties the
+/// session cookie to the user agent that solved the challenge, so the upgrade
+/// has to look like the same browser.
+async fn open_upstream_websocket(
+    client: &reqwest::Client,
+    url: &str,
+    user_agent: &str,
+    client_headers: &hyper::HeaderMap,
+    timeout: Duration,
+) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+    let mut headers = navigation_headers(user_agent, true);
+    // Browsers mark WebSocket handshakes as their own fetch mode.
+    for name in ["upgrade-insecure-requests", "sec-fetch-user"] {
+        headers.remove(name);
+    }
+    for name in ["sec-fetch-mode", "sec-fetch-dest"] {
+        if headers.contains_key(name) {
+            headers.insert(name, reqwest::header::HeaderValue::from_static("websocket"));
+        }
+    }
+    let mut request = client
+        .get(url)
+        .headers(headers)
+        .header(reqwest::header::CONNECTION, "Upgrade")
+        .header(reqwest::header::UPGRADE, "websocket");
+    for &name in WEBSOCKET_REQUEST_HEADERS {
+        for value in client_headers.get_all(name) {
+            request = request.header(name, value);
+        }
+    }
+    Ok(tokio::time::timeout(timeout, request.send()).await??)
+}
+
+/// Status line and headers, one per line, for diagnosing submissions
+/// (e.g. whether `Set-Cookie` was sent and where `Location` points).
+fn format_response_head(status: StatusCode, headers: &HeaderMap) -> String {
+    let mut out = format!("  {}\n", status);
+    for (name, value) in headers {
+        out.push_str(&format!(
+            "  {}: {}\n",
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+    out
+}
+
+fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+fn stream_body(resp: reqwest::Response) -> ProxyBody {
+    let frames = resp
+        .bytes_stream()
+        .map_ok(Frame::data)
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
+    StreamBody::new(frames).boxed_unsync()
+}
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"))
+}
+
+/// Sends a request upstream and reads its body, unless it is an event stream.
+async fn send_upstream(
+    request: reqwest::RequestBuilder,
+    timeout: Duration,
+) -> Result<UpstreamResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let resp = tokio::time::timeout_at(deadline, request.send()).await??;
+    let status = resp.status();
+    let headers = resp.headers().clone();
+
+    if is_event_stream(&headers) {
+        return Ok((status, headers, UpstreamBody::Streaming(resp)));
+    }
+
+    let body = tokio::time::timeout_at(deadline, resp.bytes()).await??;
+    Ok((status, headers, UpstreamBody::Buffered(body.to_vec())))
+}
+
+/// Builds the upstream client for one host, applying the proxy-wide settings.
+fn build_client(
+    config: &ProxyConfig,
+    jar: Arc<HostJar>,
+) -> Result<reqwest::Client, reqwest::Error> {
+    client_builder(config, jar).build()
+}
+
+fn client_builder(config: &ProxyConfig, jar: Arc<HostJar>) -> reqwest::ClientBuilder {
+    // No overall client timeout: event streams stay open indefinitely, so
+    // send_upstream bounds everything else instead.
+    // rustls keeps TLS sessions (tickets) per client and resumes them when a
+    // pooled connection to the host has idled out; native-tls doesn't.
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .connect_timeout(config.timeouts.connect)
+        .cookie_provider(jar)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(proxy) = &config.upstream_proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    if let Some(addr) = config.local_address {
+        builder = builder.local_address(addr);
+    }
+    builder
+}
+
+fn get_or_create_session(
+    sessions: &SessionCache,
+    host: &str,
+    config: &ProxyConfig,
+) -> Result<HostSession, reqwest::Error> {
+    let session = sessions.entry(host.to_string()).or_try_insert_with(|| {
+        let jar = Arc::new(HostJar::default());
+        Ok(HostSession {
+            client: build_client(config, jar.clone())?,
+            jar,
+            difficulties: Arc::default(),
+            passed: Arc::default(),
+            user_agent: get_chrome_rua().into(),
+            algorithm: Arc::default(),
+            https: Arc::new(AtomicBool::new(true)),
+        })
+    })?;
+    Ok(session.clone())
+}
+
+/// Solves `challenge` with `solve_options`, waits out its `min_wait` and
+/// submits it with `submission`.
+#[allow(clippy::too_many_arguments)]
+async fn solve_and_submit(
+    session: &HostSession,
+    original_url: &str,
+    host: &str,
+    user_agent: &str,
+    challenge: &AnubisChallenge,
+    solve_options: &SolveOptions,
+    submission: SubmissionOptions,
+    config: &ProxyConfig,
+) -> Result<SubmissionOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let permit = match &config.solve_limit {
+        Some(limit) => Some(limit.acquire(host).await?),
+        None => None,
+    };
+    let start_time = Instant::now();
+
+    // Solving blocks, and so does the first hashrate measurement, so keep
+    // them off the async workers. The permit goes with the task, so a solve
+    // keeps its slot even if the client gives up on it; the min_wait below
+    // needs no CPU and holds none.
+    let solved = {
+        let host = host.to_string();
+        let challenge = challenge.clone();
+        let solve_options = solve_options.clone();
+        tokio::task::spawn_blocking(move || {
+            warn_if_solve_is_slow(&host, &challenge, &solve_options);
+            let result = solve_challenge_with_options::<fn(u64)>(&challenge, None, &solve_options);
+            drop(permit);
+            result
+        })
+        .await
+        .unwrap_or_else(|e| Err(format!("solver panicked: {}", e)))
+    };
+    let result = match solved {
+        Ok(result) => result,
+        Err(e) => {
+            config
+                .metrics
+                .solve_failures
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(e.into());
+        }
+    };
+    config.metrics.observe_solve(start_time.elapsed());
+
+    if let Some(min_wait) = challenge.min_wait() {
+        let elapsed = start_time.elapsed();
+        if elapsed < min_wait {
+            if config.skip_min_wait {
+                info!(
+                    "Skipping {:?} of min_wait for {} challenge",
+                    min_wait - elapsed,
+                    challenge.algorithm()
+                );
+            } else {
+                tokio::time::sleep(min_wait - elapsed).await;
+            }
+        }
+    }
+
+    let elapsed_time = start_time.elapsed();
+    info!(
+        "Solved {} challenge in {:?}",
+        challenge.algorithm(),
+        elapsed_time
+    );
+    if let Some(webhook) = &config.solve_webhook {
+        webhook.notify(serde_json::json!({
+            "host": host,
+            "difficulty": challenge.rules.difficulty,
+            "algorithm": challenge.algorithm(),
+            "solve_ms": elapsed_time.as_millis() as u64,
+        }));
+    }
+
+    let scheme = config
+        .submit_scheme
+        .unwrap_or(if original_url.starts_with("http://") {
+            "http"
+        } else {
+            "https"
+        });
+    let context = SubmissionContext {
+        client: &session.client,
+        scheme,
+        host,
+        original_url,
+        user_agent,
+        elapsed: elapsed_time,
+        options: submission,
+        config,
+    };
+    match &config.submission_backend {
+        Some(backend) => backend.submit(challenge, &result, &context).await,
+        None => GetSubmission.submit(challenge, &result, &context).await,
+    }
+}
+
+/// Nonce formats, answer formats and concat orders `AUTODETECT` tries after a
+/// rejected PoW submission, the most common first.
+///
+/// Difficulty counted in bits needs no probe: a hash with `d` leading zero
+/// nibbles has at least `d` leading zero bits, so a server that counts bits
+/// accepts every solution, only at more work than it asked for. Salts and
+/// padded or separated nonces are fork-specific and aren't guessed.
+const AUTODETECT_VARIANTS: &[(NonceFormat, AnswerFormat, ConcatOrder)] = &[
+    (
+        NonceFormat::Decimal,
+        AnswerFormat::Hash,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Decimal,
+        AnswerFormat::HashAndNonce,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Hex,
+        AnswerFormat::Hash,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Hex,
+        AnswerFormat::HashAndNonce,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Decimal,
+        AnswerFormat::Preimage,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Hex,
+        AnswerFormat::Preimage,
+        ConcatOrder::ChallengeThenNonce,
+    ),
+    (
+        NonceFormat::Decimal,
+        AnswerFormat::Hash,
+        ConcatOrder::NonceThenChallenge,
+    ),
+    (
+        NonceFormat::Decimal,
+        AnswerFormat::HashAndNonce,
+        ConcatOrder::NonceThenChallenge,
+    ),
+];
+/// Most probe submissions one rejection may trigger, so a server that
+/// rejects everything isn't hammered with solves.
+const MAX_AUTODETECT_PROBES: usize = 7;
+
+/// Retries a rejected PoW solve with the other [`AUTODETECT_VARIANTS`], each
+/// on a freshly fetched challenge, and logs the settings of the first one the
+/// server accepts, then sends `request` again. `None` if none was accepted.
+async fn autodetect(
+    session: &HostSession,
+    request: &ClientRequest,
+    original_url: &str,
+    host: &str,
+    user_agent: &str,
+    config: &ProxyConfig,
+) -> Result<Option<UpstreamResponse>, Box<dyn std::error::Error + Send + Sync>> {
+    let configured = (
+        config.solve_options.nonce_format,
+        config.submission.answer_format,
+        config.solve_options.concat_order,
+    );
+    let variants = AUTODETECT_VARIANTS
+        .iter()
+        .filter(|&&variant| variant != configured)
+        .take(MAX_AUTODETECT_PROBES);
+    for (probe, &(nonce_format, answer_format, concat_order)) in variants.enumerate() {
+        let page = send_upstream(
+            session
+                .client
+                .get(original_url)
+                .headers(navigation_headers(user_agent, true)),
+            config.timeouts.read,
+        )
+        .await?;
+        let parsed = match &page.2 {
+            UpstreamBody::Buffered(body) => try_parse_challenge_from_html_with_selectors(
+                &String::from_utf8_lossy(body),
+                &config.selectors,
+            )?,
+            UpstreamBody::Streaming(_) => None,
+        };
+        let Some(mut parsed) = parsed else {
+            info!("Autodetect: {} no longer serves a challenge", host);
+            // The probe fetched the page with a GET; any other request is
+            // sent again as the client made it.
+            if request.method == Method::GET {
+                return Ok(Some(page));
+            }
+            let response = send_upstream(
+                request.to_upstream(&session.client, original_url, user_agent, true),
+                config.timeouts.read,
+            )
+            .await?;
+            return Ok(Some(response));
+        };
+        if let Some(algorithm) = &config.default_algorithm {
+            parsed.challenge.set_default_algorithm(algorithm);
+        }
+
+        info!(
+            "Autodetect probe {}/{} for {}: NONCE_FORMAT={} ANSWER_FORMAT={} CONCAT_ORDER={}",
+            probe + 1,
+            MAX_AUTODETECT_PROBES,
+            host,
+            nonce_format,
+            answer_format,
+            concat_order
+        );
+        let solve_options = SolveOptions {
+            nonce_format,
+            concat_order,
+            ..config.solve_options.clone()
+        };
+        let mut submission = config.submission.for_version(&parsed.version);
+        submission.answer_format = answer_format;
+        match solve_and_submit(
+            session,
+            original_url,
+            host,
+            user_agent,
+            &parsed.challenge,
+            &solve_options,
+            submission,
+            config,
+        )
+        .await?
+        {
+            SubmissionOutcome::Passed | SubmissionOutcome::AlreadyUsed(_) => {
+                info!(
+                    "Autodetect: {} accepted NONCE_FORMAT={} ANSWER_FORMAT={} CONCAT_ORDER={}",
+                    host, nonce_format, answer_format, concat_order
+                );
+                mark_passed(session, host, config).await;
+                let response = send_upstream(
+                    request.to_upstream(&session.client, original_url, user_agent, true),
+                    config.timeouts.read,
+                )
+                .await?;
+                return Ok(Some(response));
+            }
+            SubmissionOutcome::Rejected(response) => {
+                info!("Autodetect probe rejected with {}", response.0);
+            }
+        }
+    }
+    warn!(
+        "Autodetect: {} rejected every probed nonce and answer format",
+        host
+    );
+    Ok(None)
+}
+
+/// A client's request to proxy, kept so it can be sent again once a
+/// challenge it ran into is passed.
+struct ClientRequest {
+    method: Method,
+    /// Sent along with `body`, so form posts keep their encoding.
+    content_type: Option<hyper::header::HeaderValue>,
+    /// Sent along with `body`, which is forwarded still compressed.
+    content_encoding: Option<hyper::header::HeaderValue>,
+    body: Bytes,
+}
+
+impl ClientRequest {
+    fn get() -> Self {
+        ClientRequest {
+            method: Method::GET,
+            content_type: None,
+            content_encoding: None,
+            body: Bytes::new(),
+        }
+    }
+
+    /// This request to `url` with `user_agent`'s navigation headers. reqwest
+    /// sets `Content-Length` from the body.
+    fn to_upstream(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        user_agent: &str,
+        same_origin: bool,
+    ) -> reqwest::RequestBuilder {
+        let mut request = client
+            .request(self.method.clone(), url)
+            .headers(navigation_headers(user_agent, same_origin));
+        if let Some(content_type) = &self.content_type {
+            request = request.header(hyper::header::CONTENT_TYPE, content_type.clone());
+        }
+        if let Some(content_encoding) = &self.content_encoding {
+            request = request.header(hyper::header::CONTENT_ENCODING, content_encoding.clone());
+        }
+        if !self.body.is_empty() {
+            request = request.body(self.body.clone());
+        }
+        request
+    }
+}
+
+async fn proxy_request(
+    request: &ClientRequest,
+    url: &str,
+    host: &str,
+    sessions: SessionCache,
+    config: &ProxyConfig,
+) -> Result<(UpstreamResponse, AnubisStatus), Box<dyn std::error::Error + Send + Sync>> {
+    let session = get_or_create_session(&sessions, host, config)?;
+    session
+        .https
+        .store(url.starts_with("https://"), Ordering::Relaxed);
+    let user_agent = &*session.user_agent;
+    let client = &session.client;
+    let without_challenge = if session.passed.load(Ordering::Relaxed) {
+        AnubisStatus::CachedSession
+    } else {
+        AnubisStatus::NoChallenge
+    };
+
+    let (status, headers, body) = send_upstream(
+        request.to_upstream(client, url, user_agent, false),
+        config.timeouts.read,
+    )
+    .await?;
+    let body = match body {
+        UpstreamBody::Buffered(body) => body,
+        streaming => return Ok(((status, headers, streaming), without_challenge)),
+    };
+
+    let html = String::from_utf8_lossy(&body);
+    if let Some(mut parsed) =
+        try_parse_challenge_from_html_with_selectors(&html, &config.selectors)?
+    {
+        if let Some(algorithm) = &config.default_algorithm {
+            parsed.challenge.set_default_algorithm(algorithm);
+        }
+        info!(
+            "Detected Anubis {} challenge for {} (algorithm={}, difficulty={})",
+            parsed.version,
+            host,
+            parsed.challenge.algorithm(),
+            parsed.challenge.rules.difficulty
+        );
+        config
+            .metrics
+            .challenges_detected
+            .fetch_add(1, Ordering::Relaxed);
+        *session.algorithm.lock().unwrap() = Some(parsed.challenge.algorithm().to_string());
+        {
+            let mut difficulties = session.difficulties.lock().unwrap();
+            if let Some(previous) = difficulties.record(parsed.challenge.rules.difficulty) {
+                warn!(
+                    "Difficulty for {} rose from {} to {} (history: {:?}); the server may be rate-limiting by escalating difficulty",
+                    host,
+                    previous,
+                    parsed.challenge.rules.difficulty,
+                    difficulties.observed()
+                );
+            }
+        }
+        let unrecognized = parsed.challenge.unrecognized_fields();
+        if !unrecognized.is_empty() {
+            info!(
+                "Unrecognized challenge fields: {}",
+                serde_json::Value::Object(unrecognized)
+            );
+        }
+        let response =
+            solve_and_retry(&session, request, url, host, user_agent, parsed, config).await?;
+        return Ok((response, AnubisStatus::Solved));
+    }
+
+    Ok((
+        (status, headers, UpstreamBody::Buffered(body)),
+        without_challenge,
+    ))
+}
+
+/// Most times a host may answer an accepted solution with another challenge
+/// before the proxy gives up on it.
+const MAX_RECHALLENGES: usize = 3;
+
+/// Solves `parsed`, submits it and sends `request` again.
+///
+/// Some servers issue a fresh challenge on every GET and only accept the
+/// latest, so nothing here may fetch the page between reading the challenge
+/// and submitting it: the solution must be for the challenge from the
+/// response that served it. The only refetch is after the submission.
+///
+/// If that refetch serves a challenge again, it is solved in turn, up to
+/// [`MAX_RECHALLENGES`] times.
+async fn solve_and_retry(
+    session: &HostSession,
+    request: &ClientRequest,
+    original_url: &str,
+    host: &str,
+    user_agent: &str,
+    mut parsed: anubis_solver::ParsedChallenge,
+    config: &ProxyConfig,
+) -> Result<UpstreamResponse, Box<dyn std::error::Error + Send + Sync>> {
+    for rechallenges in 0.. {
+        let (response, passed) = solve_and_retry_once(
+            session,
+            request,
+            original_url,
+            host,
+            user_agent,
+            &parsed,
+            config,
+        )
+        .await?;
+        let (true, (_, _, UpstreamBody::Buffered(body))) = (passed, &response) else {
+            return Ok(response);
+        };
+        let body = String::from_utf8_lossy(body);
+        let Some(mut next) =
+            try_parse_challenge_from_html_with_selectors(&body, &config.selectors)?
+        else {
+            return Ok(response);
+        };
+        if rechallenges == MAX_RECHALLENGES {
+            break;
+        }
+        if let Some(algorithm) = &config.default_algorithm {
+            next.challenge.set_default_algorithm(algorithm);
+        }
+        config
+            .metrics
+            .challenges_detected
+            .fetch_add(1, Ordering::Relaxed);
+        // The same challenge again suggests the pass was not recorded (e.g.
+        // timing); a new one, that it expired or the server wants another.
+        let same = next.challenge.challenge.random_data == parsed.challenge.challenge.random_data;
+        warn!(
+            "{} served {} challenge (difficulty={}) after accepting a solution, solving again ({}/{})",
+            host,
+            if same { "the same" } else { "a new" },
+            next.challenge.rules.difficulty,
+            rechallenges + 1,
+            MAX_RECHALLENGES
+        );
+        parsed = next;
+    }
+    Err(format!(
+        "re-challenge loop: {} kept issuing challenges after {} accepted solutions",
+        host,
+        MAX_RECHALLENGES + 1
+    )
+    .into())
+}
+
+/// One solve, submission and refetch for [`solve_and_retry`], and whether
+/// the response is a refetch after a passed submission.
+async fn solve_and_retry_once(
+    session: &HostSession,
+    request: &ClientRequest,
+    original_url: &str,
+    host: &str,
+    user_agent: &str,
+    parsed: &anubis_solver::ParsedChallenge,
+    config: &ProxyConfig,
+) -> Result<(UpstreamResponse, bool), Box<dyn std::error::Error + Send + Sync>> {
+    let client = &session.client;
+    // Only PoW submissions have nonce and answer formats to autodetect;
+    // time-based challenges are the ones with a min_wait.
+    let is_pow = parsed.challenge.min_wait().is_none();
+    let submission = config.submission.for_version(&parsed.version);
+    let outcome = solve_and_submit(
+        session,
+        original_url,
+        host,
+        user_agent,
+        &parsed.challenge,
+        &config.solve_options,
+        submission,
+        config,
+    )
+    .await?;
+
+    match outcome {
+        SubmissionOutcome::Passed => {}
+        SubmissionOutcome::AlreadyUsed(submit_status) => {
+            info!(
+                "Submission rejected as already used ({}), refetching content...",
+                submit_status
+            );
+            let refetched = send_upstream(
+                request.to_upstream(client, original_url, user_agent, true),
+                config.timeouts.read,
+            )
+            .await?;
+            // Only trust the rejection if the earlier submission really
+            // passed; another challenge means it didn't.
+            if let (_, _, UpstreamBody::Buffered(body)) = &refetched {
+                let body = String::from_utf8_lossy(body);
+                if try_parse_challenge_from_html_with_selectors(&body, &config.selectors)?.is_some()
+                {
+                    return Err(format!(
+                        "Submission rejected as already used ({}) but {} still serves a challenge",
+                        submit_status, host
+                    )
+                    .into());
+                }
+            }
+            mark_passed(session, host, config).await;
+            return Ok((refetched, true));
+        }
+        SubmissionOutcome::Rejected(response) => {
+            warn!(
+                "Challenge submission returned {} instead of {} (server-side issue)",
+                response.0, config.pass_statuses
+            );
+            if config.autodetect && is_pow {
+                if let Some(response) =
+                    autodetect(session, request, original_url, host, user_agent, config).await?
+                {
+                    return Ok((response, false));
+                }
+            }
+            return Ok((*response, false));
+        }
+    }
+
+    mark_passed(session, host, config).await;
+    info!("Challenge passed, fetching content...");
+
+    let response = send_upstream(
+        request.to_upstream(client, original_url, user_agent, true),
+        config.timeouts.read,
+    )
+    .await?;
+    Ok((response, true))
+}
+
+/// What became of a submitted solution.
+enum SubmissionOutcome {
+    /// Accepted; the session cookie is set.
+    Passed,
+    /// Rejected as a repeat of an earlier submission, which may have passed.
+    AlreadyUsed(StatusCode),
+    /// Any other response, relayed to the client as is.
+    Rejected(Box<UpstreamResponse>),
+}
+
+/// Everything a [`SubmissionBackend`] needs besides the solution itself.
+struct SubmissionContext<'a> {
+    /// The host session's client, so cookies set by the response are kept.
+    client: &'a reqwest::Client,
+    scheme: &'a str,
+    host: &'a str,
+    original_url: &'a str,
+    user_agent: &'a str,
+    elapsed: Duration,
+    /// Already resolved for the server's version.
+    options: SubmissionOptions,
+    config: &'a ProxyConfig,
+}
+
+/// How a solution reaches the server, separate from how it is computed, for
+/// forks that need another transport and for testing the outcome handling.
+trait SubmissionBackend: Send + Sync {
+    fn submit<'a>(
+        &'a self,
+        challenge: &'a AnubisChallenge,
+        result: &'a SolverResult,
+        context: &'a SubmissionContext<'a>,
+    ) -> BoxFuture<'a, Result<SubmissionOutcome, Box<dyn std::error::Error + Send + Sync>>>;
+}
+
+/// The upstream Anubis transport: a GET of the submission URL, passed on a 302.
+struct GetSubmission;
+
+impl SubmissionBackend for GetSubmission {
+    fn submit<'a>(
+        &'a self,
+        challenge: &'a AnubisChallenge,
+        result: &'a SolverResult,
+        context: &'a SubmissionContext<'a>,
+    ) -> BoxFuture<'a, Result<SubmissionOutcome, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let submit_url = build_submission_url_with_options(
+                context.scheme,
+                context.host,
+                challenge,
+                result,
+                context.original_url,
+                context.elapsed.as_millis(),
+                &context.options,
+            )?;
+
+            let (submit_status, submit_headers, submit_body) = send_upstream(
+                context
+                    .client
+                    .get(&submit_url)
+                    .headers(navigation_headers(context.user_agent, true)),
+                context.config.timeouts.read,
+            )
+            .await?;
+
+            if context.config.dump_response_headers {
+                info!(
+                    "Submission response from {}:\n{}",
+                    context.host,
+                    format_response_head(submit_status, &submit_headers).trim_end()
+                );
+            }
+
+            if context.config.pass_statuses.0.contains(&submit_status) {
+                return Ok(SubmissionOutcome::Passed);
+            }
+            if let UpstreamBody::Buffered(body) = &submit_body {
+                if is_duplicate_submission(&String::from_utf8_lossy(body)) {
+                    return Ok(SubmissionOutcome::AlreadyUsed(submit_status));
+                }
+            }
+            Ok(SubmissionOutcome::Rejected(Box::new((
+                submit_status,
+                submit_headers,
+                submit_body,
+            ))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config_with_proxy(proxy_url: &str) -> ProxyConfig {
+        ProxyConfig {
+            upstream_proxy: Some(reqwest::Proxy::all(proxy_url).unwrap()),
+            ..ProxyConfig::default()
+        }
+    }
+
+    /// Serves a challenge at `/` until its cookie is set by a submission, and
+    /// `/open` without one.
+    async fn spawn_protected_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(|req: Request<hyper::body::Incoming>| async move {
+                            let passed = req
+                                .headers()
+                                .get(hyper::header::COOKIE)
+                                .is_some_and(|cookie| cookie == "passed=1");
+                            let response = match req.uri().path() {
+                                "/open" => Response::new(Full::new(Bytes::from("open"))),
+                                "/" if passed => Response::new(Full::new(Bytes::from("content"))),
+                                "/" => Response::new(Full::new(Bytes::from(
+                                    r#"<script id="anubis_challenge" type="application/json">{"challenge":"abc","rules":{"difficulty":1,"algorithm":"fast"}}</script>"#,
+                                ))),
+                                _ => Response::builder()
+                                    .status(StatusCode::FOUND)
+                                    .header(hyper::header::LOCATION, "/")
+                                    .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                    .body(Full::new(Bytes::new()))
+                                    .unwrap(),
+                            };
+                            Ok::<_, std::convert::Infallible>(response)
+                        }),
+                    ),
+                );
+            }
+        });
+        addr
+    }
+
+    /// Answers every submission with a canned outcome, checking the solution
+    /// it was given first.
+    struct FakeSubmission(fn() -> SubmissionOutcome);
+
+    impl SubmissionBackend for FakeSubmission {
+        fn submit<'a>(
+            &'a self,
+            challenge: &'a AnubisChallenge,
+            result: &'a SolverResult,
+            _context: &'a SubmissionContext<'a>,
+        ) -> BoxFuture<'a, Result<SubmissionOutcome, Box<dyn std::error::Error + Send + Sync>>>
+        {
+            Box::pin(async move {
+                verify_solution_with_options(challenge, result, &SolveOptions::default())?;
+                Ok((self.0)())
+            })
+        }
+    }
+
+    async fn solve_with_backend(
+        addr: SocketAddr,
+        path: &str,
+        outcome: fn() -> SubmissionOutcome,
+    ) -> (
+        Result<UpstreamResponse, Box<dyn std::error::Error + Send + Sync>>,
+        bool,
+    ) {
+        let config = ProxyConfig {
+            submission_backend: Some(Box::new(FakeSubmission(outcome))),
+            ..ProxyConfig::default()
+        };
+        let host = addr.to_string();
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let session = get_or_create_session(&sessions, &host, &config).unwrap();
+        let parsed = anubis_solver::parse_challenge_from_html(
+            r#"<script id="anubis_challenge">{"challenge":"abc","rules":{"difficulty":1}}</script>"#,
+        )
+        .unwrap();
+        let url = format!("http://{}{}", addr, path);
+        let response = solve_and_retry(
+            &session,
+            &ClientRequest::get(),
+            &url,
+            &host,
+            get_chrome_rua(),
+            parsed,
+            &config,
+        )
+        .await;
+        (response, session.passed.load(Ordering::Relaxed))
+    }
+
+    fn body_text(response: &UpstreamResponse) -> String {
+        match &response.2 {
+            UpstreamBody::Buffered(body) => String::from_utf8_lossy(body).into_owned(),
+            UpstreamBody::Streaming(_) => panic!("unexpected streaming body"),
+        }
+    }
+
+    /// Serves a challenge at `/echo` until its cookie is set by a submission,
+    /// then answers with the method, content type, content encoding, length and
+    /// body it got.
+    async fn spawn_echo_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(|req: Request<hyper::body::Incoming>| async move {
+                            let passed = req
+                                .headers()
+                                .get(hyper::header::COOKIE)
+                                .is_some_and(|cookie| cookie == "passed=1");
+                            let header = |name| {
+                                req.headers()
+                                    .get(name)
+                                    .map_or("-", |value| value.to_str().unwrap())
+                                    .to_string()
+                            };
+                            let response = if req.uri().path() != "/echo" {
+                                Response::builder()
+                                    .status(StatusCode::FOUND)
+                                    .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                    .body(Full::new(Bytes::new()))
+                                    .unwrap()
+                            } else if passed {
+                                let echo = format!(
+                                    "{} {} {} {} ",
+                                    req.method(),
+                                    header(hyper::header::CONTENT_TYPE),
+                                    header(hyper::header::CONTENT_ENCODING),
+                                    header(hyper::header::CONTENT_LENGTH)
+                                );
+                                let body = req.into_body().collect().await.unwrap().to_bytes();
+                                let mut echo = echo.into_bytes();
+                                echo.extend_from_slice(&body);
+                                Response::new(Full::new(Bytes::from(echo)))
+                            } else {
+                                Response::new(Full::new(Bytes::from(
+                                    r#"<script id="anubis_challenge" type="application/json">{"challenge":"abc","rules":{"difficulty":1,"algorithm":"fast"}}</script>"#,
+                                )))
+                            };
+                            Ok::<_, std::convert::Infallible>(response)
+                        }),
+                    ),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn non_get_requests_are_replayed_with_their_body_after_a_challenge() {
+        let addr = spawn_echo_upstream().await;
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig::default();
+        let url = format!("http://{}/echo", addr);
+        let request = ClientRequest {
+            method: Method::POST,
+            content_type: Some(hyper::header::HeaderValue::from_static(
+                "application/x-www-form-urlencoded",
+            )),
+            content_encoding: None,
+            body: Bytes::from("name=value&other=1"),
+        };
+
+        let (response, anubis_status) =
+            proxy_request(&request, &url, &addr.to_string(), sessions.clone(), &config)
+                .await
+                .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+        assert_eq!(
+            body_text(&response),
+            "POST application/x-www-form-urlencoded - 18 name=value&other=1"
+        );
+
+        let request = ClientRequest {
+            method: Method::DELETE,
+            content_type: None,
+            content_encoding: None,
+            body: Bytes::new(),
+        };
+        let (response, anubis_status) =
+            proxy_request(&request, &url, &addr.to_string(), sessions, &config)
+                .await
+                .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::CachedSession);
+        assert_eq!(body_text(&response), "DELETE - - - ");
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_are_forwarded_with_their_encoding() {
+        // gzip of "name=value&other=1".
+        const GZIPPED: &[u8] = &[
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 75, 204, 77, 181, 45, 75, 204, 41, 77, 85, 203,
+            47, 201, 72, 45, 178, 53, 4, 0, 123, 83, 49, 222, 18, 0, 0, 0,
+        ];
+        let addr = spawn_echo_upstream().await;
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig::default();
+        let request = ClientRequest {
+            method: Method::POST,
+            content_type: Some(hyper::header::HeaderValue::from_static(
+                "application/x-www-form-urlencoded",
+            )),
+            content_encoding: Some(hyper::header::HeaderValue::from_static("gzip")),
+            body: Bytes::from_static(GZIPPED),
+        };
+
+        let ((_, _, body), anubis_status) = proxy_request(
+            &request,
+            &format!("http://{}/echo", addr),
+            &addr.to_string(),
+            sessions,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+        let UpstreamBody::Buffered(body) = body else {
+            panic!("unexpected streaming body");
+        };
+        let head = format!(
+            "POST application/x-www-form-urlencoded gzip {} ",
+            GZIPPED.len()
+        );
+        assert_eq!(&body[..head.len()], head.as_bytes());
+        assert_eq!(&body[head.len()..], GZIPPED);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_detected_and_solved_challenges() {
+        let addr = spawn_protected_upstream().await;
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig::default();
+        proxy_request(
+            &ClientRequest::get(),
+            &format!("http://{}/", addr),
+            &addr.to_string(),
+            sessions.clone(),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let text = config.metrics.render(sessions.len());
+        for line in [
+            "anubis_proxy_challenges_detected_total 1",
+            "anubis_proxy_challenges_solved_total 1",
+            "anubis_proxy_solve_failures_total 0",
+            "anubis_proxy_sessions 1",
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "anubis_proxy_solve_duration_seconds_count 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn solve_duration_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.observe_solve(Duration::from_millis(30));
+        metrics.observe_solve(Duration::from_millis(700));
+        metrics.observe_solve(Duration::from_secs(60));
+        let text = metrics.render(0);
+        for line in [
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"0.01\"} 0",
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"0.05\"} 1",
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"1\"} 2",
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"30\"} 2",
+            "anubis_proxy_solve_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "anubis_proxy_solve_duration_seconds_sum 60.73",
+            "anubis_proxy_solve_duration_seconds_count 3",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn solves_past_the_limit_queue_and_time_out() {
+        let addr = spawn_protected_upstream().await;
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig {
+            solve_limit: Some(SolveLimit::new(1, Some(Duration::from_millis(50)))),
+            ..ProxyConfig::default()
+        };
+        let url = format!("http://{}/", addr);
+        let host = addr.to_string();
+        let limit = config.solve_limit.as_ref().unwrap();
+
+        let held = limit.permits.acquire().await.unwrap();
+        let error = proxy_request(
+            &ClientRequest::get(),
+            &url,
+            &host,
+            sessions.clone(),
+            &config,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(error.is::<SolveQueueTimeout>(), "{}", error);
+
+        // A queued solve goes ahead once the permit is released in time.
+        let release = async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(held);
+        };
+        let request = ClientRequest::get();
+        let (result, ()) = tokio::join!(
+            proxy_request(&request, &url, &host, sessions.clone(), &config),
+            release
+        );
+        let (response, anubis_status) = result.unwrap();
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+        assert_eq!(body_text(&response), "content");
+        assert_eq!(limit.permits.available_permits(), 1);
+
+        // Cached sessions don't need a permit.
+        let _held = limit.permits.acquire().await.unwrap();
+        let (_, anubis_status) =
+            proxy_request(&ClientRequest::get(), &url, &host, sessions, &config)
+                .await
+                .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::CachedSession);
+    }
+
+    #[tokio::test]
+    async fn solves_leave_the_event_loop_running() {
+        let config = ProxyConfig {
+            solve_limit: Some(SolveLimit::new(1, None)),
+            ..ProxyConfig::default()
+        };
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let session = get_or_create_session(&sessions, "example.com", &config).unwrap();
+        let challenge: AnubisChallenge = serde_json::from_str(
+            r#"{"challenge":"abc","rules":{"difficulty":64,"algorithm":"fast"}}"#,
+        )
+        .unwrap();
+        let solve_options = SolveOptions {
+            max_time: Some(Duration::from_millis(300)),
+            ..SolveOptions::default()
+        };
+
+        // The test runtime has a single thread, so ticks only happen while
+        // the solve runs if it runs elsewhere.
+        let ticks = async {
+            let mut ticks = 0;
+            let mut interval = tokio::time::interval(Duration::from_millis(10));
+            for _ in 0..10 {
+                interval.tick().await;
+                ticks += 1;
+            }
+            ticks
+        };
+        let (result, ticks) = tokio::join!(
+            solve_and_submit(
+                &session,
+                "https://example.com/",
+                "example.com",
+                get_chrome_rua(),
+                &challenge,
+                &solve_options,
+                SubmissionOptions::default(),
+                &config,
+            ),
+            tokio::time::timeout(Duration::from_millis(250), ticks)
+        );
+        assert!(result.err().unwrap().to_string().contains("Time limit"));
+        assert_eq!(ticks, Ok(10));
+        assert_eq!(config.solve_limit.unwrap().permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn submission_outcomes_decide_what_the_client_gets() {
+        let addr = spawn_protected_upstream().await;
+
+        let (response, passed) =
+            solve_with_backend(addr, "/open", || SubmissionOutcome::Passed).await;
+        assert_eq!(body_text(&response.unwrap()), "open");
+        assert!(passed);
+
+        let (response, passed) = solve_with_backend(addr, "/open", || {
+            SubmissionOutcome::Rejected(Box::new((
+                StatusCode::FORBIDDEN,
+                HeaderMap::new(),
+                UpstreamBody::Buffered(b"bad nonce".to_vec()),
+            )))
+        })
+        .await;
+        let response = response.unwrap();
+        assert_eq!(response.0, StatusCode::FORBIDDEN);
+        assert_eq!(body_text(&response), "bad nonce");
+        assert!(!passed);
+
+        // An "already used" rejection is only trusted if the page is now open.
+        let (response, passed) = solve_with_backend(addr, "/open", || {
+            SubmissionOutcome::AlreadyUsed(StatusCode::FORBIDDEN)
+        })
+        .await;
+        assert_eq!(body_text(&response.unwrap()), "open");
+        assert!(passed);
+
+        let (response, passed) = solve_with_backend(addr, "/", || {
+            SubmissionOutcome::AlreadyUsed(StatusCode::FORBIDDEN)
+        })
+        .await;
+        assert!(response.is_err());
+        assert!(!passed);
+    }
+
+    /// Serves a new challenge on every GET of `/` and only accepts a
+    /// submission for the latest one, like one-shot challenge servers.
+    async fn spawn_rotating_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let issued = Arc::new(std::sync::Mutex::new(0u32));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let issued = issued.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let issued = issued.clone();
+                        async move {
+                            let challenge_json = |n: u32| {
+                                format!(
+                                    r#"{{"challenge":{{"id":"c{0}","randomData":"data{0}"}},"rules":{{"difficulty":2}}}}"#,
+                                    n
+                                )
+                            };
+                            let passed = req
+                                .headers()
+                                .get(hyper::header::COOKIE)
+                                .is_some_and(|cookie| cookie == "passed=1");
+                            let response = if req.uri().path() == "/" {
+                                if passed {
+                                    Response::new(Full::new(Bytes::from("content")))
+                                } else {
+                                    let mut issued = issued.lock().unwrap();
+                                    *issued += 1;
+                                    Response::new(Full::new(Bytes::from(format!(
+                                        r#"<script id="anubis_challenge">{}</script>"#,
+                                        challenge_json(*issued)
+                                    ))))
+                                }
+                            } else {
+                                let latest: AnubisChallenge =
+                                    serde_json::from_str(&challenge_json(*issued.lock().unwrap()))
+                                        .unwrap();
+                                let url = format!("http://{}{}", addr, req.uri());
+                                if anubis_solver::simulate_server_verification(&latest, &url) {
+                                    Response::builder()
+                                        .status(StatusCode::FOUND)
+                                        .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                        .body(Full::new(Bytes::new()))
+                                        .unwrap()
+                                } else {
+                                    Response::builder()
+                                        .status(StatusCode::FORBIDDEN)
+                                        .body(Full::new(Bytes::from("stale challenge")))
+                                        .unwrap()
+                                }
+                            };
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    }),
+                ));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn snapshots_restore_host_sessions() {
+        let config = ProxyConfig::default();
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let session = get_or_create_session(&sessions, "example.com", &config).unwrap();
+        let url = host_url("https", "example.com").unwrap();
+        session
+            .jar
+            .add_cookie_str("techaro.lol-anubis-auth=token; Path=/; Max-Age=3600", &url);
+        session
+            .jar
+            .add_cookie_str("theme=dark; Path=/docs; Domain=example.com", &url);
+        session.difficulties.lock().unwrap().record(4);
+        session.difficulties.lock().unwrap().record(5);
+        *session.algorithm.lock().unwrap() = Some("fast".to_string());
+        session.passed.store(true, Ordering::Relaxed);
+
+        let path =
+            std::env::temp_dir().join(format!("anubis-proxy-snapshot-{}.json", std::process::id()));
+        let mut states = snapshot_sessions(&sessions);
+        // Expired by the time the snapshot is read back.
+        states
+            .get_mut("example.com")
+            .unwrap()
+            .cookies
+            .push(StoredCookie {
+                name: "stale".to_string(),
+                value: "1".to_string(),
+                domain: None,
+                path: "/".to_string(),
+                expires: Some(1),
+            });
+        write_snapshot(&path, &states).await.unwrap();
+        let restored: SessionCache = Arc::new(DashMap::new());
+        restore_sessions(&restored, load_snapshot(&path).await.unwrap(), &config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let before = session.state();
+        let after = restored.get("example.com").unwrap().state();
+        let cookies = |state: &HostState| {
+            let mut cookies = state.cookies.clone();
+            cookies.sort_by(|a, b| a.name.cmp(&b.name));
+            cookies
+        };
+        let (before_cookies, after_cookies) = (cookies(&before), cookies(&after));
+        let names: Vec<&str> = after_cookies.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["techaro.lol-anubis-auth", "theme"]);
+        for (before, after) in before_cookies.iter().zip(&after_cookies) {
+            assert_eq!(
+                (&after.value, &after.domain, &after.path),
+                (&before.value, &before.domain, &before.path)
+            );
+            // Expiry is restored as a Max-Age, so it can land a second later.
+            assert_eq!(after.expires.is_some(), before.expires.is_some());
+            assert!(after.expires.unwrap_or(0) - before.expires.unwrap_or(0) <= 1);
+        }
+        assert_eq!(after_cookies[1].path, "/docs");
+        assert_eq!(after.user_agent, before.user_agent);
+        assert_eq!(after.difficulty.observed(), [4, 5]);
+        assert_eq!(after.algorithm.as_deref(), Some("fast"));
+        assert!(after.passed);
+    }
+
+    #[tokio::test]
+    async fn cookie_jars_round_trip_through_the_cookie_dir() {
+        let dir = std::env::temp_dir().join(format!("anubis-proxy-cookies-{}", std::process::id()));
+        let config = ProxyConfig {
+            cookie_dir: Some(dir.clone()),
+            ..ProxyConfig::default()
+        };
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let session = get_or_create_session(&sessions, "example.com:8443", &config).unwrap();
+        let url: reqwest::Url = "https://example.com:8443/app/page".parse().unwrap();
+        let headers = [
+            "techaro.lol-anubis-auth=token; Path=/; Max-Age=3600",
+            "stale=1; Path=/; Expires=Thu, 01 Jan 1970 00:00:01 GMT",
+            "wide=1; Domain=.Example.com; Path=/app",
+            "local=1",
+        ]
+        .map(reqwest::header::HeaderValue::from_static);
+        session.jar.set_cookies(&mut headers.iter(), &url);
+        mark_passed(&session, "example.com:8443", &config).await;
+
+        let files = load_cookie_dir(&dir).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].host, "example.com:8443");
+        let mut cookies = files[0].cookies.clone();
+        cookies.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<&str> = cookies.iter().map(|cookie| cookie.name.as_str()).collect();
+        assert_eq!(names, ["local", "techaro.lol-anubis-auth", "wide"]);
+        assert_eq!(
+            (cookies[0].domain.as_deref(), cookies[0].path.as_str()),
+            (None, "/app")
+        );
+        let expires = cookies[1].expires.unwrap();
+        assert!(
+            (3590..=3600).contains(&(expires - unix_now())),
+            "{}",
+            expires
+        );
+        assert_eq!(
+            (cookies[2].domain.as_deref(), cookies[2].path.as_str()),
+            (Some("example.com"), "/app")
+        );
+
+        // A restarted proxy sends them again, to the same paths.
+        let restored: SessionCache = Arc::new(DashMap::new());
+        restore_cookies(&restored, &config).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let session = restored.get("example.com:8443").unwrap().clone();
+        assert!(session.passed.load(Ordering::Relaxed));
+        let sent = |url: &str| {
+            let mut cookies: Vec<String> = session
+                .jar
+                .cookies(&url.parse().unwrap())
+                .map(|header| header.to_str().unwrap().to_string())
+                .unwrap_or_default()
+                .split("; ")
+                .map(str::to_string)
+                .collect();
+            cookies.sort();
+            cookies
+        };
+        assert_eq!(
+            sent("https://example.com:8443/"),
+            ["techaro.lol-anubis-auth=token"]
+        );
+        assert_eq!(
+            sent("https://example.com:8443/app/x"),
+            ["local=1", "techaro.lol-anubis-auth=token", "wide=1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_http_hosts_keep_their_scheme_across_restarts() {
+        let addr = spawn_protected_upstream().await;
+        let host = addr.to_string();
+        let url = format!("http://{}/", addr);
+        let dir =
+            std::env::temp_dir().join(format!("anubis-proxy-http-cookies-{}", std::process::id()));
+        let path = std::env::temp_dir().join(format!(
+            "anubis-proxy-http-snapshot-{}.json",
+            std::process::id()
+        ));
+        let config = ProxyConfig {
+            cookie_dir: Some(dir.clone()),
+            snapshot: Some(SnapshotConfig {
+                path: path.clone(),
+                interval: DEFAULT_SNAPSHOT_INTERVAL,
+            }),
+            ..ProxyConfig::default()
+        };
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let (_, anubis_status) = proxy_request(
+            &ClientRequest::get(),
+            &url,
+            &host,
+            sessions.clone(),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+        persist_sessions(&sessions, &config).await;
+
+        let files = load_cookie_dir(&dir).await.unwrap();
+        assert_eq!(files[0].scheme, "http");
+        let from_cookie_dir: SessionCache = Arc::new(DashMap::new());
+        restore_cookies(&from_cookie_dir, &config).await.unwrap();
+        let from_snapshot: SessionCache = Arc::new(DashMap::new());
+        restore_sessions(&from_snapshot, load_snapshot(&path).await.unwrap(), &config).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for restored in [from_cookie_dir, from_snapshot] {
+            let session = restored.get(&host).unwrap().clone();
+            assert_eq!(session.scheme(), "http");
+            assert_eq!(
+                session.jar.cookies(&url.parse().unwrap()).unwrap(),
+                "passed=1"
+            );
+            let (_, anubis_status) = proxy_request(
+                &ClientRequest::get(),
+                &url,
+                &host,
+                restored,
+                &ProxyConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(anubis_status, AnubisStatus::CachedSession);
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_snapshot_is_empty() {
+        let path = std::env::temp_dir().join("anubis-proxy-snapshot-missing.json");
+        assert!(load_snapshot(&path).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_submit_params() {
+        assert_eq!(
+            parse_submit_params("site_key=abc, v=1.2=3,empty=").unwrap(),
+            [
+                ("site_key".to_string(), "abc".to_string()),
+                ("v".to_string(), "1.2=3".to_string()),
+                ("empty".to_string(), String::new()),
+            ]
+        );
+        assert!(parse_submit_params("").unwrap().is_empty());
+        assert!(parse_submit_params("novalue").is_err());
+        assert!(parse_submit_params("=x").is_err());
+    }
+
+    /// Serves the same challenge at `/` until a submission passes `accept`,
+    /// and counts submissions.
+    async fn spawn_picky_upstream(
+        accept: fn(&anubis_solver::SubmissionParams) -> bool,
+    ) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let submissions = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = submissions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let counter = counter.clone();
+                        async move {
+                            let passed = req
+                                .headers()
+                                .get(hyper::header::COOKIE)
+                                .is_some_and(|cookie| cookie == "passed=1");
+                            let response = if req.uri().path() == "/" {
+                                Response::new(Full::new(Bytes::from(if passed {
+                                    "content"
+                                } else {
+                                    r#"<script id="anubis_challenge">{"challenge":"abc","rules":{"difficulty":1}}</script>"#
+                                })))
+                            } else {
+                                counter.fetch_add(1, Ordering::Relaxed);
+                                let params = anubis_solver::parse_submission_url(&format!(
+                                    "http://{}{}",
+                                    addr,
+                                    req.uri()
+                                ))
+                                .unwrap();
+                                if accept(&params) {
+                                    Response::builder()
+                                        .status(StatusCode::FOUND)
+                                        .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                        .body(Full::new(Bytes::new()))
+                                        .unwrap()
+                                } else {
+                                    Response::builder()
+                                        .status(StatusCode::FORBIDDEN)
+                                        .body(Full::new(Bytes::from("rejected")))
+                                        .unwrap()
+                                }
+                            };
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    }),
+                ));
+            }
+        });
+        (addr, submissions)
+    }
+
+    async fn fetch_with_autodetect(addr: SocketAddr) -> UpstreamResponse {
+        let config = ProxyConfig {
+            autodetect: true,
+            ..ProxyConfig::default()
+        };
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let (response, _) = proxy_request(
+            &ClientRequest::get(),
+            &format!("http://{}/", addr),
+            &addr.to_string(),
+            sessions,
+            &config,
+        )
+        .await
+        .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn autodetect_finds_the_accepted_answer_format() {
+        let (addr, submissions) = spawn_picky_upstream(|params| {
+            let nonce = params.get("nonce").unwrap();
+            let hash = hex::encode(sha2::Sha256::digest(format!("abc{}", nonce)));
+            params.get("response") == Some(format!("{}:{}", hash, nonce).as_str())
+        })
+        .await;
+        let response = fetch_with_autodetect(addr).await;
+        assert_eq!(body_text(&response), "content");
+        // The configured hash format, then `hash:nonce` as the first probe.
+        assert_eq!(submissions.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn autodetect_tries_the_nonce_before_the_challenge() {
+        let (addr, submissions) = spawn_picky_upstream(|params| {
+            let nonce = params.get("nonce").unwrap();
+            let hash = hex::encode(sha2::Sha256::digest(format!("{}abc", nonce)));
+            params.get("response") == Some(hash.as_str())
+        })
+        .await;
+        let response = fetch_with_autodetect(addr).await;
+        assert_eq!(body_text(&response), "content");
+        // Nonce-first decimal hashes are the sixth probe.
+        assert_eq!(submissions.load(Ordering::Relaxed), 7);
+    }
+
+    #[tokio::test]
+    async fn autodetect_gives_up_after_a_bounded_number_of_probes() {
+        let (addr, submissions) = spawn_picky_upstream(|_| false).await;
+        let response = fetch_with_autodetect(addr).await;
+        assert_eq!(response.0, StatusCode::FORBIDDEN);
+        assert_eq!(
+            submissions.load(Ordering::Relaxed),
+            1 + MAX_AUTODETECT_PROBES
+        );
+    }
+
+    /// Serves a new challenge for the first `challenges` page fetches, cookie
+    /// or not, then the content, and accepts every submission, counting them.
+    async fn spawn_rechallenging_upstream(
+        challenges: usize,
+    ) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pages = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let submissions = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = submissions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let pages = pages.clone();
+                let submissions = submissions.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let pages = pages.clone();
+                        let submissions = submissions.clone();
+                        async move {
+                            let response = if req.uri().path() == "/" {
+                                let page = pages.fetch_add(1, Ordering::Relaxed);
+                                if page < challenges {
+                                    Response::new(Full::new(Bytes::from(format!(
+                                        r#"<script id="anubis_challenge">{{"challenge":"data{}","rules":{{"difficulty":1}}}}</script>"#,
+                                        page
+                                    ))))
+                                } else {
+                                    Response::new(Full::new(Bytes::from("content")))
+                                }
+                            } else {
+                                submissions.fetch_add(1, Ordering::Relaxed);
+                                Response::builder()
+                                    .status(StatusCode::FOUND)
+                                    .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                    .body(Full::new(Bytes::new()))
+                                    .unwrap()
+                            };
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    }),
+                ));
+            }
+        });
+        (addr, counted)
+    }
+
+    #[tokio::test]
+    async fn challenges_served_after_a_pass_are_solved_a_bounded_number_of_times() {
+        let config = ProxyConfig::default();
+
+        let (addr, submissions) = spawn_rechallenging_upstream(2).await;
+        let (response, anubis_status) = proxy_request(
+            &ClientRequest::get(),
+            &format!("http://{}/", addr),
+            &addr.to_string(),
+            Arc::new(DashMap::new()),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+        assert_eq!(body_text(&response), "content");
+        assert_eq!(submissions.load(Ordering::Relaxed), 2);
+
+        let (addr, submissions) = spawn_rechallenging_upstream(usize::MAX).await;
+        let error = proxy_request(
+            &ClientRequest::get(),
+            &format!("http://{}/", addr),
+            &addr.to_string(),
+            Arc::new(DashMap::new()),
+            &config,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(
+            error.to_string().starts_with("re-challenge loop: "),
+            "{}",
+            error
+        );
+        assert_eq!(submissions.load(Ordering::Relaxed), 1 + MAX_RECHALLENGES);
+    }
+
+    #[tokio::test]
+    async fn one_shot_challenges_are_submitted_for_the_page_that_served_them() {
+        let addr = spawn_rotating_upstream().await;
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig::default();
+        let ((status, _, body), anubis_status) = proxy_request(
+            &ClientRequest::get(),
+            &format!("http://{}/", addr),
+            &addr.to_string(),
+            sessions,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body_text(&(status, HeaderMap::new(), body)), "content");
+        assert_eq!(anubis_status, AnubisStatus::Solved);
+    }
+
+    #[tokio::test]
+    async fn responses_report_how_the_session_was_obtained() {
+        let addr = spawn_protected_upstream().await;
+        let host = addr.to_string();
+        let sessions: SessionCache = Arc::new(DashMap::new());
+        let config = ProxyConfig::default();
+        let fetch = |path: &str| {
+            let url = format!("http://{}{}", addr, path);
+            let sessions = sessions.clone();
+            let host = host.clone();
+            let config = &config;
+            async move {
+                let ((status, _, body), anubis_status) =
+                    proxy_request(&ClientRequest::get(), &url, &host, sessions, config)
+                        .await
+                        .unwrap();
+                let UpstreamBody::Buffered(body) = body else {
+                    panic!("unexpected streaming body");
+                };
+                assert_eq!(status, StatusCode::OK);
+                (String::from_utf8(body).unwrap(), anubis_status)
+            }
+        };
+
+        assert_eq!(
+            fetch("/open").await,
+            ("open".to_string(), AnubisStatus::NoChallenge)
+        );
+        assert_eq!(
+            fetch("/").await,
+            ("content".to_string(), AnubisStatus::Solved)
+        );
+        assert_eq!(
+            fetch("/").await,
+            ("content".to_string(), AnubisStatus::CachedSession)
+        );
+    }
+
+    #[tokio::test]
+    async fn solve_webhook_posts_the_solve_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "webhook request ended early");
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let webhook = SolveWebhook::new(&format!("http://{}/solved", addr)).unwrap();
+        webhook.notify(serde_json::json!({"host": "example.com", "difficulty": 4}));
+
+        let request = tokio::time::timeout(Duration::from_secs(5), received)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(request.starts_with("POST /solved HTTP/1.1"));
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["host"], "example.com");
+        assert_eq!(body["difficulty"], 4);
+    }
+
+    #[tokio::test]
+    async fn read_timeout_bounds_a_silent_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let started = Instant::now();
+        let client = reqwest::Client::new();
+        let result = send_upstream(
+            client.get(format!("http://{}/", addr)),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_wait_for_a_free_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ProxyConfig {
+            max_connections: Some(1),
+            ..ProxyConfig::default()
+        };
+        tokio::spawn(serve(listener, Arc::new(config), std::future::pending()));
+
+        let idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut waiting = tokio::net::TcpStream::connect(addr).await.unwrap();
+        waiting
+            .write_all(b"GET /health HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 256];
+        let early = tokio::time::timeout(Duration::from_millis(200), waiting.read(&mut buf)).await;
+        assert!(
+            early.is_err(),
+            "second connection was served over the limit"
+        );
+
+        drop(idle);
+        let n = tokio::time::timeout(Duration::from_secs(5), waiting.read(&mut buf))
+            .await
+            .expect("second connection was never served")
+            .unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_in_flight_requests_and_saves_sessions() {
+        let upstream = spawn_protected_upstream().await;
+        let slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_addr = slow.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = slow.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                        .await;
+                });
+            }
+        });
+
+        let path =
+            std::env::temp_dir().join(format!("anubis-proxy-shutdown-{}.json", std::process::id()));
+        let config = ProxyConfig {
+            snapshot: Some(SnapshotConfig {
+                path: path.clone(),
+                interval: Duration::from_secs(3600),
+            }),
+            shutdown_timeout: Duration::from_secs(5),
+            ..ProxyConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Arc::new(config), async {
+            let _ = shutdown.await;
+        }));
+
+        let client = reqwest::Client::new();
+        let solved = client
+            .get(format!("http://{}/proxy/http://{}/", proxy, upstream))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(solved.headers()["x-anubis-status"], "solved");
+        let in_flight = tokio::spawn(
+            client
+                .get(format!("http://{}/proxy/http://{}/", proxy, slow_addr))
+                .send(),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger.send(()).unwrap();
+
+        let response = in_flight.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "slow");
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("shutdown never finished")
+            .unwrap()
+            .unwrap();
+        assert!(tokio::net::TcpStream::connect(proxy).await.is_err());
+
+        let states = load_snapshot(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(states.values().any(|state| state.passed), "{:?}", states);
+    }
+
+    #[test]
+    fn proxy_paths_may_name_the_upstream_scheme() {
+        assert_eq!(
+            split_target("example.com/a?b", "https"),
+            ("https", "example.com", "/a?b")
+        );
+        assert_eq!(
+            split_target("http://localhost:8080/a", "https"),
+            ("http", "localhost:8080", "/a")
+        );
+        assert_eq!(
+            split_target("https://example.com", "http"),
+            ("https", "example.com", "/")
+        );
+        assert_eq!(
+            split_target("example.com", "http"),
+            ("http", "example.com", "/")
+        );
+        assert!(parse_scheme("UPSTREAM_SCHEME", "ftp").is_err());
+        assert_eq!(parse_scheme("UPSTREAM_SCHEME", "HTTP"), Ok("http"));
+    }
+
+    #[tokio::test]
+    async fn plain_http_upstreams_are_proxied_and_solved() {
+        let upstream = spawn_protected_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            Arc::new(ProxyConfig::default()),
+            std::future::pending(),
+        ));
+
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("http://{}/proxy/http://{}/", proxy, upstream))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-anubis-status"], "solved");
+        assert_eq!(response.text().await.unwrap(), "content");
+
+        // The session is kept per host, whichever scheme named it.
+        let config = ProxyConfig {
+            upstream_scheme: Some("http"),
+            ..ProxyConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(config), std::future::pending()));
+        for expected in ["solved", "cached-session"] {
+            let response = client
+                .get(format!("http://{}/proxy/{}/", proxy, upstream))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.headers()["x-anubis-status"], expected);
+        }
+        let response = client
+            .get(format!("http://{}/proxy/http://{}/", proxy, upstream))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-anubis-status"], "cached-session");
+    }
+
+    #[test]
+    fn solve_batch_keeps_order_and_reports_errors_per_challenge() {
+        let body = br#"[
+            {"challenge":"abc","rules":{"difficulty":2}},
+            {"challenge":"abc"},
+            {"challenge":"abc","rules":{"difficulty":1,"algorithm":"preact"}}
+        ]"#;
+        let (status, response) = solve_batch(body, &ProxyConfig::default());
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        let results = response.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[0]["result"]["difficulty"], 2);
+        assert!(results[0]["result"]["hash"]
+            .as_str()
+            .unwrap()
+            .starts_with("00"));
+        assert!(results[1]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("malformed challenge"));
+        assert!(results[2]["result"]["nonce"].is_null());
+    }
+
+    #[test]
+    fn solve_batch_rejects_oversized_batches() {
+        let config = ProxyConfig::default();
+        let too_many = format!(
+            "[{}]",
+            vec![r#"{"challenge":"abc","rules":{"difficulty":1}}"#; MAX_SOLVE_BATCH + 1].join(",")
+        );
+        assert_eq!(
+            solve_batch(too_many.as_bytes(), &config).0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let too_hard = br#"[{"challenge":"abc","rules":{"difficulty":8}}]"#;
+        assert_eq!(
+            solve_batch(too_hard, &config).0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(solve_batch(b"{}", &config).0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn pass_statuses_parse_comma_separated_codes() {
+        let statuses: PassStatuses = "200, 302,303".parse().unwrap();
+        assert_eq!(
+            statuses.0,
+            [StatusCode::OK, StatusCode::FOUND, StatusCode::SEE_OTHER]
+        );
+        assert_eq!(statuses.to_string(), "200/302/303");
+        assert_eq!(PassStatuses::default().0, [StatusCode::FOUND]);
+        for invalid in ["", "30x", "302,,303", "99"] {
+            assert!(invalid.parse::<PassStatuses>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn deep_health_check_passes_with_every_nonce_format() {
+        for format in ["decimal", "hex", "padded:20"] {
+            let options = SolveOptions {
+                nonce_format: format.parse().unwrap(),
+                ..SolveOptions::default()
+            };
+            assert_eq!(deep_health_check(&options), Ok(()), "{}", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn deep_health_checks_are_reused_for_a_while() {
+        let health = DeepHealth::default();
+        let options = SolveOptions::default();
+        assert_eq!(health.check(&options).await, Ok(()));
+        let first = health.0.lock().await.as_ref().unwrap().0;
+
+        let broken = SolveOptions {
+            nonce_format: NonceFormat::Padded(anubis_solver::MAX_PADDED_WIDTH + 1),
+            ..SolveOptions::default()
+        };
+        assert_eq!(health.check(&broken).await, Ok(()));
+        assert_eq!(health.0.lock().await.as_ref().unwrap().0, first);
+
+        // Once the result is stale, the next probe solves again.
+        *health.0.lock().await = Some((first - DEEP_HEALTH_CACHE, Ok(())));
+        assert!(health.check(&broken).await.is_err());
+    }
+
+    #[test]
+    fn response_head_lists_status_and_every_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::LOCATION, "/after".parse().unwrap());
+        headers.append(reqwest::header::SET_COOKIE, "a=1".parse().unwrap());
+        headers.append(reqwest::header::SET_COOKIE, "b=2".parse().unwrap());
+        assert_eq!(
+            format_response_head(StatusCode::FOUND, &headers),
+            "  302 Found\n  location: /after\n  set-cookie: a=1\n  set-cookie: b=2\n"
+        );
+    }
+
+    #[test]
+    fn detects_websocket_upgrades() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            hyper::header::CONNECTION,
+            "keep-alive, Upgrade".parse().unwrap(),
+        );
+        headers.insert(hyper::header::UPGRADE, "websocket".parse().unwrap());
+        assert!(is_websocket_upgrade(&headers));
+
+        headers.insert(hyper::header::UPGRADE, "h2c".parse().unwrap());
+        assert!(!is_websocket_upgrade(&headers));
+
+        headers.remove(hyper::header::CONNECTION);
+        headers.insert(hyper::header::UPGRADE, "websocket".parse().unwrap());
+        assert!(!is_websocket_upgrade(&headers));
+    }
+
+    #[tokio::test]
+    async fn websocket_handshake_is_relayed_and_upgraded() {
+        use tokio::io::AsyncWriteExt;
+        const FIREFOX_UA: &str =
+            "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0";
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await.unwrap();
+                request.push(byte[0]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                      Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                      Sec-WebSocket-Accept: accepted\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut frame = [0u8; 4];
+            stream.read_exact(&mut frame).await.unwrap();
+            stream.write_all(&frame).await.unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        let mut client_headers = hyper::HeaderMap::new();
+        client_headers.insert("sec-websocket-key", "dGhlIHNhbXBsZQ==".parse().unwrap());
+        client_headers.insert("sec-websocket-version", "13".parse().unwrap());
+        client_headers.insert(
+            hyper::header::ORIGIN,
+            "http://localhost:8192".parse().unwrap(),
+        );
+        let client = reqwest::Client::builder().http1_only().build().unwrap();
+        let resp = open_upstream_websocket(
+            &client,
+            &format!("http://{}/ws", addr),
+            FIREFOX_UA,
+            &client_headers,
+            DEFAULT_UPSTREAM_TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(resp.headers()["sec-websocket-accept"], "accepted");
+
+        let mut upgraded = resp.upgrade().await.unwrap();
+        upgraded.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        upgraded.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let request = upstream.await.unwrap();
+        assert!(request.contains("upgrade: websocket"));
+        assert!(request.contains("sec-websocket-key: dghlihnhbxbszq=="));
+        assert!(request.contains("sec-websocket-version: 13"));
+        assert!(!request.contains("origin:"));
+        assert!(request.contains(&format!("user-agent: {}", FIREFOX_UA.to_lowercase())));
+        assert!(request.contains("sec-fetch-mode: websocket"));
+        assert!(!request.contains("upgrade-insecure-requests"));
+    }
+
+    #[tokio::test]
+    async fn browser_headers_keep_their_order_on_the_wire() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ProxyConfig::default();
+        let client = build_client(&config, Arc::default()).unwrap();
+        let user_agent = get_chrome_rua();
+        let headers = navigation_headers(user_agent, false);
+        let expected: Vec<String> = headers.keys().map(|name| name.to_string()).collect();
+
+        let request = tokio::spawn(
+            client
+                .get(format!("http://{}/", addr))
+                .headers(headers)
+                .send(),
+        );
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut raw = Vec::new();
+        while !raw.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            raw.push(byte[0]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        request.await.unwrap().unwrap();
+
+        let sent: Vec<String> = String::from_utf8(raw)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, _)| name.to_lowercase())
+            .filter(|name| expected.contains(name))
+            .collect();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn listen_address_prefers_flags_over_the_environment() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let env = |value: &str| Some(value.to_string());
+
+        assert_eq!(
+            listen_address(args(&[]), None, None).unwrap(),
+            "0.0.0.0:8192".parse().unwrap()
+        );
+        assert_eq!(
+            listen_address(args(&[]), env("127.0.0.1"), env("9000")).unwrap(),
+            "127.0.0.1:9000".parse().unwrap()
+        );
+        assert_eq!(
+            listen_address(
+                args(&["--bind", "::1", "--port=9001"]),
+                env("127.0.0.1"),
+                env("9000")
+            )
+            .unwrap(),
+            "[::1]:9001".parse().unwrap()
+        );
+        assert_eq!(
+            listen_address(args(&[]), None, env("not a port")).unwrap(),
+            "0.0.0.0:8192".parse().unwrap()
+        );
+
+        for bad in [
+            &["--port", "99999"][..],
+            &["--bind", "localhost"],
+            &["--port"],
+            &["-v"],
+        ] {
+            assert!(listen_address(args(bad), None, None).is_err(), "{:?}", bad);
+        }
+        assert!(listen_address(args(&[]), env("0.0.0.0.0"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn upstream_requests_go_through_socks_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = config_with_proxy(&format!("socks5h://{}", addr));
+        let client = build_client(&config, Arc::default()).unwrap();
+
+        // The challenge fetch and the submission share the host's client,
+        // so both must open their connection to the SOCKS listener.
+        let urls = [
+            "https://example.invalid/page".to_string(),
+            format!("https://example.invalid/{}", anubis_solver::SUBMISSION_PATH),
+        ];
+        for url in urls {
+            let request = tokio::spawn(client.get(url).send());
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).await.unwrap();
+            assert_eq!(version[0], 0x05, "expected a SOCKS5 greeting");
+            drop(stream);
+            assert!(request.await.unwrap().is_err());
+        }
+    }
 }
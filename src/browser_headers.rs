@@ -0,0 +1,198 @@
+//! Browser-like request headers, in the order each browser sends them.
+//!
+//! Stricter deployments look at which headers are present and in what order,
+//! not only at the `User-Agent`. `http::HeaderMap` iterates in insertion order
+//! and hyper writes headers in that order, so the maps built here reach the
+//! wire as listed (after `Host`, which hyper always writes first, and before
+//! `Cookie`, which reqwest appends).
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Accept header browsers send for top-level navigations.
+const NAVIGATION_ACCEPT: &str =
+    "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8";
+const ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrowserFamily {
+    /// Chrome and other Chromium browsers, which send client hints.
+    Chromium,
+    Firefox,
+    Safari,
+}
+
+impl BrowserFamily {
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        if user_agent.contains("Firefox/") {
+            BrowserFamily::Firefox
+        } else if user_agent.contains("Chrome/") || user_agent.contains("Chromium/") {
+            BrowserFamily::Chromium
+        } else if user_agent.contains("Safari/") {
+            BrowserFamily::Safari
+        } else {
+            BrowserFamily::Chromium
+        }
+    }
+}
+
+/// Headers for a top-level page load with `user_agent`, in its browser's order.
+/// `same_origin` marks navigations from a page on the same site, such as the
+/// challenge submission.
+///
+/// `Accept-Encoding` is left out: responses must stay uncompressed so the
+/// proxy can look for challenges in them.
+pub fn navigation_headers(user_agent: &str, same_origin: bool) -> HeaderMap {
+    let fetch_site = if same_origin { "same-origin" } else { "none" };
+    let family = BrowserFamily::from_user_agent(user_agent);
+    let mut headers = HeaderMap::new();
+    let mut push = |name: &'static str, value: &str| {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.append(HeaderName::from_static(name), value);
+        }
+    };
+
+    match family {
+        BrowserFamily::Chromium => {
+            let major = chromium_major_version(user_agent).unwrap_or("0");
+            push(
+                "sec-ch-ua",
+                &format!(
+                    "\"Chromium\";v=\"{0}\", \"Google Chrome\";v=\"{0}\", \"Not-A.Brand\";v=\"99\"",
+                    major
+                ),
+            );
+            push(
+                "sec-ch-ua-mobile",
+                if user_agent.contains("Mobile") {
+                    "?1"
+                } else {
+                    "?0"
+                },
+            );
+            push(
+                "sec-ch-ua-platform",
+                &format!("\"{}\"", platform(user_agent)),
+            );
+            push("upgrade-insecure-requests", "1");
+            push("user-agent", user_agent);
+            push(
+                "accept",
+                &format!(
+                    "{},application/signed-exchange;v=b3;q=0.7",
+                    NAVIGATION_ACCEPT
+                ),
+            );
+            push("sec-fetch-site", fetch_site);
+            push("sec-fetch-mode", "navigate");
+            push("sec-fetch-user", "?1");
+            push("sec-fetch-dest", "document");
+            push("accept-language", ACCEPT_LANGUAGE);
+        }
+        BrowserFamily::Firefox => {
+            push("user-agent", user_agent);
+            push("accept", NAVIGATION_ACCEPT);
+            push("accept-language", "en-US,en;q=0.5");
+            push("upgrade-insecure-requests", "1");
+            push("sec-fetch-dest", "document");
+            push("sec-fetch-mode", "navigate");
+            push("sec-fetch-site", fetch_site);
+            push("sec-fetch-user", "?1");
+        }
+        BrowserFamily::Safari => {
+            push("accept", NAVIGATION_ACCEPT);
+            push("sec-fetch-site", fetch_site);
+            push("sec-fetch-dest", "document");
+            push("accept-language", ACCEPT_LANGUAGE);
+            push("sec-fetch-mode", "navigate");
+            push("user-agent", user_agent);
+        }
+    }
+
+    headers
+}
+
+fn chromium_major_version(user_agent: &str) -> Option<&str> {
+    let version = user_agent.split("Chrome/").nth(1)?;
+    version.split('.').next().filter(|major| !major.is_empty())
+}
+
+/// Platform name as Chromium reports it in `Sec-CH-UA-Platform`.
+fn platform(user_agent: &str) -> &'static str {
+    if user_agent.contains("Android") {
+        "Android"
+    } else if user_agent.contains("Windows") {
+        "Windows"
+    } else if user_agent.contains("Mac OS X") {
+        "macOS"
+    } else if user_agent.contains("CrOS") {
+        "Chrome OS"
+    } else if user_agent.contains("Linux") {
+        "Linux"
+    } else {
+        "Unknown"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.6367.91 Safari/537.36";
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0";
+    const SAFARI: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15";
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        headers.keys().map(|name| name.as_str()).collect()
+    }
+
+    #[test]
+    fn detects_browser_family() {
+        assert_eq!(
+            BrowserFamily::from_user_agent(CHROME),
+            BrowserFamily::Chromium
+        );
+        assert_eq!(
+            BrowserFamily::from_user_agent(FIREFOX),
+            BrowserFamily::Firefox
+        );
+        assert_eq!(
+            BrowserFamily::from_user_agent(SAFARI),
+            BrowserFamily::Safari
+        );
+    }
+
+    #[test]
+    fn chromium_headers_follow_chrome_order_and_version() {
+        let headers = navigation_headers(CHROME, false);
+        assert_eq!(
+            names(&headers),
+            [
+                "sec-ch-ua",
+                "sec-ch-ua-mobile",
+                "sec-ch-ua-platform",
+                "upgrade-insecure-requests",
+                "user-agent",
+                "accept",
+                "sec-fetch-site",
+                "sec-fetch-mode",
+                "sec-fetch-user",
+                "sec-fetch-dest",
+                "accept-language",
+            ]
+        );
+        assert!(headers["sec-ch-ua"].to_str().unwrap().contains("v=\"124\""));
+        assert_eq!(headers["sec-ch-ua-platform"], "\"Windows\"");
+        assert_eq!(headers["sec-fetch-site"], "none");
+        assert_eq!(
+            navigation_headers(CHROME, true)["sec-fetch-site"],
+            "same-origin"
+        );
+    }
+
+    #[test]
+    fn firefox_headers_have_no_client_hints() {
+        let headers = navigation_headers(FIREFOX, false);
+        assert_eq!(names(&headers)[0], "user-agent");
+        assert!(!headers.contains_key("sec-ch-ua"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Fetching a protected page end to end: fetch it, solve the challenge it
+//! serves, submit the solution and fetch it again with the session cookie.
+//!
+//! This is the sequence the proxy runs for each host, without its session
+//! cache, autodetection or configuration, for programs that only want one
+//! page's content.
+
+use reqwest::header::HeaderMap;
+use reqwest::{StatusCode, Url};
+use std::time::{Duration, Instant};
+
+use crate::browser_headers::navigation_headers;
+use crate::{
+    build_submission_url_with_options, solve_challenge_with_options, try_parse_challenge_from_html,
+    ParsedChallenge, SolveOptions, SolverResult, SubmissionOptions,
+};
+
+/// Limit on each request, body included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most times the page may serve another challenge after an accepted
+/// solution before [`solve_and_fetch`] gives up.
+pub const MAX_RECHALLENGES: usize = 3;
+
+/// The page [`solve_and_fetch`] ended up with.
+#[derive(Debug)]
+pub struct SolvedPage {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// The solution that was submitted, `None` if the page served no
+    /// challenge.
+    pub solution: Option<SolverResult>,
+}
+
+/// Fetches `url`, solving and submitting its challenge with `options` if it
+/// serves one, and returns the page fetched afterwards.
+///
+/// All requests share one client, cookie jar and randomly picked Chrome user
+/// agent. Upstream Anubis answers a passed submission with a 302; any other
+/// status is an error. Solving runs on a blocking thread, and time-based
+/// challenges wait out their `min_wait` before submitting. A page that still
+/// serves a challenge afterwards is solved again, up to [`MAX_RECHALLENGES`]
+/// times.
+pub async fn solve_and_fetch(url: &str, options: &SolveOptions) -> Result<SolvedPage, String> {
+    let parsed_url = Url::parse(url).map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+    let mut host = parsed_url
+        .host_str()
+        .ok_or_else(|| format!("URL {:?} has no host", url))?
+        .to_string();
+    if let Some(port) = parsed_url.port() {
+        host = format!("{}:{}", host, port);
+    }
+    let user_agent = fake_user_agent::get_chrome_rua();
+    let client = reqwest::Client::builder()
+        .cookie_store(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut page = fetch(
+        client
+            .get(url)
+            .headers(navigation_headers(user_agent, false)),
+    )
+    .await?;
+    for _ in 0..=MAX_RECHALLENGES {
+        let html = String::from_utf8_lossy(&page.body);
+        let Some(parsed) = try_parse_challenge_from_html(&html)? else {
+            return Ok(page);
+        };
+        page = solve_and_refetch(
+            &client,
+            url,
+            parsed_url.scheme(),
+            &host,
+            user_agent,
+            parsed,
+            options,
+        )
+        .await?;
+    }
+    Err("re-challenge loop: server kept issuing challenges".to_string())
+}
+
+/// Solves and submits `parsed`, then fetches `url` again.
+async fn solve_and_refetch(
+    client: &reqwest::Client,
+    url: &str,
+    scheme: &str,
+    host: &str,
+    user_agent: &str,
+    parsed: ParsedChallenge,
+    options: &SolveOptions,
+) -> Result<SolvedPage, String> {
+    let start_time = Instant::now();
+    let challenge = parsed.challenge;
+    let result = {
+        let challenge = challenge.clone();
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || {
+            solve_challenge_with_options::<fn(u64)>(&challenge, None, &options)
+        })
+        .await
+        .map_err(|e| format!("solver thread failed: {}", e))??
+    };
+    if let Some(min_wait) = challenge.min_wait() {
+        tokio::time::sleep(min_wait.saturating_sub(start_time.elapsed())).await;
+    }
+
+    let submit_url = build_submission_url_with_options(
+        scheme,
+        host,
+        &challenge,
+        &result,
+        url,
+        start_time.elapsed().as_millis(),
+        &SubmissionOptions::default().for_version(&parsed.version),
+    )?;
+    let submitted = fetch(
+        client
+            .get(&submit_url)
+            .headers(navigation_headers(user_agent, true)),
+    )
+    .await?;
+    if submitted.status != StatusCode::FOUND {
+        return Err(format!(
+            "challenge submission for {} returned {} instead of 302",
+            host, submitted.status
+        ));
+    }
+
+    let page = fetch(
+        client
+            .get(url)
+            .headers(navigation_headers(user_agent, true)),
+    )
+    .await?;
+    Ok(SolvedPage {
+        solution: Some(result),
+        ..page
+    })
+}
+
+async fn fetch(request: reqwest::RequestBuilder) -> Result<SolvedPage, String> {
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    Ok(SolvedPage {
+        status,
+        headers,
+        body: body.to_vec(),
+        solution: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    /// Serves a challenge at `/page` until its cookie is set by a submission,
+    /// which is rejected with a 403 if `accept` is false, and at `/always`
+    /// regardless.
+    async fn spawn_protected_server(accept: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| async move {
+                        let passed = req
+                            .headers()
+                            .get(hyper::header::COOKIE)
+                            .is_some_and(|cookie| cookie == "passed=1");
+                        let response = match req.uri().path() {
+                            "/page" if passed => Response::new(Full::new(Bytes::from("content"))),
+                            "/page" | "/always" => Response::new(Full::new(Bytes::from(
+                                r#"<script id="anubis_challenge" type="application/json">{"challenge":"abc","rules":{"difficulty":1,"algorithm":"fast"}}</script>"#,
+                            ))),
+                            "/open" => Response::new(Full::new(Bytes::from("open"))),
+                            _ if !accept => Response::builder()
+                                .status(StatusCode::FORBIDDEN)
+                                .body(Full::new(Bytes::new()))
+                                .unwrap(),
+                            _ => Response::builder()
+                                .status(StatusCode::FOUND)
+                                .header(hyper::header::LOCATION, "/page")
+                                .header(hyper::header::SET_COOKIE, "passed=1; Path=/")
+                                .body(Full::new(Bytes::new()))
+                                .unwrap(),
+                        };
+                        Ok::<_, std::convert::Infallible>(response)
+                    }),
+                ));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn protected_pages_are_solved_and_fetched_again() {
+        let addr = spawn_protected_server(true).await;
+        let page = solve_and_fetch(&format!("http://{}/page", addr), &SolveOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(page.status, StatusCode::OK);
+        assert_eq!(page.body, b"content");
+        assert!(page.solution.unwrap().hash.starts_with('0'));
+    }
+
+    #[tokio::test]
+    async fn endless_challenges_are_given_up_on() {
+        let addr = spawn_protected_server(true).await;
+        let error = solve_and_fetch(&format!("http://{}/always", addr), &SolveOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error, "re-challenge loop: server kept issuing challenges");
+    }
+
+    #[tokio::test]
+    async fn unprotected_pages_and_rejections() {
+        let addr = spawn_protected_server(false).await;
+        let page = solve_and_fetch(&format!("http://{}/open", addr), &SolveOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(page.body, b"open");
+        assert!(page.solution.is_none());
+
+        let error = solve_and_fetch(&format!("http://{}/page", addr), &SolveOptions::default())
+            .await
+            .unwrap_err();
+        assert!(error.contains("returned 403 Forbidden"), "{}", error);
+    }
+}
@@ -1,21 +1,91 @@
-use hex;
-use itoa;
+#[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::digest::generic_array::{typenum::U64, GenericArray};
 use sha2::{Digest, Sha256};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+use std::time::Instant;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+use web_time::Instant;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod browser_headers;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fetch;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub mod simd;
 
 pub const SUBMISSION_PATH: &str = ".within.website/x/cmd/anubis/api/pass-challenge";
 
-#[derive(Debug, Deserialize, Clone)]
+/// Algorithm assumed when a challenge has no `algorithm` field, as in Anubis
+/// releases before the field existed.
+pub const DEFAULT_ALGORITHM: &str = "fast";
+
+#[derive(Debug, Clone)]
 pub struct AnubisChallengeRules {
-    #[serde(rename = "difficulty")]
     pub difficulty: usize,
-    #[serde(rename = "algorithm", default)]
+    /// Empty when the server doesn't send one.
     pub algorithm: String,
+    /// Fields we don't interpret (e.g. `report_as`), kept for debugging forks.
+    pub extra: Map<String, Value>,
+}
+
+// Hand-written rather than `#[serde(flatten)]`, which would buffer the whole
+// object before deserializing it.
+impl<'de> Deserialize<'de> for AnubisChallengeRules {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{self, MapAccess, Visitor};
+        use std::fmt;
+
+        struct RulesVisitor;
+
+        impl<'de> Visitor<'de> for RulesVisitor {
+            type Value = AnubisChallengeRules;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an object with difficulty")
+            }
+
+            fn visit_map<M>(self, mut map: M) -> Result<AnubisChallengeRules, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let mut difficulty: Option<usize> = None;
+                let mut algorithm = String::new();
+                let mut extra = Map::new();
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "difficulty" => difficulty = Some(map.next_value()?),
+                        "algorithm" => algorithm = map.next_value()?,
+                        _ => {
+                            let value = map.next_value::<Value>()?;
+                            extra.insert(key, value);
+                        }
+                    }
+                }
+
+                let difficulty =
+                    difficulty.ok_or_else(|| de::Error::missing_field("difficulty"))?;
+
+                Ok(AnubisChallengeRules {
+                    difficulty,
+                    algorithm,
+                    extra,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(RulesVisitor)
+    }
 }
 
 /// New format (Aug 2025+): challenge is an object with id, randomData, etc.
@@ -31,6 +101,8 @@ pub struct ChallengeDataNew {
 pub struct ChallengeData {
     pub id: Option<String>,
     pub random_data: String,
+    /// Unrecognized keys of the new object format.
+    pub extra: Map<String, Value>,
 }
 
 impl<'de> Deserialize<'de> for ChallengeData {
@@ -58,6 +130,7 @@ impl<'de> Deserialize<'de> for ChallengeData {
                 Ok(ChallengeData {
                     id: None,
                     random_data: value.to_string(),
+                    extra: Map::new(),
                 })
             }
 
@@ -68,13 +141,15 @@ impl<'de> Deserialize<'de> for ChallengeData {
             {
                 let mut id: Option<String> = None;
                 let mut random_data: Option<String> = None;
+                let mut extra = Map::new();
 
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         "id" => id = Some(map.next_value()?),
                         "randomData" => random_data = Some(map.next_value()?),
                         _ => {
-                            let _ = map.next_value::<serde::de::IgnoredAny>()?;
+                            let value = map.next_value::<Value>()?;
+                            extra.insert(key, value);
                         }
                     }
                 }
@@ -82,7 +157,11 @@ impl<'de> Deserialize<'de> for ChallengeData {
                 let random_data =
                     random_data.ok_or_else(|| de::Error::missing_field("randomData"))?;
 
-                Ok(ChallengeData { id, random_data })
+                Ok(ChallengeData {
+                    id,
+                    random_data,
+                    extra,
+                })
             }
         }
 
@@ -90,20 +169,565 @@ impl<'de> Deserialize<'de> for ChallengeData {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Clone)]
 pub struct AnubisChallenge {
-    #[serde(rename = "challenge")]
     pub challenge: ChallengeData,
-    #[serde(rename = "rules")]
     pub rules: AnubisChallengeRules,
+    /// Top-level fields we don't interpret.
+    pub extra: Map<String, Value>,
+}
+
+impl<'de> Deserialize<'de> for AnubisChallenge {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{self, MapAccess, Visitor};
+        use std::fmt;
+
+        struct ChallengeVisitor;
+
+        impl<'de> Visitor<'de> for ChallengeVisitor {
+            type Value = AnubisChallenge;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an object with challenge and rules")
+            }
+
+            fn visit_map<M>(self, mut map: M) -> Result<AnubisChallenge, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let mut challenge: Option<ChallengeData> = None;
+                let mut rules: Option<AnubisChallengeRules> = None;
+                let mut extra = Map::new();
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "challenge" => challenge = Some(map.next_value()?),
+                        "rules" => rules = Some(map.next_value()?),
+                        _ => {
+                            let value = map.next_value::<Value>()?;
+                            extra.insert(key, value);
+                        }
+                    }
+                }
+
+                Ok(AnubisChallenge {
+                    challenge: challenge.ok_or_else(|| de::Error::missing_field("challenge"))?,
+                    rules: rules.ok_or_else(|| de::Error::missing_field("rules"))?,
+                    extra,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(ChallengeVisitor)
+    }
+}
+
+/// How the nonce is written after the challenge data. Apart from a
+/// separator, the same text is hashed and submitted, since the server
+/// rebuilds the preimage from the submission.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonceFormat {
+    /// Plain decimal, as upstream Anubis does (`42`).
+    #[default]
+    Decimal,
+    /// Lowercase hex without a prefix (`2a`).
+    Hex,
+    /// Decimal left-padded with zeros to the given width (`Padded(8)` gives `00000042`),
+    /// at most [`MAX_PADDED_WIDTH`].
+    Padded(usize),
+    /// Decimal after a separator: `Separated(':')` hashes `data:42`. Only
+    /// `42` is submitted; the server adds the separator back.
+    Separated(char),
+}
+
+/// Widest `NonceFormat::Padded` accepted: the digits of `u64::MAX`.
+pub const MAX_PADDED_WIDTH: usize = 20;
+
+impl NonceFormat {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            NonceFormat::Padded(width) if *width > MAX_PADDED_WIDTH => Err(format!(
+                "nonce padding width {} exceeds the maximum of {}",
+                width, MAX_PADDED_WIDTH
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Formats `nonce` exactly as it is submitted.
+    pub fn format(&self, nonce: u64) -> String {
+        match self {
+            NonceFormat::Separated(_) => nonce.to_string(),
+            _ => self.preimage_suffix(nonce),
+        }
+    }
+
+    /// What follows the challenge data in the hashed preimage for `nonce`.
+    pub fn preimage_suffix(&self, nonce: u64) -> String {
+        let mut encoder = NonceEncoder::default();
+        String::from_utf8_lossy(encoder.encode(*self, nonce)).into_owned()
+    }
+}
+
+impl std::str::FromStr for NonceFormat {
+    type Err = String;
+
+    /// Parses `decimal`, `hex`, `padded:<width>` or `separator:<char>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(separator) = s.strip_prefix("separator:") {
+            let mut chars = separator.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(separator), None) => Ok(NonceFormat::Separated(separator)),
+                _ => Err(format!("nonce separator must be one character: {}", s)),
+            };
+        }
+        let format = match s {
+            "decimal" => NonceFormat::Decimal,
+            "hex" => NonceFormat::Hex,
+            _ => s
+                .strip_prefix("padded:")
+                .and_then(|width| width.parse().ok())
+                .map(NonceFormat::Padded)
+                .ok_or_else(|| format!("unknown nonce format: {}", s))?,
+        };
+        format.validate()?;
+        Ok(format)
+    }
+}
+
+impl std::fmt::Display for NonceFormat {
+    /// Writes the spelling [`FromStr`](std::str::FromStr) parses.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NonceFormat::Decimal => f.write_str("decimal"),
+            NonceFormat::Hex => f.write_str("hex"),
+            NonceFormat::Padded(width) => write!(f, "padded:{}", width),
+            NonceFormat::Separated(separator) => write!(f, "separator:{}", separator),
+        }
+    }
+}
+
+/// Reusable scratch space for formatting nonces without allocating per attempt.
+#[derive(Clone, Default)]
+struct NonceEncoder {
+    itoa_buf: itoa::Buffer,
+    scratch: Vec<u8>,
+    /// Written after every nonce; see [`SolveOptions::salt`].
+    salt: Vec<u8>,
+    salted: Vec<u8>,
+}
+
+impl NonceEncoder {
+    fn salted(salt: &[u8]) -> Self {
+        NonceEncoder {
+            salt: salt.to_vec(),
+            ..NonceEncoder::default()
+        }
+    }
+
+    #[inline]
+    fn encode(&mut self, format: NonceFormat, nonce: u64) -> &[u8] {
+        let encoded = Self::encode_nonce(&mut self.itoa_buf, &mut self.scratch, format, nonce);
+        if self.salt.is_empty() {
+            return encoded;
+        }
+        self.salted.clear();
+        self.salted.extend_from_slice(encoded);
+        self.salted.extend_from_slice(&self.salt);
+        &self.salted
+    }
+
+    #[inline]
+    fn encode_nonce<'a>(
+        itoa_buf: &'a mut itoa::Buffer,
+        scratch: &'a mut Vec<u8>,
+        format: NonceFormat,
+        nonce: u64,
+    ) -> &'a [u8] {
+        match format {
+            NonceFormat::Decimal => itoa_buf.format(nonce).as_bytes(),
+            NonceFormat::Hex => {
+                const DIGITS: &[u8; 16] = b"0123456789abcdef";
+                let len = (64 - nonce.leading_zeros() as usize).div_ceil(4).max(1);
+                scratch.clear();
+                for i in (0..len).rev() {
+                    scratch.push(DIGITS[((nonce >> (i * 4)) & 0xf) as usize]);
+                }
+                scratch
+            }
+            NonceFormat::Padded(width) => {
+                let digits = itoa_buf.format(nonce).as_bytes();
+                scratch.clear();
+                scratch.resize(width.saturating_sub(digits.len()), b'0');
+                scratch.extend_from_slice(digits);
+                scratch
+            }
+            NonceFormat::Separated(separator) => {
+                scratch.clear();
+                let mut utf8 = [0; 4];
+                scratch.extend_from_slice(separator.encode_utf8(&mut utf8).as_bytes());
+                scratch.extend_from_slice(itoa_buf.format(nonce).as_bytes());
+                scratch
+            }
+        }
+    }
+}
+
+/// Where the nonce goes in the hashed preimage, relative to the challenge
+/// data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcatOrder {
+    /// `data + nonce`, as upstream Anubis does.
+    #[default]
+    ChallengeThenNonce,
+    /// `nonce + data`. The nonce changes the first block, so every hash
+    /// starts from scratch instead of from the data's midstate, several times
+    /// slower for long challenge data.
+    NonceThenChallenge,
+}
+
+impl std::str::FromStr for ConcatOrder {
+    type Err = String;
+
+    /// Parses `challenge_then_nonce` or `nonce_then_challenge`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "challenge_then_nonce" => Ok(ConcatOrder::ChallengeThenNonce),
+            "nonce_then_challenge" => Ok(ConcatOrder::NonceThenChallenge),
+            _ => Err(format!("unknown concat order: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ConcatOrder {
+    /// Writes the spelling [`FromStr`](std::str::FromStr) parses.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConcatOrder::ChallengeThenNonce => "challenge_then_nonce",
+            ConcatOrder::NonceThenChallenge => "nonce_then_challenge",
+        })
+    }
+}
+
+/// Longest nonce any [`NonceFormat`] writes: a 4-byte separator and the
+/// digits of `u64::MAX`.
+const MAX_NONCE_LEN: usize = 4 + MAX_PADDED_WIDTH;
+
+/// Longest [`SolveOptions::salt`] accepted with
+/// [`ConcatOrder::ChallengeThenNonce`], so the nonce and salt fit in the
+/// blocks after the data's midstate.
+pub const MAX_SALT_LEN: usize = Sha256Midstate::MAX_SUFFIX_LEN - MAX_NONCE_LEN;
+
+/// The preimage hashed for a nonce written as `nonce`.
+fn build_preimage(data: &str, nonce: &str, order: ConcatOrder, salt: Option<&str>) -> String {
+    let salt = salt.unwrap_or_default();
+    match order {
+        ConcatOrder::ChallengeThenNonce => format!("{}{}{}", data, nonce, salt),
+        ConcatOrder::NonceThenChallenge => format!("{}{}{}", nonce, data, salt),
+    }
+}
+
+/// Splits the preimage into the bytes that are the same for every nonce and
+/// an encoder writing the rest: the nonce and salt after the data, or with
+/// [`ConcatOrder::NonceThenChallenge`] just the nonce, before the data and
+/// salt.
+fn preimage_parts(data: &str, options: &SolveOptions) -> (Vec<u8>, NonceEncoder) {
+    let salt = options.salt.as_deref().unwrap_or_default().as_bytes();
+    match options.concat_order {
+        ConcatOrder::ChallengeThenNonce => (data.as_bytes().to_vec(), NonceEncoder::salted(salt)),
+        ConcatOrder::NonceThenChallenge => {
+            ([data.as_bytes(), salt].concat(), NonceEncoder::default())
+        }
+    }
+}
+
+/// SHA-256 of `nonce + rest`, twice if `double`, for
+/// [`ConcatOrder::NonceThenChallenge`].
+fn hash_nonce_first(nonce: &[u8], rest: &[u8], double: bool) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(rest);
+    let hash = hasher.finalize();
+    if double {
+        Sha256::digest(hash).into()
+    } else {
+        hash.into()
+    }
+}
+
+/// Clean-ups applied to the challenge data before it is hashed, for forks
+/// whose server hashes a slightly different string than the page shows.
+/// All off by default, so the raw challenge is hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChallengeNormalization {
+    /// Strip leading and trailing whitespace.
+    pub trim: bool,
+    /// Remove one pair of matching surrounding `"` or `'` quotes.
+    pub strip_quotes: bool,
+    /// Lowercase ASCII letters.
+    pub lowercase: bool,
+}
+
+impl ChallengeNormalization {
+    pub fn apply(&self, data: &str) -> String {
+        let mut data = data;
+        if self.trim {
+            data = data.trim();
+        }
+        if self.strip_quotes {
+            for quote in ['"', '\''] {
+                if let Some(inner) = data
+                    .strip_prefix(quote)
+                    .and_then(|rest| rest.strip_suffix(quote))
+                {
+                    data = inner;
+                    break;
+                }
+            }
+        }
+        if self.lowercase {
+            data.to_ascii_lowercase()
+        } else {
+            data.to_string()
+        }
+    }
+}
+
+impl std::str::FromStr for ChallengeNormalization {
+    type Err = String;
+
+    /// Parses a comma separated list of `trim`, `quotes` and `lowercase`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut normalization = ChallengeNormalization::default();
+        for step in s.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+            match step {
+                "trim" => normalization.trim = true,
+                "quotes" => normalization.strip_quotes = true,
+                "lowercase" => normalization.lowercase = true,
+                _ => return Err(format!("unknown normalization step: {}", step)),
+            }
+        }
+        Ok(normalization)
+    }
+}
+
+/// Start offsets are kept below this, so nonces stay in the range browser JS
+/// solvers produce and the search never runs into `u64::MAX`.
+const MAX_START_OFFSET: u64 = 1 << 32;
+
+/// Where the PoW search starts counting.
+///
+/// Browser solvers that start at a random nonce never submit the tiny nonces
+/// that starting at 0 finds for easy challenges, so an anti-bot system could
+/// use a run of small nonces to spot us. A random start avoids that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonceStart {
+    /// Nonce 0, as upstream Anubis's own solver does.
+    #[default]
+    Zero,
+    /// An offset derived from this seed, for reproducible runs.
+    Seeded(u64),
+    /// A fresh offset for every solve.
+    Random,
+}
+
+impl NonceStart {
+    fn offset(&self) -> u64 {
+        let seed = match self {
+            NonceStart::Zero => return 0,
+            NonceStart::Seeded(seed) => *seed,
+            NonceStart::Random => {
+                use std::hash::{BuildHasher, Hasher};
+                std::collections::hash_map::RandomState::new()
+                    .build_hasher()
+                    .finish()
+            }
+        };
+        // splitmix64, so nearby seeds give unrelated offsets.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) % MAX_START_OFFSET
+    }
+}
+
+impl std::str::FromStr for NonceStart {
+    type Err = String;
+
+    /// Parses `zero`, `random` or `seed:<n>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(NonceStart::Zero),
+            "random" => Ok(NonceStart::Random),
+            _ => match s.strip_prefix("seed:") {
+                Some(seed) => seed
+                    .parse()
+                    .map(NonceStart::Seeded)
+                    .map_err(|e| format!("invalid nonce seed {:?}: {}", seed, e)),
+                None => Err(format!("unknown nonce start: {}", s)),
+            },
+        }
+    }
+}
+
+/// Knobs for the PoW solver. `Default` matches upstream Anubis.
+#[derive(Debug, Clone, Default)]
+pub struct SolveOptions {
+    pub nonce_format: NonceFormat,
+    pub normalization: ChallengeNormalization,
+    pub concat_order: ConcatOrder,
+    /// Hashed right after the nonce in either order, but not submitted, for
+    /// forks whose server adds a fixed string of its own. At most
+    /// [`MAX_SALT_LEN`] bytes with the default order.
+    pub salt: Option<String>,
+    /// Pool to run the parallel search in instead of rayon's global one, e.g.
+    /// to give solving its own named threads apart from an async runtime's.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub thread_pool: Option<Arc<rayon::ThreadPool>>,
+    /// Give up on a PoW search that runs longer than this.
+    pub max_time: Option<Duration>,
+    /// First nonce to try; see [`NonceStart`].
+    pub start: NonceStart,
+    /// Pin each solver thread to its own CPU, so workers don't migrate
+    /// between cores (or between P and E cores) mid-search. Ignored where
+    /// affinity isn't supported.
+    ///
+    /// Pinning is applied to the pool's worker threads and outlives the solve,
+    /// so prefer a dedicated `thread_pool`. On a machine busy with other work
+    /// it can be slower, as a pinned thread waits for its core instead of
+    /// moving to an idle one.
+    pub pin_threads: bool,
+    /// Stops the search early with a `"cancelled"` error once cancelled.
+    pub cancel: Option<CancelToken>,
+    /// Give up on a PoW search after this many hashes across all threads,
+    /// with an "attempt budget exhausted" error. Each thread gets an equal
+    /// share.
+    pub max_attempts: Option<u64>,
+    /// Track the hash with the most leading zero nibbles seen so far, for
+    /// [`ProgressUpdate::best_seen`] and [`SolverStats::best_seen`]. Threads
+    /// search for one nibble more than the best so far and carry on past
+    /// each near miss, so this costs a few short batches early on.
+    pub track_best: bool,
+}
+
+/// Aborts an in-flight solve from another thread, e.g. when the client that
+/// wanted it disconnects. Clones share the same flag.
+///
+/// Solver threads look at it between batches of 16K nonces, so a cancelled
+/// solve returns within about a millisecond.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl SolveOptions {
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.nonce_format.validate()?;
+        let salt_len = self.salt.as_ref().map_or(0, String::len);
+        if self.concat_order == ConcatOrder::ChallengeThenNonce && salt_len > MAX_SALT_LEN {
+            return Err(format!(
+                "salt of {} bytes exceeds the maximum of {}",
+                salt_len, MAX_SALT_LEN
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// CPUs the process may run on, read once: after pinning, the pinned thread
+/// itself would only see its own CPU.
+#[cfg(not(target_arch = "wasm32"))]
+fn solver_cores() -> &'static [core_affinity::CoreId] {
+    static CORES: std::sync::OnceLock<Vec<core_affinity::CoreId>> = std::sync::OnceLock::new();
+    CORES.get_or_init(|| core_affinity::get_core_ids().unwrap_or_default())
+}
+
+/// Threads the parallel solvers split a search between: those of the current
+/// rayon pool, or just the calling thread on wasm32, which has none.
+fn solver_threads() -> usize {
+    #[cfg(not(target_arch = "wasm32"))]
+    return rayon::current_num_threads();
+    #[cfg(target_arch = "wasm32")]
+    return 1;
 }
 
+/// Nonces a solver thread hashes between checks of whether another thread has
+/// found a solution or the deadline has passed, and between progress reports.
+///
+/// Keeping every check out of the per-hash path measured about 2% faster than
+/// loading the found flag on each hash. The cost is that a search can overrun
+/// by one interval per thread, around a millisecond.
+const CHECK_INTERVAL: u64 = 1024 * 16;
+
 #[derive(Debug, Serialize, Clone)]
 pub struct SolverResult {
     pub hash: String,
     pub data: String,
     pub difficulty: usize,
     pub nonce: Option<u64>,
+    /// Format `nonce` was hashed with, and must be submitted in.
+    pub nonce_format: NonceFormat,
+    /// Where `nonce` went in the hashed preimage.
+    pub concat_order: ConcatOrder,
+    /// Hashed after `nonce`.
+    pub salt: Option<String>,
+    /// Algorithm that produced `hash`.
+    pub algorithm: AnubisAlgorithm,
+    /// Nonces hashed by the search that found this, across all threads. A
+    /// lower bound: threads that lose the race stop mid-batch uncounted. 0
+    /// when nothing was searched (preact, metarefresh, `check_candidates`)
+    /// and for `solve_challenge_many`.
+    pub total_attempts: u64,
+    /// `total_attempts` over the search's wall-clock time, or 0 where
+    /// `total_attempts` is and for `solve_challenge_single_range`.
+    pub hashes_per_second: f64,
+}
+
+impl SolverResult {
+    /// The nonce as submitted to the server, if this algorithm uses one.
+    pub fn nonce_string(&self) -> Option<String> {
+        self.nonce.map(|nonce| self.nonce_format.format(nonce))
+    }
+
+    /// The nonce as it was written into the preimage, if this algorithm uses
+    /// one.
+    pub fn preimage_suffix(&self) -> Option<String> {
+        self.nonce
+            .map(|nonce| self.nonce_format.preimage_suffix(nonce))
+    }
+
+    /// Everything that was hashed, if this algorithm uses a nonce: `data`,
+    /// the nonce and any salt, in `concat_order`.
+    pub fn preimage(&self) -> Option<String> {
+        let nonce = self.preimage_suffix()?;
+        Some(build_preimage(
+            &self.data,
+            &nonce,
+            self.concat_order,
+            self.salt.as_deref(),
+        ))
+    }
 }
 
 /// Preact: SHA256(randomData), server enforces difficulty * 80ms wait.
@@ -114,6 +738,12 @@ pub fn solve_preact_challenge(challenge: &AnubisChallenge) -> SolverResult {
         data: challenge.challenge.random_data.clone(),
         difficulty: challenge.rules.difficulty,
         nonce: None,
+        nonce_format: NonceFormat::default(),
+        concat_order: ConcatOrder::default(),
+        salt: None,
+        algorithm: AnubisAlgorithm::Preact,
+        total_attempts: 0,
+        hashes_per_second: 0.0,
     }
 }
 
@@ -124,6 +754,190 @@ pub fn solve_metarefresh_challenge(challenge: &AnubisChallenge) -> SolverResult
         data: challenge.challenge.random_data.clone(),
         difficulty: challenge.rules.difficulty,
         nonce: None,
+        nonce_format: NonceFormat::default(),
+        concat_order: ConcatOrder::default(),
+        salt: None,
+        algorithm: AnubisAlgorithm::Metarefresh,
+        total_attempts: 0,
+        hashes_per_second: 0.0,
+    }
+}
+
+/// Returns true if the CPU has SHA-256 instructions: SHA-NI (with the SSE
+/// levels `sha2` needs alongside it) on x86/x86_64, the SHA2 crypto extension
+/// on aarch64. Always false on other architectures.
+pub fn cpu_has_sha_extensions() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::is_x86_feature_detected!("sha")
+            && std::is_x86_feature_detected!("sse2")
+            && std::is_x86_feature_detected!("ssse3")
+            && std::is_x86_feature_detected!("sse4.1")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("sha2")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Returns true if the `sha2` backend will use hardware SHA-256 instructions.
+///
+/// `sha2` picks SHA-NI at runtime on x86/x86_64. On aarch64 it only uses the
+/// crypto extension when built with its `asm` feature, which this crate
+/// doesn't enable, so it falls back to the much slower software path there
+/// even if [`cpu_has_sha_extensions`] is true.
+pub fn sha_hardware_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        cpu_has_sha_extensions()
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+/// SHA-256 of `prefix + suffix` for a fixed prefix, computed from the state
+/// after the prefix's full 64-byte blocks. Each [`hash`](Self::hash) only
+/// compresses the one or two final blocks holding the prefix's tail, the suffix
+/// and the padding, without going through the buffering `Digest` API.
+#[derive(Debug, Clone)]
+pub struct Sha256Midstate {
+    state: [u32; 8],
+    tail: [u8; 64],
+    tail_len: usize,
+    prefix_len: u64,
+}
+
+impl Sha256Midstate {
+    /// Longest suffix that still fits in the two final blocks.
+    pub const MAX_SUFFIX_LEN: usize = 55;
+
+    const INITIAL_STATE: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    pub fn new(prefix: &[u8]) -> Self {
+        let full_len = prefix.len() / 64 * 64;
+        let mut state = Self::INITIAL_STATE;
+        let blocks: Vec<GenericArray<u8, U64>> = prefix[..full_len]
+            .chunks_exact(64)
+            .map(|block| *GenericArray::from_slice(block))
+            .collect();
+        sha2::compress256(&mut state, &blocks);
+
+        let mut tail = [0u8; 64];
+        tail[..prefix.len() - full_len].copy_from_slice(&prefix[full_len..]);
+        Sha256Midstate {
+            state,
+            tail,
+            tail_len: prefix.len() - full_len,
+            prefix_len: prefix.len() as u64,
+        }
+    }
+
+    /// Hashes `prefix + suffix`. `suffix` must be at most
+    /// [`MAX_SUFFIX_LEN`](Self::MAX_SUFFIX_LEN) bytes.
+    #[inline]
+    pub fn hash(&self, suffix: &[u8]) -> [u8; 32] {
+        assert!(suffix.len() <= Self::MAX_SUFFIX_LEN, "suffix too long");
+
+        // Written straight into the blocks: staging them in one buffer and
+        // copying left dirty ymm state right before the SHA-NI rounds, which
+        // ran ~80x slower with target-cpu=native.
+        let mut blocks = [GenericArray::<u8, U64>::default(); 2];
+        blocks[0][..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+        let end = self.tail_len + suffix.len();
+        let (in_first, in_second) = suffix.split_at(suffix.len().min(64 - self.tail_len));
+        blocks[0][self.tail_len..self.tail_len + in_first.len()].copy_from_slice(in_first);
+        blocks[1][..in_second.len()].copy_from_slice(in_second);
+        blocks[end / 64][end % 64] = 0x80;
+
+        let block_count = if end + 9 <= 64 { 1 } else { 2 };
+        let bit_len = (self.prefix_len + suffix.len() as u64) * 8;
+        blocks[block_count - 1][56..].copy_from_slice(&bit_len.to_be_bytes());
+
+        let mut state = self.state;
+        sha2::compress256(&mut state, &blocks[..block_count]);
+
+        Self::digest(state)
+    }
+
+    /// `SHA256(SHA256(prefix + suffix))`, for double SHA-256 challenges. The
+    /// inner digest always fits one block, so the outer hash is a single
+    /// compression with fixed padding.
+    #[inline]
+    pub fn hash_twice(&self, suffix: &[u8]) -> [u8; 32] {
+        let inner = self.hash(suffix);
+        let mut block = [GenericArray::<u8, U64>::default()];
+        block[0][..32].copy_from_slice(&inner);
+        block[0][32] = 0x80;
+        block[0][56..].copy_from_slice(&256u64.to_be_bytes());
+
+        let mut state = Self::INITIAL_STATE;
+        sha2::compress256(&mut state, &block);
+        Self::digest(state)
+    }
+
+    #[inline]
+    fn digest(state: [u32; 8]) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// A SHA-256 implementation for [`solve_challenge_native_with_hasher`], for
+/// backends that use SHA instructions `sha2` doesn't (see
+/// [`sha_hardware_accelerated`]). Every solver thread builds its own.
+///
+/// Unlike [`Sha256Midstate`], a `Hasher` hashes the whole prefix for every
+/// nonce, so it only pays off when it is several times faster than `sha2`.
+pub trait Hasher: Default {
+    fn update(&mut self, data: &[u8]);
+    /// SHA-256 of everything since the last reset, leaving the hasher empty.
+    fn finalize_reset(&mut self) -> [u8; 32];
+}
+
+/// [`Hasher`] backed by `sha2`, the same implementation the default solvers use.
+#[derive(Debug, Clone, Default)]
+pub struct Sha2Hasher(Sha256);
+
+impl Hasher for Sha2Hasher {
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    #[inline]
+    fn finalize_reset(&mut self) -> [u8; 32] {
+        self.0.finalize_reset().into()
+    }
+}
+
+/// [`Hasher`] backed by OpenSSL, which uses the SHA2 crypto extension on
+/// aarch64.
+#[cfg(feature = "openssl")]
+#[derive(Clone, Default)]
+pub struct OpensslHasher(openssl::sha::Sha256);
+
+#[cfg(feature = "openssl")]
+impl Hasher for OpensslHasher {
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    #[inline]
+    fn finalize_reset(&mut self) -> [u8; 32] {
+        std::mem::take(&mut self.0).finish()
     }
 }
 
@@ -138,7 +952,7 @@ fn check_difficulty_fast(hash: &[u8], difficulty: usize) -> bool {
         return false;
     }
 
-    if difficulty % 2 != 0 {
+    if difficulty % 2 == 1 {
         if hash.len() <= full_bytes {
             return false;
         }
@@ -149,73 +963,585 @@ fn check_difficulty_fast(hash: &[u8], difficulty: usize) -> bool {
     true
 }
 
-/// PoW solver: find nonce where SHA256(randomData + nonce) has `difficulty` leading zero nibbles.
-impl AnubisChallenge {
-    /// Returns the effective algorithm, defaulting to "fast" for old versions.
-    pub fn algorithm(&self) -> &str {
-        if self.rules.algorithm.is_empty() {
-            "fast"
-        } else {
-            &self.rules.algorithm
-        }
+/// Check if hash has at least `bits` leading zero bits, for PoW variants that
+/// count difficulty in bits rather than nibbles.
+pub fn check_difficulty_bits(hash: &[u8], bits: usize) -> bool {
+    let full_bytes = bits / 8;
+    let partial_bits = bits % 8;
+    if hash.len() < full_bytes + usize::from(partial_bits > 0) {
+        return false;
     }
 
-    /// Returns the minimum wait duration for time-based challenges.
-    pub fn min_wait(&self) -> Option<Duration> {
-        match self.algorithm() {
-            "preact" => Some(Duration::from_millis((self.rules.difficulty as u64) * 80)),
-            "metarefresh" => Some(Duration::from_millis((self.rules.difficulty as u64) * 800)),
-            _ => None,
-        }
+    if hash[..full_bytes].iter().any(|&byte| byte != 0) {
+        return false;
     }
 
-    /// Builds the id query parameter if present.
-    pub fn id_param(&self) -> String {
-        self.challenge
+    // The top `partial_bits` bits of the next byte must be zero too.
+    partial_bits == 0 || hash[full_bytes] >> (8 - partial_bits) == 0
+}
+
+/// What a difficulty counts: leading zero hex digits, as upstream Anubis
+/// does, or leading zero bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DifficultyUnit {
+    #[default]
+    Nibbles,
+    Bits,
+}
+
+impl DifficultyUnit {
+    /// Whether `hash` meets `difficulty` counted in this unit.
+    pub fn check(self, hash: &[u8], difficulty: usize) -> bool {
+        match self {
+            DifficultyUnit::Nibbles => check_difficulty_fast(hash, difficulty),
+            DifficultyUnit::Bits => check_difficulty_bits(hash, difficulty),
+        }
+    }
+}
+
+/// A challenge algorithm this build solves, parsed from `rules.algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnubisAlgorithm {
+    /// PoW over `SHA256(data + nonce)`.
+    #[default]
+    Fast,
+    /// Same hash as `Fast`; upstream's JS just computes it more slowly.
+    Slow,
+    /// PoW over `SHA256(SHA256(data + nonce))`, also spelled `sha256d`.
+    DoubleSha256,
+    Preact,
+    Metarefresh,
+}
+
+impl AnubisAlgorithm {
+    /// The spelling used by `rules.algorithm`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnubisAlgorithm::Fast => "fast",
+            AnubisAlgorithm::Slow => "slow",
+            AnubisAlgorithm::DoubleSha256 => "double_sha256",
+            AnubisAlgorithm::Preact => "preact",
+            AnubisAlgorithm::Metarefresh => "metarefresh",
+        }
+    }
+
+    /// Whether solving means searching for a nonce.
+    pub fn is_pow(&self) -> bool {
+        !matches!(self, AnubisAlgorithm::Preact | AnubisAlgorithm::Metarefresh)
+    }
+}
+
+impl std::str::FromStr for AnubisAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(AnubisAlgorithm::Fast),
+            "slow" => Ok(AnubisAlgorithm::Slow),
+            "double_sha256" | "sha256d" => Ok(AnubisAlgorithm::DoubleSha256),
+            "preact" => Ok(AnubisAlgorithm::Preact),
+            "metarefresh" => Ok(AnubisAlgorithm::Metarefresh),
+            _ => Err(format!("unsupported algorithm: {}", s)),
+        }
+    }
+}
+
+impl AnubisChallenge {
+    /// [`algorithm`](Self::algorithm) parsed, or an error for one this build
+    /// can't solve.
+    pub fn anubis_algorithm(&self) -> Result<AnubisAlgorithm, String> {
+        self.algorithm().parse()
+    }
+
+    /// The PoW algorithm to search with, or an error for unsupported and
+    /// time-based ones.
+    fn pow_algorithm(&self) -> Result<AnubisAlgorithm, String> {
+        let algorithm = self.anubis_algorithm()?;
+        if !algorithm.is_pow() {
+            return Err(format!(
+                "{} challenges have no nonce to search for",
+                algorithm.as_str()
+            ));
+        }
+        Ok(algorithm)
+    }
+
+    /// Collects every field the server sent that we don't interpret, keyed by
+    /// its location (`challenge.*`, `rules.*`, or top level).
+    pub fn unrecognized_fields(&self) -> Map<String, Value> {
+        let mut fields = Map::new();
+        for (key, value) in &self.challenge.extra {
+            fields.insert(format!("challenge.{}", key), value.clone());
+        }
+        for (key, value) in &self.rules.extra {
+            fields.insert(format!("rules.{}", key), value.clone());
+        }
+        for (key, value) in &self.extra {
+            fields.insert(key.clone(), value.clone());
+        }
+        fields
+    }
+
+    /// Returns the effective algorithm: the challenge's own `algorithm` field,
+    /// else one set by [`set_default_algorithm`](Self::set_default_algorithm),
+    /// else [`DEFAULT_ALGORITHM`] for old versions that never sent one.
+    pub fn algorithm(&self) -> &str {
+        if self.rules.algorithm.is_empty() {
+            DEFAULT_ALGORITHM
+        } else {
+            &self.rules.algorithm
+        }
+    }
+
+    /// Uses `algorithm` if the server didn't name one, for forks that omit the
+    /// field but don't run the upstream default. An explicit field always wins.
+    pub fn set_default_algorithm(&mut self, algorithm: &str) {
+        if self.rules.algorithm.is_empty() {
+            self.rules.algorithm = algorithm.to_string();
+        }
+    }
+
+    /// Returns the minimum wait duration for time-based challenges.
+    pub fn min_wait(&self) -> Option<Duration> {
+        match self.algorithm() {
+            "preact" => Some(Duration::from_millis((self.rules.difficulty as u64) * 80)),
+            "metarefresh" => Some(Duration::from_millis((self.rules.difficulty as u64) * 800)),
+            _ => None,
+        }
+    }
+
+    /// Hex length of the digest submitted for this algorithm, or `None` when
+    /// the submitted value isn't a digest (metarefresh echoes the challenge).
+    /// Unknown algorithms are an error rather than assumed to be SHA-256.
+    pub fn digest_hex_len(&self) -> Result<Option<usize>, String> {
+        match self.algorithm() {
+            "fast" | "slow" | "double_sha256" | "sha256d" | "preact" => {
+                Ok(Some(Sha256::output_size() * 2))
+            }
+            "metarefresh" => Ok(None),
+            other => Err(format!("unknown digest length for algorithm: {}", other)),
+        }
+    }
+
+    /// PoW over `SHA256(SHA256(data + nonce))`, as Bitcoin-style forks use.
+    pub fn is_double_sha256(&self) -> bool {
+        matches!(self.algorithm(), "double_sha256" | "sha256d")
+    }
+
+    /// Builds the URL-encoded id query parameter if present.
+    pub fn id_param(&self) -> String {
+        self.challenge
             .id
             .as_ref()
-            .map(|id| format!("&id={}", id))
+            .map(|id| format!("&id={}", urlencoding::encode(id)))
             .unwrap_or_default()
     }
 }
 
+/// How the challenge was written in the page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeFormat {
+    /// A JSON object with `challenge` and `rules`, as upstream Anubis sends.
+    Json,
+    /// Everything packed into one string, such as `difficulty:5|data:abc123`,
+    /// either on its own or as the `challenge` of an object without a
+    /// difficulty in `rules`.
+    Inline,
+}
+
+/// Where in the page the challenge was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeSource {
+    /// The text of the challenge selector's element (`#anubis_challenge`).
+    Element,
+    /// The `value` of the challenge selector's element, for pages that put
+    /// the challenge in a hidden `<input>`.
+    Value,
+    /// One of [`CHALLENGE_ATTRIBUTES`] on any element.
+    Attribute(&'static str),
+    /// A [`JSON_LD_PROPERTIES`] property of a `application/ld+json` script.
+    JsonLd,
+}
+
+/// `data-*` attributes some integrations put the challenge JSON in.
+pub const CHALLENGE_ATTRIBUTES: &[&str] = &["data-anubis-challenge", "data-challenge"];
+/// JSON-LD properties holding the challenge object.
+pub const JSON_LD_PROPERTIES: &[&str] = &["anubisChallenge", "anubis_challenge"];
+
 /// Parsed challenge with optional version info.
+#[derive(Debug, Clone)]
 pub struct ParsedChallenge {
     pub challenge: AnubisChallenge,
     pub version: String,
+    pub format: ChallengeFormat,
+    pub source: ChallengeSource,
 }
 
 /// Parse Anubis challenge from HTML response body.
+///
+/// Returns `None` both for pages without a challenge and for challenges that
+/// fail to parse; use [`try_parse_challenge_from_html`] to tell them apart.
 pub fn parse_challenge_from_html(html: &str) -> Option<ParsedChallenge> {
-    if !html.contains("anubis_challenge") {
-        return None;
+    try_parse_challenge_from_html(html).ok().flatten()
+}
+
+/// Parse Anubis challenge from HTML response body, distinguishing pages that
+/// carry no challenge (`Ok(None)`, usually the real content) from a challenge
+/// element whose JSON can't be parsed (`Err`).
+pub fn try_parse_challenge_from_html(html: &str) -> Result<Option<ParsedChallenge>, String> {
+    try_parse_challenge_from_html_with_selectors(html, &ChallengeSelectors::default())
+}
+
+/// Compiles a CSS selector, with an error that names the offending input.
+pub fn compile_selector(css: &str) -> Result<Selector, String> {
+    Selector::parse(css).map_err(|e| format!("invalid selector {:?}: {}", css, e))
+}
+
+/// Where the challenge and version JSON live in the page. Compiled up front,
+/// so a bad user-supplied selector fails at startup rather than per request.
+#[derive(Debug, Clone)]
+pub struct ChallengeSelectors {
+    challenge: Selector,
+    version: Selector,
+    /// Whether these are upstream's ids, which allows a cheap text pre-check.
+    upstream: bool,
+}
+
+impl ChallengeSelectors {
+    pub const CHALLENGE: &'static str = "#anubis_challenge";
+    pub const VERSION: &'static str = "#anubis_version";
+
+    pub fn new(challenge: &str, version: &str) -> Result<Self, String> {
+        Ok(ChallengeSelectors {
+            challenge: compile_selector(challenge)?,
+            version: compile_selector(version)?,
+            upstream: challenge == Self::CHALLENGE,
+        })
     }
+}
 
-    let document = Html::parse_document(html);
+impl Default for ChallengeSelectors {
+    fn default() -> Self {
+        Self::new(Self::CHALLENGE, Self::VERSION).expect("built-in selectors are valid")
+    }
+}
+
+/// [`try_parse_challenge_from_html`] for forks that embed the challenge under
+/// other elements.
+///
+/// The challenge element's text is tried first, then its `value`, then
+/// [`CHALLENGE_ATTRIBUTES`], then JSON-LD scripts; the first that parses
+/// wins. If some were found but none
+/// parsed, the error is the first one's.
+pub fn try_parse_challenge_from_html_with_selectors(
+    html: &str,
+    selectors: &ChallengeSelectors,
+) -> Result<Option<ParsedChallenge>, String> {
+    if selectors.upstream
+        && ![
+            "anubis_challenge",
+            "anubis-challenge",
+            "anubisChallenge",
+            "data-challenge",
+        ]
+        .iter()
+        .any(|marker| html.contains(marker))
+    {
+        return Ok(None);
+    }
 
-    let challenge_selector = Selector::parse("#anubis_challenge").ok()?;
-    let challenge_element = document.select(&challenge_selector).next()?;
-    let challenge_json = challenge_element.text().collect::<String>();
+    let document = Html::parse_document(html);
 
-    if challenge_json.trim() == "null" || challenge_json.is_empty() {
-        return None;
+    let mut candidates = Vec::new();
+    if let Some(element) = document.select(&selectors.challenge).next() {
+        candidates.push((ChallengeSource::Element, element.text().collect::<String>()));
+        if let Some(value) = element.value().attr("value") {
+            candidates.push((ChallengeSource::Value, value.to_string()));
+        }
+    }
+    for &attribute in CHALLENGE_ATTRIBUTES {
+        let selector = compile_selector(&format!("[{}]", attribute))
+            .expect("built-in attribute selectors are valid");
+        for element in document.select(&selector) {
+            if let Some(value) = element.value().attr(attribute) {
+                candidates.push((ChallengeSource::Attribute(attribute), value.to_string()));
+            }
+        }
+    }
+    let json_ld = compile_selector(r#"script[type="application/ld+json"]"#)
+        .expect("built-in JSON-LD selector is valid");
+    for script in document.select(&json_ld) {
+        if let Ok(Value::Object(mut object)) =
+            serde_json::from_str::<Value>(&script.text().collect::<String>())
+        {
+            for property in JSON_LD_PROPERTIES {
+                if let Some(challenge) = object.remove(*property) {
+                    candidates.push((ChallengeSource::JsonLd, challenge.to_string()));
+                }
+            }
+        }
     }
 
-    let challenge: AnubisChallenge = serde_json::from_str(&challenge_json).ok()?;
+    let mut first_error = None;
+    let mut found = None;
+    for (source, text) in candidates {
+        if text.trim() == "null" || text.is_empty() {
+            continue;
+        }
+        match parse_challenge_text(&text) {
+            Ok((challenge, format)) => {
+                found = Some((challenge, format, source));
+                break;
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    let (challenge, format, source) = match (found, first_error) {
+        (Some(found), _) => found,
+        (None, Some(e)) => return Err(e),
+        (None, None) => return Ok(None),
+    };
 
-    let version = Selector::parse("#anubis_version")
-        .ok()
-        .and_then(|sel| document.select(&sel).next())
+    let version = document
+        .select(&selectors.version)
+        .next()
         .and_then(|el| {
             let json = el.text().collect::<String>();
             serde_json::from_str::<String>(&json).ok()
         })
         .unwrap_or_else(|| "unknown".to_string());
 
-    Some(ParsedChallenge { challenge, version })
+    Ok(Some(ParsedChallenge {
+        challenge,
+        version,
+        format,
+        source,
+    }))
+}
+
+/// Parses challenge text from any [`ChallengeSource`], as JSON or in the
+/// [`ChallengeFormat::Inline`] shape.
+fn parse_challenge_text(text: &str) -> Result<(AnubisChallenge, ChallengeFormat), String> {
+    let json = extract_json_object(text).unwrap_or(text);
+    match serde_json::from_str::<AnubisChallenge>(json) {
+        Ok(challenge) => Ok((challenge, ChallengeFormat::Json)),
+        Err(e) => match parse_inline_challenge(json) {
+            Some(challenge) => Ok((challenge, ChallengeFormat::Inline)),
+            None => Err(format!("Malformed Anubis challenge: {}", e)),
+        },
+    }
+}
+
+/// Parses the [`ChallengeFormat::Inline`] shape: `|`-separated `key:value`
+/// pairs with `difficulty` and `data`, and optionally `algorithm`. Any other
+/// key means the string isn't in this format.
+fn parse_inline_challenge(text: &str) -> Option<AnubisChallenge> {
+    let (packed, mut rules, extra) = match serde_json::from_str::<Value>(text) {
+        Ok(Value::String(packed)) => (packed, Map::new(), Map::new()),
+        Ok(Value::Object(mut object)) => {
+            let packed = match object.remove("challenge")? {
+                Value::String(packed) => packed,
+                _ => return None,
+            };
+            let rules = match object.remove("rules") {
+                Some(Value::Object(rules)) => rules,
+                None => Map::new(),
+                Some(_) => return None,
+            };
+            (packed, rules, object)
+        }
+        Ok(_) => return None,
+        Err(_) => (text.trim().to_string(), Map::new(), Map::new()),
+    };
+
+    let mut difficulty = None;
+    let mut data = None;
+    let mut algorithm = None;
+    for pair in packed.split('|') {
+        let (key, value) = pair.split_once(':')?;
+        match key.trim() {
+            "difficulty" => difficulty = Some(value.trim().parse().ok()?),
+            "data" => data = Some(value.to_string()),
+            "algorithm" => algorithm = Some(value.trim().to_string()),
+            _ => return None,
+        }
+    }
+
+    let algorithm = match (algorithm, rules.remove("algorithm")) {
+        (Some(algorithm), _) => algorithm,
+        (None, Some(Value::String(algorithm))) => algorithm,
+        _ => String::new(),
+    };
+    Some(AnubisChallenge {
+        challenge: ChallengeData {
+            id: None,
+            random_data: data?,
+            extra: Map::new(),
+        },
+        rules: AnubisChallengeRules {
+            difficulty: difficulty?,
+            algorithm,
+            extra: rules,
+        },
+        extra,
+    })
+}
+
+/// Returns the first balanced `{...}` object in `text`, dropping what some pages
+/// put around it: a `var x =` prefix, a trailing `;` or JS comments. Braces
+/// inside strings and comments don't count towards the balance.
+fn extract_json_object(text: &str) -> Option<&str> {
+    let bytes = text.as_bytes();
+    let mut start = None;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+        } else if bytes[i..].starts_with(b"//") {
+            i = text[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            continue;
+        } else if bytes[i..].starts_with(b"/*") {
+            i = text[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2);
+            continue;
+        } else if b == b'"' {
+            in_string = true;
+        } else if b == b'{' {
+            start.get_or_insert(i);
+            depth += 1;
+        } else if b == b'}' {
+            if let Some(start) = start {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[start..=i]);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// What a PoW submission sends as `response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnswerFormat {
+    /// The hex digest, as upstream Anubis expects.
+    #[default]
+    Hash,
+    /// `<hash>:<nonce>`.
+    HashAndNonce,
+    /// The hashed preimage (challenge data followed by the nonce), hex encoded.
+    Preimage,
+}
+
+impl AnswerFormat {
+    fn answer(&self, result: &SolverResult) -> String {
+        let nonce = result.nonce_string().unwrap_or_else(|| "0".to_string());
+        match self {
+            AnswerFormat::Hash => result.hash.clone(),
+            AnswerFormat::HashAndNonce => format!("{}:{}", result.hash, nonce),
+            AnswerFormat::Preimage => hex::encode(
+                result
+                    .preimage()
+                    .unwrap_or_else(|| format!("{}{}", result.data, nonce)),
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for AnswerFormat {
+    type Err = String;
+
+    /// Parses `hash`, `hash:nonce` or `preimage`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(AnswerFormat::Hash),
+            "hash:nonce" => Ok(AnswerFormat::HashAndNonce),
+            "preimage" => Ok(AnswerFormat::Preimage),
+            _ => Err(format!("unknown answer format: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for AnswerFormat {
+    /// Writes the spelling [`FromStr`](std::str::FromStr) parses.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AnswerFormat::Hash => "hash",
+            AnswerFormat::HashAndNonce => "hash:nonce",
+            AnswerFormat::Preimage => "preimage",
+        })
+    }
+}
+
+/// Where and how a solution is submitted, for forks that moved the endpoint or
+/// expect more parameters.
+#[derive(Debug, Clone)]
+pub struct SubmissionOptions {
+    /// Path of the pass-challenge endpoint, without the leading `/`.
+    pub path: String,
+    /// Appended after the standard parameters, URL-encoded. One named like a
+    /// standard parameter is an error unless `override_params` is set.
+    pub extra_params: Vec<(String, String)>,
+    /// Let `extra_params` replace standard parameters such as `redir` instead
+    /// of failing.
+    pub override_params: bool,
+    /// What PoW submissions send as `response`.
+    pub answer_format: AnswerFormat,
+    /// Overrides of `answer_format` for servers whose `anubis_version` starts
+    /// with the given prefix; the first match wins. Applied by
+    /// [`for_version`](Self::for_version).
+    pub version_answer_formats: Vec<(String, AnswerFormat)>,
+}
+
+impl SubmissionOptions {
+    /// These options with `answer_format` resolved for a server's version.
+    pub fn for_version(&self, version: &str) -> SubmissionOptions {
+        let mut options = self.clone();
+        if let Some((_, format)) = self
+            .version_answer_formats
+            .iter()
+            .find(|(prefix, _)| version.starts_with(prefix.as_str()))
+        {
+            options.answer_format = *format;
+        }
+        options
+    }
+}
+
+impl Default for SubmissionOptions {
+    fn default() -> Self {
+        SubmissionOptions {
+            path: SUBMISSION_PATH.to_string(),
+            extra_params: Vec::new(),
+            override_params: false,
+            answer_format: AnswerFormat::default(),
+            version_answer_formats: Vec::new(),
+        }
+    }
 }
 
 /// Build submission URL for the solved challenge.
+///
+/// Fails if `result.hash` isn't the hex length of the digest
+/// `challenge.algorithm()` produces, which means the wrong algorithm was solved,
+/// or if the algorithm is unknown.
 pub fn build_submission_url(
     scheme: &str,
     host: &str,
@@ -223,140 +1549,3243 @@ pub fn build_submission_url(
     result: &SolverResult,
     redir_url: &str,
     elapsed_ms: u128,
-) -> String {
+) -> Result<String, String> {
+    build_submission_url_with_options(
+        scheme,
+        host,
+        challenge,
+        result,
+        redir_url,
+        elapsed_ms,
+        &SubmissionOptions::default(),
+    )
+}
+
+/// [`build_submission_url`] with a custom endpoint and extra parameters.
+///
+/// The query holds, in order:
+/// - the solution: `response` (see [`AnswerFormat`]) and `nonce` for PoW,
+///   `result` for preact, `challenge` for metarefresh
+/// - `redir`, the page to return to, URL-encoded
+/// - `elapsedTime`, the solve time in milliseconds
+/// - `id`, if the challenge has one
+/// - `options.extra_params`, unless they replace one of the above
+///
+/// [`parse_submission_url`] takes such a URL apart again.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(host = host, algorithm = challenge.algorithm(), elapsed_ms = elapsed_ms as u64),
+        err
+    )
+)]
+pub fn build_submission_url_with_options(
+    scheme: &str,
+    host: &str,
+    challenge: &AnubisChallenge,
+    result: &SolverResult,
+    redir_url: &str,
+    elapsed_ms: u128,
+    options: &SubmissionOptions,
+) -> Result<String, String> {
+    if let Some(expected) = challenge.digest_hex_len()? {
+        if result.hash.len() != expected {
+            return Err(format!(
+                "{} hash must be {} hex chars, got {}",
+                challenge.algorithm(),
+                expected,
+                result.hash.len()
+            ));
+        }
+    }
+
+    // Every value is encoded: redir is a full URL with its own query, and
+    // metarefresh echoes challenge data verbatim.
     let id_param = challenge.id_param();
     let encoded_redir = urlencoding::encode(redir_url);
+    let hash = urlencoding::encode(&result.hash);
+    let path = &options.path;
 
-    match challenge.algorithm() {
+    let mut url = match challenge.algorithm() {
         "preact" => format!(
             "{}://{}/{}?result={}&redir={}&elapsedTime={}{}",
-            scheme, host, SUBMISSION_PATH, result.hash, encoded_redir, elapsed_ms, id_param
+            scheme, host, path, hash, encoded_redir, elapsed_ms, id_param
         ),
         "metarefresh" => format!(
             "{}://{}/{}?challenge={}&redir={}&elapsedTime={}{}",
-            scheme, host, SUBMISSION_PATH, result.hash, encoded_redir, elapsed_ms, id_param
+            scheme, host, path, hash, encoded_redir, elapsed_ms, id_param
         ),
         _ => format!(
             "{}://{}/{}?response={}&nonce={}&redir={}&elapsedTime={}{}",
             scheme,
             host,
-            SUBMISSION_PATH,
-            result.hash,
-            result.nonce.unwrap_or(0),
+            path,
+            urlencoding::encode(&options.answer_format.answer(result)),
+            result.nonce_string().unwrap_or_else(|| "0".to_string()),
             encoded_redir,
             elapsed_ms,
             id_param
         ),
+    };
+    for (name, value) in &options.extra_params {
+        let name = urlencoding::encode(name);
+        let value = urlencoding::encode(value);
+        let (base, query) = url.split_once('?').expect("submission URLs have a query");
+        let mut replaced = false;
+        let pairs: Vec<String> = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((existing, _)) if existing == name => {
+                    replaced = true;
+                    format!("{}={}", name, value)
+                }
+                _ => pair.to_string(),
+            })
+            .collect();
+        if !replaced {
+            url.push_str(&format!("&{}={}", name, value));
+        } else if options.override_params {
+            url = format!("{}?{}", base, pairs.join("&"));
+        } else {
+            return Err(format!(
+                "extra parameter {:?} would replace a standard one; set override_params to allow it",
+                name
+            ));
+        }
     }
+    Ok(url)
 }
 
-/// Solve the challenge based on its algorithm type.
-pub fn solve_challenge<F>(
-    challenge: &AnubisChallenge,
-    progress_callback: Option<F>,
-) -> Result<SolverResult, String>
-where
-    F: Fn(u64) + Send + Sync + 'static,
-{
-    match challenge.algorithm() {
-        "preact" => Ok(solve_preact_challenge(challenge)),
-        "metarefresh" => Ok(solve_metarefresh_challenge(challenge)),
-        _ => solve_challenge_native(challenge, progress_callback),
+/// A submission URL taken apart by [`parse_submission_url`], with its
+/// parameters decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmissionParams {
+    pub scheme: String,
+    pub host: String,
+    /// Without the leading `/`.
+    pub path: String,
+    /// In URL order.
+    pub params: Vec<(String, String)>,
+}
+
+impl SubmissionParams {
+    /// First value of the parameter `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
     }
 }
 
-/// PoW solver: find nonce where SHA256(randomData + nonce) has `difficulty` leading zero nibbles.
-pub fn solve_challenge_native<F>(
-    challenge: &AnubisChallenge,
-    progress_callback: Option<F>,
-) -> Result<SolverResult, String>
-where
-    F: Fn(u64) + Send + Sync + 'static,
-{
-    let num_threads = rayon::current_num_threads();
-    let difficulty = challenge.rules.difficulty;
-    let data_bytes = challenge.challenge.random_data.as_bytes();
-    let initial_capacity = data_bytes.len() + 20;
+/// Splits a submission URL into its parts, e.g. to check one produced
+/// elsewhere against [`build_submission_url`].
+pub fn parse_submission_url(url: &str) -> Result<SubmissionParams, String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("Invalid submission URL: {}", e))?;
+    let host = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err("Submission URL has no host".to_string()),
+    };
+    Ok(SubmissionParams {
+        scheme: parsed.scheme().to_string(),
+        host,
+        path: parsed.path().trim_start_matches('/').to_string(),
+        params: parsed.query_pairs().into_owned().collect(),
+    })
+}
 
-    let found_solution = Arc::new(AtomicBool::new(false));
-    let result_nonce = Arc::new(AtomicU64::new(0));
-    let progress_callback = progress_callback.map(Arc::new);
+/// One way a submission URL differs from a reference, e.g. a browser's
+/// successful submission captured from devtools. See [`compare_submissions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmissionMismatch {
+    /// Different scheme, host or path.
+    Endpoint { ours: String, theirs: String },
+    /// Sent by the reference but not by us.
+    Missing { name: String, theirs: String },
+    /// Sent by us but not by the reference.
+    Unexpected { name: String, ours: String },
+    /// Sent by both with different decoded values.
+    Value {
+        name: String,
+        ours: String,
+        theirs: String,
+    },
+    /// Same decoded value, encoded differently on the wire.
+    Encoding {
+        name: String,
+        ours: String,
+        theirs: String,
+    },
+}
 
-    let result = (0..num_threads)
-        .into_par_iter()
-        .map(|thread_id| {
-            let mut nonce = thread_id as u64;
-            let mut hasher = Sha256::new();
-            let local_found = found_solution.clone();
-            let local_progress_callback = progress_callback.clone();
+impl std::fmt::Display for SubmissionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmissionMismatch::Endpoint { ours, theirs } => {
+                write!(f, "endpoint: ours {}, theirs {}", ours, theirs)
+            }
+            SubmissionMismatch::Missing { name, theirs } => {
+                write!(f, "{}: missing (theirs {:?})", name, theirs)
+            }
+            SubmissionMismatch::Unexpected { name, ours } => {
+                write!(f, "{}: not sent by theirs (ours {:?})", name, ours)
+            }
+            SubmissionMismatch::Value { name, ours, theirs } => {
+                write!(f, "{}: ours {:?}, theirs {:?}", name, ours, theirs)
+            }
+            SubmissionMismatch::Encoding { name, ours, theirs } => {
+                write!(f, "{}: encoded as {}, theirs {}", name, ours, theirs)
+            }
+        }
+    }
+}
 
-            let mut buffer = Vec::with_capacity(initial_capacity);
-            buffer.extend_from_slice(data_bytes);
-            let data_len = data_bytes.len();
-            let mut itoa_buf = itoa::Buffer::new();
+/// Lists how the submission URL `ours` differs from `theirs`, to find why a
+/// submission is rejected where a browser's succeeds. Parameters are matched
+/// by name, first occurrence. `response`, `nonce` and `elapsedTime` differ
+/// between any two solves; a mismatch there only matters if the value looks
+/// different in kind, e.g. seconds instead of milliseconds.
+pub fn compare_submissions(ours: &str, theirs: &str) -> Result<Vec<SubmissionMismatch>, String> {
+    let ours_params = parse_submission_url(ours)?;
+    let theirs_params = parse_submission_url(theirs)?;
+    let ours_raw = raw_query_pairs(ours)?;
+    let theirs_raw = raw_query_pairs(theirs)?;
+    let mut mismatches = Vec::new();
 
-            while !local_found.load(Ordering::Relaxed) {
-                let nonce_str_bytes = itoa_buf.format(nonce).as_bytes();
-                buffer.truncate(data_len);
-                buffer.extend_from_slice(nonce_str_bytes);
+    let endpoint =
+        |params: &SubmissionParams| format!("{}://{}/{}", params.scheme, params.host, params.path);
+    if endpoint(&ours_params) != endpoint(&theirs_params) {
+        mismatches.push(SubmissionMismatch::Endpoint {
+            ours: endpoint(&ours_params),
+            theirs: endpoint(&theirs_params),
+        });
+    }
 
-                hasher.update(&buffer);
-                let hash_result = hasher.finalize_reset();
+    for (index, (name, theirs_value)) in theirs_params.params.iter().enumerate() {
+        let Some(ours_index) = ours_params.params.iter().position(|(key, _)| key == name) else {
+            mismatches.push(SubmissionMismatch::Missing {
+                name: name.clone(),
+                theirs: theirs_value.clone(),
+            });
+            continue;
+        };
+        let ours_value = &ours_params.params[ours_index].1;
+        if ours_value != theirs_value {
+            mismatches.push(SubmissionMismatch::Value {
+                name: name.clone(),
+                ours: ours_value.clone(),
+                theirs: theirs_value.clone(),
+            });
+        } else if ours_raw[ours_index] != theirs_raw[index] {
+            mismatches.push(SubmissionMismatch::Encoding {
+                name: name.clone(),
+                ours: ours_raw[ours_index].clone(),
+                theirs: theirs_raw[index].clone(),
+            });
+        }
+    }
+    for (name, ours_value) in &ours_params.params {
+        if theirs_params.get(name).is_none() {
+            mismatches.push(SubmissionMismatch::Unexpected {
+                name: name.clone(),
+                ours: ours_value.clone(),
+            });
+        }
+    }
 
-                if check_difficulty_fast(&hash_result, difficulty) {
-                    if !local_found.swap(true, Ordering::SeqCst) {
-                        result_nonce.store(nonce, Ordering::Relaxed);
-                        return Some(SolverResult {
-                            hash: hex::encode(hash_result),
-                            data: challenge.challenge.random_data.clone(),
-                            difficulty,
-                            nonce: Some(nonce),
-                        });
-                    } else {
-                        return None;
-                    }
-                }
+    Ok(mismatches)
+}
 
-                if nonce % (1024 * 16) == thread_id as u64 {
-                    if let Some(ref cb_arc) = local_progress_callback {
-                        cb_arc(nonce);
-                    }
-                }
+/// Query values as they appear in `url`, before decoding, in the same order as
+/// [`SubmissionParams::params`].
+fn raw_query_pairs(url: &str) -> Result<Vec<String>, String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("Invalid submission URL: {}", e))?;
+    Ok(parsed
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map_or("", |(_, value)| value)
+                .to_string()
+        })
+        .collect())
+}
 
-                match nonce.checked_add(num_threads as u64) {
-                    Some(next_nonce) => nonce = next_nonce,
-                    None => break,
-                }
+/// Result of one [`NonceSearcher::next_chunk`] call.
+#[derive(Debug, Clone)]
+pub enum SearchStep {
+    Found(SolverResult),
+    /// No solution in this chunk; total attempts made so far.
+    Progress(u64),
+    /// Every nonce has been tried.
+    Exhausted,
+}
+
+/// Pull-based, single-threaded PoW search for embedders that drive their own
+/// event loop: each [`next_chunk`](Self::next_chunk) call hashes a bounded
+/// number of nonces and returns, so the caller can interleave other work or
+/// stop between chunks. Only for PoW algorithms; preact and metarefresh
+/// challenges have no nonce to search for.
+pub struct NonceSearcher {
+    hasher: Sha256Midstate,
+    algorithm: AnubisAlgorithm,
+    data: String,
+    /// What [`preimage_parts`] keeps fixed.
+    fixed: Vec<u8>,
+    difficulty: usize,
+    nonce_format: NonceFormat,
+    concat_order: ConcatOrder,
+    salt: Option<String>,
+    next_nonce: Option<u64>,
+    attempts: u64,
+    encoder: NonceEncoder,
+}
+
+impl NonceSearcher {
+    pub fn new(challenge: &AnubisChallenge, options: &SolveOptions) -> Result<Self, String> {
+        let algorithm = challenge.pow_algorithm()?;
+        options.validate()?;
+
+        let data = options
+            .normalization
+            .apply(&challenge.challenge.random_data);
+        let (fixed, encoder) = preimage_parts(&data, options);
+        Ok(NonceSearcher {
+            hasher: Sha256Midstate::new(&fixed),
+            algorithm,
+            data,
+            fixed,
+            difficulty: challenge.rules.difficulty,
+            nonce_format: options.nonce_format,
+            concat_order: options.concat_order,
+            salt: options.salt.clone(),
+            next_nonce: Some(options.start.offset()),
+            attempts: 0,
+            encoder,
+        })
+    }
+
+    /// Tries up to `n` nonces, continuing where the previous call stopped.
+    pub fn next_chunk(&mut self, n: u64) -> SearchStep {
+        for _ in 0..n {
+            let Some(nonce) = self.next_nonce else {
+                return SearchStep::Exhausted;
+            };
+            self.next_nonce = nonce.checked_add(1);
+            self.attempts += 1;
+
+            if let Some(result) = self.check(nonce) {
+                return SearchStep::Found(result);
             }
-            None
+        }
+
+        if self.next_nonce.is_none() {
+            SearchStep::Exhausted
+        } else {
+            SearchStep::Progress(self.attempts)
+        }
+    }
+
+    /// Hashes one nonce, returning the result if it meets the difficulty.
+    fn check(&mut self, nonce: u64) -> Option<SolverResult> {
+        let suffix = self.encoder.encode(self.nonce_format, nonce);
+        let double = self.algorithm == AnubisAlgorithm::DoubleSha256;
+        let hash_result = match self.concat_order {
+            ConcatOrder::NonceThenChallenge => hash_nonce_first(suffix, &self.fixed, double),
+            ConcatOrder::ChallengeThenNonce if double => self.hasher.hash_twice(suffix),
+            ConcatOrder::ChallengeThenNonce => self.hasher.hash(suffix),
+        };
+
+        check_difficulty_fast(&hash_result, self.difficulty).then(|| SolverResult {
+            hash: hex::encode(hash_result),
+            data: self.data.clone(),
+            difficulty: self.difficulty,
+            nonce: Some(nonce),
+            nonce_format: self.nonce_format,
+            concat_order: self.concat_order,
+            salt: self.salt.clone(),
+            algorithm: self.algorithm,
+            total_attempts: self.attempts,
+            hashes_per_second: 0.0,
         })
-        .find_any(|res| res.is_some())
-        .flatten();
+    }
 
-    match result {
-        Some(res) => Ok(res),
-        None => {
-            if found_solution.load(Ordering::Relaxed) {
-                let winning_nonce = result_nonce.load(Ordering::Relaxed);
-
-                let mut buffer = Vec::with_capacity(initial_capacity);
-                buffer.extend_from_slice(data_bytes);
-                let data_len = data_bytes.len();
-                let mut itoa_buf = itoa::Buffer::new();
-                let nonce_str_bytes = itoa_buf.format(winning_nonce).as_bytes();
-                buffer.truncate(data_len);
-                buffer.extend_from_slice(nonce_str_bytes);
-                let hash_result = Sha256::digest(&buffer);
-
-                Ok(SolverResult {
-                    hash: hex::encode(hash_result),
-                    data: challenge.challenge.random_data.clone(),
-                    difficulty,
-                    nonce: Some(winning_nonce),
-                })
-            } else {
-                Err("Solver finished without finding a solution.".to_string())
+    /// Total nonces hashed so far.
+    pub fn attempts(&self) -> u64 {
+        self.attempts
+    }
+}
+
+/// Phrases servers use when a challenge or nonce is submitted a second time.
+const DUPLICATE_SUBMISSION_MARKERS: &[&str] = &[
+    "already used",
+    "already been used",
+    "already solved",
+    "already been solved",
+    "already spent",
+    "already been spent",
+];
+
+/// Returns true if a rejected submission's body says the challenge was already
+/// passed, e.g. because a retried submission had in fact succeeded the first time.
+/// The session may then be valid, so refetching is better than failing.
+pub fn is_duplicate_submission(body: &str) -> bool {
+    let body = body.to_lowercase();
+    DUPLICATE_SUBMISSION_MARKERS
+        .iter()
+        .any(|marker| body.contains(marker))
+}
+
+/// Returns true if `result` would pass the server's checks, for results solved
+/// with the default options. A malformed hash or missing nonce is `false`; see
+/// [`verify_solution_with_options`] for other options and the reason.
+pub fn verify_solution(challenge: &AnubisChallenge, result: &SolverResult) -> bool {
+    verify_solution_with_options(challenge, result, &SolveOptions::default()).is_ok()
+}
+
+/// Checks `result` the way the server would, from the challenge alone: a PoW
+/// nonce must rebuild `result.hash` and meet the difficulty, a preact hash
+/// must be the digest of the data and a metarefresh response must echo it.
+/// `options` must be the ones the result was solved with.
+pub fn verify_solution_with_options(
+    challenge: &AnubisChallenge,
+    result: &SolverResult,
+    options: &SolveOptions,
+) -> Result<(), String> {
+    let data = &challenge.challenge.random_data;
+    let expected = match challenge.anubis_algorithm()? {
+        AnubisAlgorithm::Fast | AnubisAlgorithm::Slow | AnubisAlgorithm::DoubleSha256 => {
+            let nonce = result
+                .nonce_string()
+                .ok_or("PoW result has no nonce".to_string())?;
+            let preimage = build_preimage(
+                &options.normalization.apply(data),
+                &result.preimage_suffix().unwrap_or_default(),
+                options.concat_order,
+                options.salt.as_deref(),
+            );
+            let mut hash = Sha256::digest(preimage);
+            if challenge.is_double_sha256() {
+                hash = Sha256::digest(hash);
             }
+            if !check_difficulty_fast(&hash, challenge.rules.difficulty) {
+                return Err(format!(
+                    "nonce {} doesn't meet difficulty {}",
+                    nonce, challenge.rules.difficulty
+                ));
+            }
+            hex::encode(hash)
+        }
+        AnubisAlgorithm::Preact => hex::encode(Sha256::digest(data.as_bytes())),
+        AnubisAlgorithm::Metarefresh => data.clone(),
+    };
+
+    if result.hash != expected {
+        return Err(format!(
+            "hash {} doesn't match the expected {}",
+            result.hash, expected
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a submission URL the way an upstream Anubis server would, using only
+/// what the URL carries: for PoW the hash is recomputed from the submitted
+/// nonce, so a mismatch between the nonce that was hashed and the one that was
+/// sent is caught. Also checks that the URL carries the challenge's `id`, if it
+/// has one.
+pub fn simulate_server_verification(challenge: &AnubisChallenge, submission_url: &str) -> bool {
+    let Ok(params) = parse_submission_url(submission_url) else {
+        return false;
+    };
+    if let Some(id) = &challenge.challenge.id {
+        if params.get("id") != Some(id.as_str()) {
+            return false;
         }
     }
+
+    let data = &challenge.challenge.random_data;
+    match challenge.algorithm() {
+        "preact" => params.get("result") == Some(&hex::encode(Sha256::digest(data.as_bytes()))),
+        "metarefresh" => params.get("challenge") == Some(data.as_str()),
+        _ => {
+            let (Some(response), Some(nonce)) = (params.get("response"), params.get("nonce"))
+            else {
+                return false;
+            };
+            let mut hasher = Sha256::new();
+            hasher.update(data.as_bytes());
+            hasher.update(nonce.as_bytes());
+            let mut hash = hasher.finalize();
+            if challenge.is_double_sha256() {
+                hash = Sha256::digest(hash);
+            }
+            response == hex::encode(hash)
+                && check_difficulty_fast(&hash, challenge.rules.difficulty)
+        }
+    }
+}
+
+/// How many observations a [`DifficultyHistory`] keeps.
+const DIFFICULTY_HISTORY_LEN: usize = 32;
+
+/// Difficulties seen for one site across attempts, oldest first. Servers that
+/// raise the difficulty after repeated failures otherwise just look slow; this
+/// makes the escalation visible and can be serialized into reports.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DifficultyHistory {
+    observed: Vec<usize>,
+}
+
+impl DifficultyHistory {
+    /// Records a difficulty and returns the previous one if this is an increase.
+    /// Only the most recent observations are kept.
+    pub fn record(&mut self, difficulty: usize) -> Option<usize> {
+        let previous = self.observed.last().copied();
+        if self.observed.len() == DIFFICULTY_HISTORY_LEN {
+            self.observed.remove(0);
+        }
+        self.observed.push(difficulty);
+        previous.filter(|&previous| difficulty > previous)
+    }
+
+    pub fn observed(&self) -> &[usize] {
+        &self.observed
+    }
+}
+
+/// Algorithms this build can solve, in the spelling used by `rules.algorithm`.
+/// Anything else fails with an "unsupported algorithm" error.
+pub fn supported_algorithms() -> &'static [&'static str] {
+    &["fast", "slow", "double_sha256", "preact", "metarefresh"]
+}
+
+/// Probability that a PoW search of `difficulty` leading zero nibbles has not
+/// found a solution after `attempts_so_far` hashes: `(1 - 16^-difficulty)^n`.
+///
+/// Every hash is an independent try, so this is also the chance the search
+/// still needs more attempts, however long it has already run. A value far
+/// below 1% means the solve is unlucky rather than broken.
+pub fn remaining_probability(difficulty: usize, attempts_so_far: u64) -> f64 {
+    if attempts_so_far == 0 {
+        return 1.0;
+    }
+    let p = 16f64.powi(-(difficulty.min(i32::MAX as usize) as i32));
+    // `ln_1p` keeps `1 - p` from rounding to 1 at high difficulties.
+    (attempts_so_far as f64 * (-p).ln_1p()).exp()
+}
+
+/// Expected time for a PoW search of `difficulty` at `hashes_per_second`,
+/// from the mean of `16^difficulty` attempts. Saturates at `Duration::MAX`.
+pub fn expected_solve_time(difficulty: usize, hashes_per_second: f64) -> Duration {
+    let attempts = 16f64.powi(difficulty.min(i32::MAX as usize) as i32);
+    Duration::try_from_secs_f64(attempts / hashes_per_second).unwrap_or(Duration::MAX)
+}
+
+/// Single-thread hashrate of the solver's hot loop, measured by hashing for
+/// about `sample`. Multiply by the solver's thread count for a pool.
+pub fn measure_hashrate(sample: Duration) -> f64 {
+    let hasher = Sha256Midstate::new(&[b'0'; 128]);
+    let mut encoder = NonceEncoder::default();
+    let started = Instant::now();
+    let mut hashes = 0u64;
+    while started.elapsed() < sample {
+        for _ in 0..1024 {
+            std::hint::black_box(hasher.hash(encoder.encode(NonceFormat::Decimal, hashes)));
+            hashes += 1;
+        }
+    }
+    hashes as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Solve the challenge based on its algorithm type.
+pub fn solve_challenge<F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+) -> Result<SolverResult, String>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    solve_challenge_with_options(challenge, progress_callback, &SolveOptions::default())
+}
+
+/// Like [`solve_challenge`], with non-default solver options.
+pub fn solve_challenge_with_options<F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+    options: &SolveOptions,
+) -> Result<SolverResult, String>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    match challenge.anubis_algorithm()? {
+        AnubisAlgorithm::Preact => Ok(solve_preact_challenge(challenge)),
+        AnubisAlgorithm::Metarefresh => Ok(solve_metarefresh_challenge(challenge)),
+        _ => solve_challenge_native_with_options(challenge, progress_callback, options),
+    }
+}
+
+/// PoW solver: find nonce where SHA256(randomData + nonce) has `difficulty` leading zero nibbles.
+pub fn solve_challenge_native<F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+) -> Result<SolverResult, String>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    solve_challenge_native_with_options(challenge, progress_callback, &SolveOptions::default())
+}
+
+/// Like [`solve_challenge_native`], with non-default solver options.
+pub fn solve_challenge_native_with_options<F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+    options: &SolveOptions,
+) -> Result<SolverResult, String>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    solve_challenge_native_with_stats(challenge, progress_callback, options).0
+}
+
+/// How one [`search_batch`] went.
+enum Batch {
+    /// No nonce met the difficulty; `nonce` is where to continue.
+    Exhausted,
+    /// `nonce` met the difficulty with `hash`, after `tried` hashes.
+    Found { tried: u64, hash: [u8; 32] },
+    /// The nonce space ran out after `tried` hashes.
+    Overflow { tried: u64 },
+}
+
+/// Hashes up to `limit` nonces from `nonce`, `step` apart, leaving
+/// `nonce` at the solution or the next nonce to try. Generic over the
+/// algorithm so the choice of hash is made once per solve, not per nonce.
+#[inline]
+fn search_batch<const DOUBLE: bool>(
+    hasher: &Sha256Midstate,
+    encoder: &mut NonceEncoder,
+    nonce_format: NonceFormat,
+    difficulty: usize,
+    nonce: &mut u64,
+    step: u64,
+    limit: u64,
+) -> Batch {
+    for tried in 1..=limit {
+        let suffix = encoder.encode(nonce_format, *nonce);
+        let hash = if DOUBLE {
+            hasher.hash_twice(suffix)
+        } else {
+            hasher.hash(suffix)
+        };
+
+        if check_difficulty_fast(&hash, difficulty) {
+            return Batch::Found { tried, hash };
+        }
+
+        match nonce.checked_add(step) {
+            Some(next_nonce) => *nonce = next_nonce,
+            None => return Batch::Overflow { tried },
+        }
+    }
+    Batch::Exhausted
+}
+
+/// Signature of [`search_batch`] and its SIMD counterpart.
+type BatchSearcher =
+    fn(&Sha256Midstate, &mut NonceEncoder, NonceFormat, usize, &mut u64, u64, u64) -> Batch;
+
+/// The batch search for `algorithm` on this CPU, picked once per solve.
+fn batch_searcher(algorithm: AnubisAlgorithm) -> BatchSearcher {
+    let double = algorithm == AnubisAlgorithm::DoubleSha256;
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if simd::preferred() {
+        return if double {
+            simd::search_batch::<true>
+        } else {
+            simd::search_batch::<false>
+        };
+    }
+    if double {
+        search_batch::<true>
+    } else {
+        search_batch::<false>
+    }
+}
+
+/// [`search_batch`] over a [`Hasher`], hashing `fixed` and the nonce from
+/// scratch for every nonce, the nonce first if `NONCE_FIRST`.
+fn search_batch_with<H: Hasher, const DOUBLE: bool, const NONCE_FIRST: bool>(
+    fixed: &[u8],
+    encoder: &mut NonceEncoder,
+    nonce_format: NonceFormat,
+    difficulty: usize,
+    nonce: &mut u64,
+    step: u64,
+    limit: u64,
+) -> Batch {
+    let mut hasher = H::default();
+    for tried in 1..=limit {
+        let encoded = encoder.encode(nonce_format, *nonce);
+        if NONCE_FIRST {
+            hasher.update(encoded);
+            hasher.update(fixed);
+        } else {
+            hasher.update(fixed);
+            hasher.update(encoded);
+        }
+        let mut hash = hasher.finalize_reset();
+        if DOUBLE {
+            hasher.update(&hash);
+            hash = hasher.finalize_reset();
+        }
+
+        if check_difficulty_fast(&hash, difficulty) {
+            return Batch::Found { tried, hash };
+        }
+
+        match nonce.checked_add(step) {
+            Some(next_nonce) => *nonce = next_nonce,
+            None => return Batch::Overflow { tried },
+        }
+    }
+    Batch::Exhausted
+}
+
+/// Signature of [`search_batch_with`].
+type HasherBatchSearcher =
+    fn(&[u8], &mut NonceEncoder, NonceFormat, usize, &mut u64, u64, u64) -> Batch;
+
+/// How solver threads hash, picked once per solve.
+#[derive(Clone, Copy)]
+enum Searcher {
+    Midstate(BatchSearcher),
+    Hasher(HasherBatchSearcher),
+}
+
+/// Hashes from the data's midstate, unless the nonce comes first.
+fn midstate_searcher(algorithm: AnubisAlgorithm, order: ConcatOrder) -> Searcher {
+    match order {
+        ConcatOrder::ChallengeThenNonce => Searcher::Midstate(batch_searcher(algorithm)),
+        ConcatOrder::NonceThenChallenge => hasher_searcher::<Sha2Hasher>(algorithm, order),
+    }
+}
+
+fn hasher_searcher<H: Hasher>(algorithm: AnubisAlgorithm, order: ConcatOrder) -> Searcher {
+    let double = algorithm == AnubisAlgorithm::DoubleSha256;
+    Searcher::Hasher(match (double, order) {
+        (true, ConcatOrder::ChallengeThenNonce) => search_batch_with::<H, true, false>,
+        (false, ConcatOrder::ChallengeThenNonce) => search_batch_with::<H, false, false>,
+        (true, ConcatOrder::NonceThenChallenge) => search_batch_with::<H, true, true>,
+        (false, ConcatOrder::NonceThenChallenge) => search_batch_with::<H, false, true>,
+    })
+}
+
+/// How a PoW search went, successful or not.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SolverStats {
+    /// Nonces hashed across all threads.
+    pub attempts: u64,
+    /// Nonces hashed by each solver thread, by thread index. A thread that did
+    /// far less than the others was likely throttled or descheduled.
+    pub per_thread_attempts: Vec<u64>,
+    /// The closest hash found, with [`SolveOptions::track_best`].
+    pub best_seen: Option<BestSeen>,
+}
+
+/// A hash found while searching and how many leading zero nibbles it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BestSeen {
+    pub leading_zeros: usize,
+    pub nonce: u64,
+}
+
+/// Bits of [`BestSeenCell`] holding the nonce; the leading zero count plus
+/// one goes above them, so comparing packed values compares zero counts
+/// first and 0 means nothing was seen.
+const BEST_NONCE_BITS: u32 = 57;
+
+/// The best hash seen across all threads, packed into one atomic.
+#[derive(Default)]
+struct BestSeenCell(AtomicU64);
+
+impl BestSeenCell {
+    /// Records `nonce` if its hash beats the best so far. A nonce too large
+    /// to pack is skipped; the search starts below 2^32, so it would take
+    /// 2^57 hashes to reach one.
+    fn record(&self, leading_zeros: usize, nonce: u64) {
+        if nonce >> BEST_NONCE_BITS == 0 {
+            let packed = ((leading_zeros as u64 + 1) << BEST_NONCE_BITS) | nonce;
+            self.0.fetch_max(packed, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> Option<BestSeen> {
+        let packed = self.0.load(Ordering::Relaxed);
+        let zeros = (packed >> BEST_NONCE_BITS).checked_sub(1)?;
+        Some(BestSeen {
+            leading_zeros: zeros as usize,
+            nonce: packed & ((1 << BEST_NONCE_BITS) - 1),
+        })
+    }
+}
+
+/// Number of leading zero nibbles of `hash`.
+fn leading_zero_nibbles(hash: &[u8]) -> usize {
+    let zero_bytes = hash.iter().take_while(|&&byte| byte == 0).count();
+    match hash.get(zero_bytes) {
+        Some(byte) if byte >> 4 == 0 => zero_bytes * 2 + 1,
+        _ => zero_bytes * 2,
+    }
+}
+
+/// Progress of a PoW search, passed to the callback of
+/// [`solve_challenge_native_with_progress`] at the start of every batch of
+/// 16K nonces on each thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressUpdate {
+    /// Next nonce of the thread reporting.
+    pub nonce: u64,
+    /// Nonces hashed so far across all threads.
+    pub total_attempts: u64,
+    /// Time since the search started.
+    pub elapsed: Duration,
+    /// Hashrate across all threads over roughly the last
+    /// [`PROGRESS_RATE_WINDOW`], or since the start before that much time
+    /// has passed.
+    pub hashes_per_second: f64,
+    /// The closest hash found so far, with [`SolveOptions::track_best`].
+    pub best_seen: Option<BestSeen>,
+}
+
+/// How far back [`ProgressUpdate::hashes_per_second`] looks, so the rate
+/// follows throttling instead of averaging it away over a long solve.
+pub const PROGRESS_RATE_WINDOW: Duration = Duration::from_millis(250);
+
+/// Attempt count at the start of the current rate window, and the rate
+/// measured over the previous one.
+struct RateWindow {
+    started: Instant,
+    attempts: u64,
+    rate: Option<f64>,
+}
+
+/// Like [`solve_challenge_native_with_options`], hashing with `H` instead of
+/// the built-in `sha2` midstate, e.g. `solve_challenge_native_with_hasher::<
+/// OpensslHasher, fn(u64)>` with the `openssl` feature.
+pub fn solve_challenge_native_with_hasher<H, F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+    options: &SolveOptions,
+) -> Result<SolverResult, String>
+where
+    H: Hasher,
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    let progress_callback =
+        progress_callback.map(|callback| move |update: ProgressUpdate| callback(update.nonce));
+    solve_native(
+        challenge,
+        progress_callback,
+        options,
+        Partition::WHOLE,
+        hasher_searcher::<H>,
+    )
+    .0
+}
+
+/// Like [`solve_challenge_native_with_options`], also returning how many
+/// nonces each thread tried, even when the search fails.
+pub fn solve_challenge_native_with_stats<F>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<F>,
+    options: &SolveOptions,
+) -> (Result<SolverResult, String>, SolverStats)
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    let progress_callback =
+        progress_callback.map(|callback| move |update: ProgressUpdate| callback(update.nonce));
+    solve_native(
+        challenge,
+        progress_callback,
+        options,
+        Partition::WHOLE,
+        midstate_searcher,
+    )
+}
+
+/// Like [`solve_challenge_native_with_options`], with a callback that also
+/// gets the attempt count, elapsed time and current hashrate, e.g. to drive a
+/// progress bar.
+pub fn solve_challenge_native_with_progress<P>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<P>,
+    options: &SolveOptions,
+) -> Result<SolverResult, String>
+where
+    P: Fn(ProgressUpdate) + Send + Sync + 'static,
+{
+    solve_native(
+        challenge,
+        progress_callback,
+        options,
+        Partition::WHOLE,
+        midstate_searcher,
+    )
+    .0
+}
+
+/// Solves a PoW challenge as worker `worker_index` of `worker_count`, e.g. one
+/// of several machines: this one searches nonces `worker_index`,
+/// `worker_index + worker_count`, ... (after `options.start`), split further
+/// between its local threads. Workers never try the same nonce and together
+/// cover every nonce, and a result from any of them is valid for the
+/// challenge.
+///
+/// Every worker must use the same `options.start`; `NonceStart::Random` is
+/// rejected, as each worker would draw its own.
+pub fn solve_challenge_distributed(
+    challenge: &AnubisChallenge,
+    worker_index: usize,
+    worker_count: usize,
+    options: &SolveOptions,
+) -> Result<SolverResult, String> {
+    if worker_index >= worker_count {
+        return Err(format!(
+            "worker index {} out of range for {} workers",
+            worker_index, worker_count
+        ));
+    }
+    if matches!(options.start, NonceStart::Random) {
+        return Err("a random nonce start can't be split between workers".to_string());
+    }
+    let partition = Partition {
+        index: worker_index as u64,
+        count: worker_count as u64,
+    };
+    solve_native::<fn(ProgressUpdate)>(challenge, None, options, partition, midstate_searcher).0
+}
+
+/// The share of the nonce space a solve searches: nonces `index`,
+/// `index + count`, ... from the start offset.
+#[derive(Debug, Clone, Copy)]
+struct Partition {
+    index: u64,
+    count: u64,
+}
+
+impl Partition {
+    const WHOLE: Partition = Partition { index: 0, count: 1 };
+
+    /// First nonce of local thread `thread`, or `None` if it would be past
+    /// the end of the nonce space. Thread `t` takes every `threads`th nonce of
+    /// this partition from its `t`th.
+    fn first_nonce(self, start: u64, thread: u64) -> Option<u64> {
+        thread
+            .checked_mul(self.count)?
+            .checked_add(self.index)?
+            .checked_add(start)
+    }
+
+    /// Distance between the nonces of one of `threads` local threads.
+    fn step(self, threads: u64) -> Option<u64> {
+        self.count.checked_mul(threads)
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "solve_pow",
+        skip_all,
+        fields(
+            algorithm = challenge.algorithm(),
+            difficulty = challenge.rules.difficulty,
+        )
+    )
+)]
+fn solve_native<P>(
+    challenge: &AnubisChallenge,
+    progress_callback: Option<P>,
+    options: &SolveOptions,
+    partition: Partition,
+    searcher: fn(AnubisAlgorithm, ConcatOrder) -> Searcher,
+) -> (Result<SolverResult, String>, SolverStats)
+where
+    P: Fn(ProgressUpdate) + Send + Sync,
+{
+    // Checked before any thread is started.
+    let algorithm = match challenge.pow_algorithm() {
+        Ok(algorithm) => algorithm,
+        Err(e) => return (Err(e), SolverStats::default()),
+    };
+    if let Err(e) = options.validate() {
+        return (Err(e), SolverStats::default());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(pool) = &options.thread_pool {
+        let options = SolveOptions {
+            thread_pool: None,
+            ..options.clone()
+        };
+        return pool
+            .install(|| solve_native(challenge, progress_callback, &options, partition, searcher));
+    }
+
+    let num_threads = solver_threads();
+    let Some(step) = partition.step(num_threads as u64) else {
+        return (
+            Err("too many workers to split the nonce space between".to_string()),
+            SolverStats::default(),
+        );
+    };
+    let difficulty = challenge.rules.difficulty;
+    let nonce_format = options.nonce_format;
+    let data = options
+        .normalization
+        .apply(&challenge.challenge.random_data);
+    let (fixed, encoder) = preimage_parts(&data, options);
+    // The prefix is constant, so its full blocks are compressed once and only
+    // the final block is hashed per nonce; see benches/hasher.rs.
+    let hasher = Sha256Midstate::new(&fixed);
+    let searcher = searcher(algorithm, options.concat_order);
+    let start = options.start.offset();
+
+    let started = Instant::now();
+    let found_solution = Arc::new(AtomicBool::new(false));
+    let deadline = options.max_time.map(|max_time| Instant::now() + max_time);
+    let timed_out = AtomicBool::new(false);
+    let out_of_budget = AtomicBool::new(false);
+    let per_thread_attempts: Vec<AtomicU64> = (0..num_threads).map(|_| AtomicU64::new(0)).collect();
+    let best_seen = BestSeenCell::default();
+    let rate_window = Mutex::new(RateWindow {
+        started,
+        attempts: 0,
+        rate: None,
+    });
+    let report = |nonce: u64| {
+        let Some(callback) = &progress_callback else {
+            return;
+        };
+        let total_attempts: u64 = per_thread_attempts
+            .iter()
+            .map(|attempts| attempts.load(Ordering::Relaxed))
+            .sum();
+        let now = Instant::now();
+        let elapsed = now - started;
+        let hashes_per_second = {
+            let mut window = rate_window.lock().unwrap();
+            let window_elapsed = now - window.started;
+            if window_elapsed >= PROGRESS_RATE_WINDOW {
+                let hashed = total_attempts.saturating_sub(window.attempts);
+                window.rate = Some(hashed as f64 / window_elapsed.as_secs_f64());
+                window.started = now;
+                window.attempts = total_attempts;
+            }
+            window.rate.unwrap_or_else(|| {
+                // A report can come within the clock's resolution of the start.
+                total_attempts as f64 / elapsed.as_secs_f64().max(1e-9)
+            })
+        };
+        callback(ProgressUpdate {
+            nonce,
+            total_attempts,
+            elapsed,
+            hashes_per_second,
+            best_seen: best_seen.get(),
+        });
+    };
+
+    let search = |thread_id: usize| {
+        // Past the end of the nonce space, this thread has nothing to do.
+        let mut nonce = partition.first_nonce(start, thread_id as u64)?;
+        let local_found = found_solution.clone();
+        let attempts = &per_thread_attempts[thread_id];
+        // The budget's remainder goes to the first threads, so the shares
+        // add up to it exactly.
+        let mut budget = options.max_attempts.map(|max| {
+            let threads = num_threads as u64;
+            max / threads + u64::from((thread_id as u64) < max % threads)
+        });
+        #[cfg(not(target_arch = "wasm32"))]
+        if options.pin_threads {
+            let cores = solver_cores();
+            if !cores.is_empty() {
+                core_affinity::set_for_current(cores[thread_id % cores.len()]);
+            }
+        }
+
+        let mut encoder = encoder.clone();
+
+        'search: while !local_found.load(Ordering::Relaxed)
+            && !timed_out.load(Ordering::Relaxed)
+            && !options.cancelled()
+        {
+            // Reported at the start of each batch, so the first nonce
+            // is reported too.
+            report(nonce);
+            let limit = budget.map_or(CHECK_INTERVAL, |left| left.min(CHECK_INTERVAL));
+            if limit == 0 {
+                out_of_budget.store(true, Ordering::Relaxed);
+                break 'search;
+            }
+            // Tracking searches for one nibble past the best so far, so
+            // each improvement comes back as a `Found` below difficulty.
+            let probe = if options.track_best {
+                let best = best_seen.get().map_or(0, |best| best.leading_zeros + 1);
+                best.min(difficulty)
+            } else {
+                difficulty
+            };
+            let batch = match searcher {
+                Searcher::Midstate(run_batch) => run_batch(
+                    &hasher,
+                    &mut encoder,
+                    nonce_format,
+                    probe,
+                    &mut nonce,
+                    step,
+                    limit,
+                ),
+                Searcher::Hasher(run_batch) => run_batch(
+                    &fixed,
+                    &mut encoder,
+                    nonce_format,
+                    probe,
+                    &mut nonce,
+                    step,
+                    limit,
+                ),
+            };
+            match batch {
+                Batch::Exhausted => {}
+                Batch::Found { tried, hash } => {
+                    attempts.fetch_add(tried, Ordering::Relaxed);
+                    if options.track_best {
+                        best_seen.record(leading_zero_nibbles(&hash), nonce);
+                    }
+                    if probe < difficulty && !check_difficulty_fast(&hash, difficulty) {
+                        // A near miss: carry on from the next nonce.
+                        if let Some(left) = &mut budget {
+                            *left -= tried;
+                        }
+                        match nonce.checked_add(step) {
+                            Some(next) => nonce = next,
+                            None => break 'search,
+                        }
+                        continue 'search;
+                    }
+                    // Several threads can hit a solution at once. Only the
+                    // one that flips the flag returns, and it returns the
+                    // nonce and hash it computed itself, so the pair always
+                    // matches.
+                    if !local_found.swap(true, Ordering::SeqCst) {
+                        return Some(SolverResult {
+                            hash: hex::encode(hash),
+                            data: data.clone(),
+                            difficulty,
+                            nonce: Some(nonce),
+                            nonce_format,
+                            concat_order: options.concat_order,
+                            salt: options.salt.clone(),
+                            algorithm,
+                            total_attempts: 0,
+                            hashes_per_second: 0.0,
+                        });
+                    } else {
+                        return None;
+                    }
+                }
+                Batch::Overflow { tried } => {
+                    attempts.fetch_add(tried, Ordering::Relaxed);
+                    break 'search;
+                }
+            }
+
+            attempts.fetch_add(limit, Ordering::Relaxed);
+            if let Some(left) = &mut budget {
+                *left -= limit;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                timed_out.store(true, Ordering::Relaxed);
+            }
+        }
+        None
+    };
+    #[cfg(not(target_arch = "wasm32"))]
+    let result = (0..num_threads)
+        .into_par_iter()
+        .map(search)
+        .find_any(|res| res.is_some())
+        .flatten();
+    #[cfg(target_arch = "wasm32")]
+    let result = search(0);
+
+    let per_thread_attempts: Vec<u64> = per_thread_attempts
+        .into_iter()
+        .map(AtomicU64::into_inner)
+        .collect();
+    let stats = SolverStats {
+        attempts: per_thread_attempts.iter().sum(),
+        per_thread_attempts,
+        best_seen: best_seen.get(),
+    };
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        attempts = stats.attempts,
+        nonce = result.as_ref().and_then(|res| res.nonce),
+        timed_out = timed_out.load(Ordering::Relaxed),
+        "PoW search finished"
+    );
+
+    let result = match result {
+        Some(mut res) => {
+            res.total_attempts = stats.attempts;
+            // A solve can finish within the clock's resolution.
+            let elapsed = started.elapsed().as_secs_f64().max(1e-9);
+            res.hashes_per_second = stats.attempts as f64 / elapsed;
+            Ok(res)
+        }
+        None => {
+            if options.cancelled() {
+                Err("cancelled".to_string())
+            } else if timed_out.load(Ordering::Relaxed) {
+                Err(format!(
+                    "Time limit of {:?} exceeded after {} attempts",
+                    options.max_time.unwrap_or_default(),
+                    stats.attempts
+                ))
+            } else if out_of_budget.load(Ordering::Relaxed) {
+                Err(format!(
+                    "attempt budget exhausted after {} hashes",
+                    stats.attempts
+                ))
+            } else {
+                Err("Solver finished without finding a solution.".to_string())
+            }
+        }
+    };
+    (result, stats)
+}
+
+/// Checks an explicit list of nonces instead of searching, e.g. to replay a
+/// nonce a server rejected or one captured from a browser. Returns the first
+/// candidate that meets the difficulty, or `None` if none do or the challenge
+/// isn't PoW.
+pub fn check_candidates(challenge: &AnubisChallenge, candidates: &[u64]) -> Option<SolverResult> {
+    check_candidates_with_options(challenge, candidates, &SolveOptions::default())
+}
+
+/// Like [`check_candidates`], with the nonce format and normalization of
+/// `options`.
+pub fn check_candidates_with_options(
+    challenge: &AnubisChallenge,
+    candidates: &[u64],
+    options: &SolveOptions,
+) -> Option<SolverResult> {
+    let mut searcher = NonceSearcher::new(challenge, options).ok()?;
+    candidates.iter().find_map(|&nonce| searcher.check(nonce))
+}
+
+/// Searches nonces `start_nonce`, `start_nonce + step`, ... on the calling
+/// thread with upstream's nonce format, until one solves the challenge, the
+/// nonce space runs out, or `deadline` passes (checked every 16K nonces).
+///
+/// Nothing here uses rayon, so callers that run their own workers can split
+/// the nonce space between them, e.g. Web Workers from a WASM build giving
+/// each worker a different `start_nonce` and `step` set to the worker count.
+/// The clock is only read for a deadline, so without the `wasm` feature on
+/// wasm32, where `std::time::Instant` panics, pass `None` and stop the worker
+/// from outside instead; `hashes_per_second` is left at 0.
+pub fn solve_challenge_single_range(
+    challenge: &AnubisChallenge,
+    start_nonce: u64,
+    step: u64,
+    deadline: Option<Instant>,
+) -> Result<SolverResult, String> {
+    let algorithm = challenge.pow_algorithm()?;
+    if step == 0 {
+        return Err("step must be at least 1".to_string());
+    }
+    let data = &challenge.challenge.random_data;
+    let difficulty = challenge.rules.difficulty;
+    let nonce_format = NonceFormat::default();
+    let hasher = Sha256Midstate::new(data.as_bytes());
+    let run_batch = batch_searcher(algorithm);
+    let mut encoder = NonceEncoder::default();
+    let mut nonce = start_nonce;
+    let mut attempts = 0u64;
+
+    loop {
+        match run_batch(
+            &hasher,
+            &mut encoder,
+            nonce_format,
+            difficulty,
+            &mut nonce,
+            step,
+            CHECK_INTERVAL,
+        ) {
+            Batch::Exhausted => attempts += CHECK_INTERVAL,
+            Batch::Found { tried, hash } => {
+                return Ok(SolverResult {
+                    hash: hex::encode(hash),
+                    data: data.clone(),
+                    difficulty,
+                    nonce: Some(nonce),
+                    nonce_format,
+                    concat_order: ConcatOrder::default(),
+                    salt: None,
+                    algorithm,
+                    total_attempts: attempts + tried,
+                    hashes_per_second: 0.0,
+                });
+            }
+            Batch::Overflow { .. } => {
+                return Err("Solver finished without finding a solution.".to_string())
+            }
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(format!("Deadline passed after {} attempts", attempts));
+        }
+    }
+}
+
+/// Finds the smallest nonce solving a PoW challenge by trying 0, 1, 2, ... on
+/// the calling thread, so the answer for a challenge never changes.
+///
+/// Meant for tests that assert on a specific nonce and for low difficulties:
+/// the parallel solvers return whichever thread's nonce wins the race, and
+/// this is as many times slower than them as there are cores. It runs on
+/// [`NonceSearcher`], the same hashing as the parallel path.
+pub fn solve_challenge_sequential(challenge: &AnubisChallenge) -> Result<SolverResult, String> {
+    let mut searcher = NonceSearcher::new(challenge, &SolveOptions::default())?;
+    loop {
+        match searcher.next_chunk(CHECK_INTERVAL) {
+            SearchStep::Found(result) => return Ok(result),
+            SearchStep::Progress(_) => {}
+            SearchStep::Exhausted => {
+                return Err("Solver finished without finding a solution.".to_string())
+            }
+        }
+    }
+}
+
+/// Finds `k` distinct solutions to a PoW challenge, e.g. to test how a server
+/// handles a reused nonce.
+///
+/// Each solution costs about as much as a single solve, so this takes roughly
+/// `k` times as long as [`solve_challenge_native_with_options`]. If
+/// `options.max_time` runs out first, the solutions found so far are returned;
+/// if `options.cancel` is cancelled, none are. Results are ordered by nonce.
+pub fn solve_challenge_many(
+    challenge: &AnubisChallenge,
+    k: usize,
+    options: &SolveOptions,
+) -> Result<Vec<SolverResult>, String> {
+    let algorithm = challenge.anubis_algorithm()?;
+    if !algorithm.is_pow() {
+        return Err(format!(
+            "{} challenges have a single answer",
+            algorithm.as_str()
+        ));
+    }
+    options.validate()?;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(pool) = &options.thread_pool {
+        let options = SolveOptions {
+            thread_pool: None,
+            ..options.clone()
+        };
+        return pool.install(|| solve_challenge_many(challenge, k, &options));
+    }
+
+    let num_threads = solver_threads();
+    let difficulty = challenge.rules.difficulty;
+    let nonce_format = options.nonce_format;
+    let data = options
+        .normalization
+        .apply(&challenge.challenge.random_data);
+    let (fixed, encoder) = preimage_parts(&data, options);
+    let hasher = Sha256Midstate::new(&fixed);
+    let double = algorithm == AnubisAlgorithm::DoubleSha256;
+    let start = options.start.offset();
+
+    let deadline = options.max_time.map(|max_time| Instant::now() + max_time);
+    let done = AtomicBool::new(k == 0);
+    let results = Mutex::new(Vec::with_capacity(k));
+
+    let search = |thread_id: usize| {
+        // Threads stride through disjoint nonces, so no two find the same one.
+        let mut nonce = start + thread_id as u64;
+        let mut encoder = encoder.clone();
+        let mut since_check = 0u64;
+
+        while !done.load(Ordering::Relaxed) {
+            let suffix = encoder.encode(nonce_format, nonce);
+            let hash_result = match options.concat_order {
+                ConcatOrder::NonceThenChallenge => hash_nonce_first(suffix, &fixed, double),
+                ConcatOrder::ChallengeThenNonce if double => hasher.hash_twice(suffix),
+                ConcatOrder::ChallengeThenNonce => hasher.hash(suffix),
+            };
+
+            if check_difficulty_fast(&hash_result, difficulty) {
+                let mut results = results.lock().unwrap();
+                if results.len() < k {
+                    results.push(SolverResult {
+                        hash: hex::encode(hash_result),
+                        data: data.clone(),
+                        difficulty,
+                        nonce: Some(nonce),
+                        nonce_format,
+                        concat_order: options.concat_order,
+                        salt: options.salt.clone(),
+                        algorithm,
+                        total_attempts: 0,
+                        hashes_per_second: 0.0,
+                    });
+                }
+                if results.len() == k {
+                    done.store(true, Ordering::Relaxed);
+                }
+            }
+
+            since_check += 1;
+            if since_check == CHECK_INTERVAL {
+                since_check = 0;
+                if options.cancelled()
+                    || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    done.store(true, Ordering::Relaxed);
+                }
+            }
+
+            match nonce.checked_add(num_threads as u64) {
+                Some(next_nonce) => nonce = next_nonce,
+                None => break,
+            }
+        }
+    };
+    #[cfg(not(target_arch = "wasm32"))]
+    (0..num_threads).into_par_iter().for_each(search);
+    #[cfg(target_arch = "wasm32")]
+    search(0);
+
+    if options.cancelled() {
+        return Err("cancelled".to_string());
+    }
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|result| result.nonce);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_old_format_without_extra_fields() {
+        let challenge: AnubisChallenge =
+            serde_json::from_str(r#"{"challenge":"abc123","rules":{"difficulty":4}}"#).unwrap();
+        assert_eq!(challenge.challenge.random_data, "abc123");
+        assert_eq!(challenge.challenge.id, None);
+        assert_eq!(challenge.rules.difficulty, 4);
+        assert_eq!(challenge.algorithm(), "fast");
+        assert!(challenge.unrecognized_fields().is_empty());
+    }
+
+    #[test]
+    fn parses_new_format_and_keeps_extra_fields() {
+        let json = r#"{
+            "challenge": {"id": "x1", "randomData": "abc123", "issuedAt": "now"},
+            "rules": {"difficulty": 5, "algorithm": "slow", "report_as": 4},
+            "spent": false
+        }"#;
+        let challenge: AnubisChallenge = serde_json::from_str(json).unwrap();
+        assert_eq!(challenge.challenge.id.as_deref(), Some("x1"));
+        assert_eq!(challenge.challenge.random_data, "abc123");
+        assert_eq!(challenge.rules.difficulty, 5);
+        assert_eq!(challenge.algorithm(), "slow");
+
+        let fields = challenge.unrecognized_fields();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields["challenge.issuedAt"], "now");
+        assert_eq!(fields["rules.report_as"], 4);
+        assert_eq!(fields["spent"], false);
+    }
+
+    fn challenge_with_algorithm(algorithm: &str) -> AnubisChallenge {
+        let json = format!(
+            r#"{{"challenge":{{"id":"x1","randomData":"abc123"}},"rules":{{"difficulty":1,"algorithm":"{}"}}}}"#,
+            algorithm
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn result_with_hash(hash: &str) -> SolverResult {
+        SolverResult {
+            hash: hash.to_string(),
+            data: "abc123".to_string(),
+            difficulty: 1,
+            nonce: Some(7),
+            nonce_format: NonceFormat::Decimal,
+            concat_order: ConcatOrder::default(),
+            salt: None,
+            algorithm: AnubisAlgorithm::Fast,
+            total_attempts: 0,
+            hashes_per_second: 0.0,
+        }
+    }
+
+    fn submission_url(challenge: &AnubisChallenge, hash: &str) -> Result<String, String> {
+        build_submission_url(
+            "https",
+            "example.com",
+            challenge,
+            &result_with_hash(hash),
+            "https://example.com/",
+            10,
+        )
+    }
+
+    #[test]
+    fn submission_accepts_sha256_length_hash_for_sha256_algorithms() {
+        let hash = "0".repeat(64);
+        for algorithm in ["fast", "slow", "preact", ""] {
+            let challenge = challenge_with_algorithm(algorithm);
+            assert!(submission_url(&challenge, &hash).is_ok(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn submission_rejects_wrong_length_hash_for_sha256_algorithms() {
+        for algorithm in ["fast", "slow", "preact"] {
+            let challenge = challenge_with_algorithm(algorithm);
+            for hash in [
+                "0".repeat(63),
+                "0".repeat(65),
+                "0".repeat(128),
+                String::new(),
+            ] {
+                assert!(submission_url(&challenge, &hash).is_err(), "{}", algorithm);
+            }
+        }
+    }
+
+    #[test]
+    fn metarefresh_submission_has_no_length_requirement() {
+        let challenge = challenge_with_algorithm("metarefresh");
+        assert_eq!(challenge.digest_hex_len(), Ok(None));
+        assert!(submission_url(&challenge, "abc123").is_ok());
+        assert!(submission_url(&challenge, &"0".repeat(64)).is_ok());
+    }
+
+    #[test]
+    fn solver_dispatches_on_algorithm_and_records_it() {
+        for (name, algorithm) in [
+            ("fast", AnubisAlgorithm::Fast),
+            ("slow", AnubisAlgorithm::Slow),
+            ("sha256d", AnubisAlgorithm::DoubleSha256),
+            ("preact", AnubisAlgorithm::Preact),
+            ("metarefresh", AnubisAlgorithm::Metarefresh),
+        ] {
+            let challenge = challenge_with_algorithm(name);
+            let result = solve_challenge::<fn(u64)>(&challenge, None).unwrap();
+            assert_eq!(result.algorithm, algorithm, "{}", name);
+            verify_solution_with_options(&challenge, &result, &SolveOptions::default()).unwrap();
+        }
+        assert_eq!(
+            serde_json::to_value(AnubisAlgorithm::DoubleSha256).unwrap(),
+            "double_sha256"
+        );
+    }
+
+    #[test]
+    fn results_report_attempts_and_hashrate() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let (result, stats) = solve_challenge_native_with_stats::<fn(u64)>(
+            &challenge,
+            None,
+            &SolveOptions::default(),
+        );
+        let result = result.unwrap();
+        assert!(result.total_attempts > 0);
+        assert_eq!(result.total_attempts, stats.attempts);
+        assert!(result.hashes_per_second.is_finite());
+        assert!(result.hashes_per_second > 0.0);
+    }
+
+    #[test]
+    fn attempt_budget_stops_the_search() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+        // Three threads split 1000 into 334 + 333 + 333.
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(3)
+                .build()
+                .unwrap(),
+        );
+        for (max_attempts, thread_pool) in [(1000, None), (100_000, None), (1000, Some(pool))] {
+            let options = SolveOptions {
+                max_attempts: Some(max_attempts),
+                thread_pool,
+                ..SolveOptions::default()
+            };
+            let (result, stats) =
+                solve_challenge_native_with_stats::<fn(u64)>(&challenge, None, &options);
+            assert_eq!(
+                result.unwrap_err(),
+                format!("attempt budget exhausted after {} hashes", max_attempts)
+            );
+            assert_eq!(stats.attempts, max_attempts);
+        }
+
+        // A budget that is large enough doesn't get in the way.
+        challenge.rules.difficulty = 1;
+        let options = SolveOptions {
+            max_attempts: Some(1 << 20),
+            ..SolveOptions::default()
+        };
+        assert!(solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).is_ok());
+    }
+
+    #[test]
+    fn progress_updates_count_attempts_across_threads() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            max_attempts: Some(200_000),
+            ..SolveOptions::default()
+        };
+        let updates = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = updates.clone();
+        let callback = move |update: ProgressUpdate| seen.lock().unwrap().push(update);
+        assert!(
+            solve_challenge_native_with_progress(&challenge, Some(callback), &options).is_err()
+        );
+
+        let updates = updates.lock().unwrap();
+        // Each thread reports before each of its 7 batches and once more
+        // when its 100K share runs out.
+        assert_eq!(updates.len(), 16);
+        let most = updates.iter().map(|u| u.total_attempts).max().unwrap();
+        assert!((100_000..=200_000).contains(&most), "{}", most);
+        for update in updates.iter() {
+            assert!(update.hashes_per_second.is_finite());
+            assert_eq!(update.total_attempts > 0, update.hashes_per_second > 0.0);
+        }
+    }
+
+    #[test]
+    fn best_seen_improves_while_searching() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            max_attempts: Some(200_000),
+            track_best: true,
+            ..SolveOptions::default()
+        };
+        let updates = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = updates.clone();
+        let callback = move |update: ProgressUpdate| seen.lock().unwrap().push(update.best_seen);
+        assert!(
+            solve_challenge_native_with_progress(&challenge, Some(callback), &options).is_err()
+        );
+        let updates = updates.lock().unwrap();
+        let zeros: Vec<usize> = updates
+            .iter()
+            .map(|best| best.map_or(0, |best| best.leading_zeros))
+            .collect();
+        assert!(
+            zeros.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            zeros
+        );
+        assert!(zeros.first() < zeros.last(), "{:?}", zeros);
+
+        let (_, stats) = solve_challenge_native_with_stats::<fn(u64)>(&challenge, None, &options);
+        assert_eq!(stats.attempts, 200_000);
+        let best = stats.best_seen.unwrap();
+        // 200K hashes all but certainly include one with 3 zero nibbles.
+        assert!(best.leading_zeros >= 3, "{:?}", best);
+        let hash = Sha256::digest(format!("abc123{}", best.nonce));
+        assert_eq!(leading_zero_nibbles(&hash), best.leading_zeros);
+
+        // Without tracking, nothing is recorded.
+        let options = SolveOptions {
+            track_best: false,
+            ..options
+        };
+        let (_, stats) = solve_challenge_native_with_stats::<fn(u64)>(&challenge, None, &options);
+        assert_eq!(stats.best_seen, None);
+    }
+
+    #[test]
+    fn bit_difficulty_masks_the_partial_byte() {
+        // One hash per possible count of leading zero bits up to 32, the rest
+        // of each hash all ones.
+        for zeros in 0..=32 {
+            let mut hash = [0xff; 32];
+            hash[..4].copy_from_slice(&u32::MAX.checked_shr(zeros).unwrap_or(0).to_be_bytes());
+            for bits in 0..=32 {
+                assert_eq!(
+                    check_difficulty_bits(&hash, bits),
+                    bits <= zeros as usize,
+                    "{} leading zero bits checked against {}",
+                    zeros,
+                    bits
+                );
+            }
+        }
+
+        // sha256("abc123506") starts 00 00 3a: 18 leading zero bits.
+        let hash = Sha256::digest(b"abc123506");
+        for bits in 0..=32 {
+            assert_eq!(check_difficulty_bits(&hash, bits), bits <= 18, "{}", bits);
+        }
+        assert!(!check_difficulty_bits(&hash[..2], 17));
+        assert!(check_difficulty_bits(&[0; 32], 256));
+        assert!(!check_difficulty_bits(&[0; 32], 257));
+    }
+
+    #[test]
+    fn difficulty_units_agree_on_whole_nibbles() {
+        let hash = Sha256::digest(b"abc123506");
+        for nibbles in 0..=8 {
+            assert_eq!(
+                DifficultyUnit::Nibbles.check(&hash, nibbles),
+                DifficultyUnit::Bits.check(&hash, nibbles * 4)
+            );
+        }
+        assert!(DifficultyUnit::Nibbles.check(&hash, 4));
+        assert!(!DifficultyUnit::Nibbles.check(&hash, 5));
+    }
+
+    #[test]
+    fn single_range_workers_split_the_nonce_space() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let whole = solve_challenge_single_range(&challenge, 0, 1, None).unwrap();
+        assert_eq!(whole.nonce, Some(506));
+        assert_eq!(whole.total_attempts, 507);
+
+        // Two workers on even and odd nonces: 506 is the even one's first hit.
+        let even = solve_challenge_single_range(&challenge, 0, 2, None).unwrap();
+        assert_eq!(even.nonce, Some(506));
+        assert_eq!(even.total_attempts, 254);
+        let odd = solve_challenge_single_range(&challenge, 1, 2, None).unwrap();
+        assert_eq!(odd.nonce.unwrap() % 2, 1);
+        verify_solution_with_options(&challenge, &odd, &SolveOptions::default()).unwrap();
+
+        assert!(solve_challenge_single_range(&challenge, 0, 0, None).is_err());
+        challenge.rules.difficulty = 64;
+        assert_eq!(
+            solve_challenge_single_range(&challenge, 0, 1, Some(Instant::now())).unwrap_err(),
+            "Deadline passed after 16384 attempts"
+        );
+    }
+
+    #[test]
+    fn distributed_workers_cover_the_nonce_space_once() {
+        const N: u64 = 1000;
+        for threads in [1, 2, 3, 4] {
+            let mut seen = vec![0; N as usize];
+            for index in 0..2 {
+                let partition = Partition { index, count: 2 };
+                let step = partition.step(threads).unwrap();
+                for thread in 0..threads {
+                    let first = partition.first_nonce(0, thread).unwrap();
+                    for nonce in (first..N).step_by(step as usize) {
+                        seen[nonce as usize] += 1;
+                    }
+                }
+            }
+            assert!(seen.iter().all(|&count| count == 1), "{} threads", threads);
+        }
+
+        // Threads whose first nonce is past the end search nothing.
+        let partition = Partition {
+            index: 1,
+            count: u64::MAX,
+        };
+        assert_eq!(partition.first_nonce(0, 0), Some(1));
+        assert_eq!(partition.first_nonce(0, 1), None);
+        assert_eq!(partition.step(2), None);
+    }
+
+    #[test]
+    fn distributed_workers_find_valid_nonces_in_their_share() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            ..SolveOptions::default()
+        };
+        // 506 is the smallest solution, so the even worker finds it first.
+        let even = solve_challenge_distributed(&challenge, 0, 2, &options).unwrap();
+        assert_eq!(even.nonce, Some(506));
+        let odd = solve_challenge_distributed(&challenge, 1, 2, &options).unwrap();
+        assert_eq!(odd.nonce.unwrap() % 2, 1);
+        verify_solution_with_options(&challenge, &odd, &options).unwrap();
+
+        assert!(solve_challenge_distributed(&challenge, 2, 2, &options).is_err());
+        let random = SolveOptions {
+            start: NonceStart::Random,
+            ..options
+        };
+        assert!(solve_challenge_distributed(&challenge, 0, 2, &random).is_err());
+    }
+
+    #[test]
+    fn sequential_solver_returns_the_smallest_nonce() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let result = solve_challenge_sequential(&challenge).unwrap();
+        // sha256("abc123506") is the first with three leading zero nibbles.
+        assert_eq!(result.nonce, Some(506));
+        assert_eq!(result.total_attempts, 507);
+        verify_solution_with_options(&challenge, &result, &SolveOptions::default()).unwrap();
+        assert_eq!(
+            solve_challenge_sequential(&challenge).unwrap().hash,
+            result.hash
+        );
+    }
+
+    #[test]
+    fn hasher_solves_match_the_midstate_search() {
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(1)
+                    .build()
+                    .unwrap(),
+            )),
+            ..SolveOptions::default()
+        };
+        for algorithm in ["fast", "double_sha256"] {
+            let mut challenge = challenge_with_algorithm(algorithm);
+            challenge.rules.difficulty = 3;
+            let expected =
+                solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+            let result = solve_challenge_native_with_hasher::<Sha2Hasher, fn(u64)>(
+                &challenge, None, &options,
+            )
+            .unwrap();
+            assert_eq!(result.nonce, expected.nonce, "{}", algorithm);
+            assert_eq!(result.hash, expected.hash, "{}", algorithm);
+            assert_eq!(result.total_attempts, expected.total_attempts);
+
+            #[cfg(feature = "openssl")]
+            {
+                let result = solve_challenge_native_with_hasher::<OpensslHasher, fn(u64)>(
+                    &challenge, None, &options,
+                )
+                .unwrap();
+                assert_eq!(result.hash, expected.hash, "{}", algorithm);
+            }
+        }
+    }
+
+    #[test]
+    fn cancelled_solves_stop_promptly() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+        let cancel = CancelToken::new();
+        let options = SolveOptions {
+            cancel: Some(cancel.clone()),
+            ..SolveOptions::default()
+        };
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let solving = options.clone();
+        let solver = std::thread::spawn(move || {
+            sender
+                .send(solve_challenge_native_with_options::<fn(u64)>(
+                    &challenge, None, &solving,
+                ))
+                .unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        let result = receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(result.unwrap_err(), "cancelled");
+        solver.join().unwrap();
+
+        let challenge = challenge_with_algorithm("fast");
+        assert_eq!(
+            solve_challenge_many(&challenge, 1, &options).unwrap_err(),
+            "cancelled"
+        );
+    }
+
+    #[test]
+    fn unsupported_algorithms_fail_before_solving() {
+        let mut challenge = challenge_with_algorithm("blake3");
+        // Unsolvable if it were searched, so only failing fast returns.
+        challenge.rules.difficulty = 64;
+        assert_eq!(
+            solve_challenge::<fn(u64)>(&challenge, None).unwrap_err(),
+            "unsupported algorithm: blake3"
+        );
+        let (result, stats) = solve_challenge_native_with_stats::<fn(u64)>(
+            &challenge,
+            None,
+            &SolveOptions::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(stats.attempts, 0);
+        assert!(
+            solve_challenge_native::<fn(u64)>(&challenge_with_algorithm("preact"), None).is_err()
+        );
+        assert!(NonceSearcher::new(&challenge, &SolveOptions::default()).is_err());
+    }
+
+    #[test]
+    fn submission_rejects_unknown_algorithm() {
+        let challenge = challenge_with_algorithm("blake3");
+        assert!(challenge.digest_hex_len().is_err());
+        assert!(submission_url(&challenge, &"0".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn parses_concat_orders() {
+        for order in [
+            ConcatOrder::ChallengeThenNonce,
+            ConcatOrder::NonceThenChallenge,
+        ] {
+            assert_eq!(order.to_string().parse(), Ok(order));
+        }
+        assert!("nonce_first".parse::<ConcatOrder>().is_err());
+    }
+
+    #[test]
+    fn parses_nonce_formats() {
+        assert_eq!("decimal".parse(), Ok(NonceFormat::Decimal));
+        assert_eq!("hex".parse(), Ok(NonceFormat::Hex));
+        assert_eq!("padded:8".parse(), Ok(NonceFormat::Padded(8)));
+        assert_eq!("padded:20".parse(), Ok(NonceFormat::Padded(20)));
+        assert!("padded:21".parse::<NonceFormat>().is_err());
+        assert!("padded:99999999999999".parse::<NonceFormat>().is_err());
+        assert!("padded:".parse::<NonceFormat>().is_err());
+        assert!("octal".parse::<NonceFormat>().is_err());
+        for format in [
+            NonceFormat::Decimal,
+            NonceFormat::Hex,
+            NonceFormat::Padded(8),
+        ] {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+        for format in [
+            AnswerFormat::Hash,
+            AnswerFormat::HashAndNonce,
+            AnswerFormat::Preimage,
+        ] {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+    }
+
+    #[test]
+    fn every_nonce_format_round_trips_through_verification() {
+        let options = SolveOptions::default();
+        for nonce_format in [
+            NonceFormat::Decimal,
+            NonceFormat::Hex,
+            NonceFormat::Padded(8),
+            NonceFormat::Separated(':'),
+            NonceFormat::Separated('é'),
+        ] {
+            let mut challenge = challenge_with_algorithm("fast");
+            challenge.rules.difficulty = 2;
+            let options = SolveOptions {
+                nonce_format,
+                ..options.clone()
+            };
+            let result =
+                solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+            let suffix = result.preimage_suffix().unwrap();
+            assert_eq!(
+                result.hash,
+                hex::encode(Sha256::digest(format!("{}{}", result.data, suffix))),
+                "{}",
+                nonce_format
+            );
+            assert_eq!(
+                verify_solution_with_options(&challenge, &result, &options),
+                Ok(())
+            );
+
+            let url =
+                build_submission_url("https", "example.com", &challenge, &result, "/", 10).unwrap();
+            let parsed = parse_submission_url(&url).unwrap();
+            assert_eq!(parsed.get("nonce"), result.nonce_string().as_deref());
+        }
+
+        let separated = NonceFormat::Separated(':');
+        assert_eq!(separated.preimage_suffix(42), ":42");
+        assert_eq!(separated.format(42), "42");
+        assert_eq!("separator::".parse(), Ok(separated));
+        assert!("separator:".parse::<NonceFormat>().is_err());
+        assert!("separator:ab".parse::<NonceFormat>().is_err());
+    }
+
+    #[test]
+    fn formats_nonces() {
+        assert_eq!(NonceFormat::Decimal.format(42), "42");
+        assert_eq!(NonceFormat::Hex.format(0), "0");
+        assert_eq!(NonceFormat::Hex.format(255), "ff");
+        assert_eq!(NonceFormat::Hex.format(u64::MAX), "ffffffffffffffff");
+        assert_eq!(NonceFormat::Padded(8).format(42), "00000042");
+        assert_eq!(NonceFormat::Padded(2).format(12345), "12345");
+        assert_eq!(
+            NonceFormat::Padded(MAX_PADDED_WIDTH).format(u64::MAX),
+            u64::MAX.to_string()
+        );
+    }
+
+    #[test]
+    fn solver_rejects_oversized_padding() {
+        let challenge = challenge_with_algorithm("fast");
+        let options = SolveOptions {
+            nonce_format: NonceFormat::Padded(MAX_PADDED_WIDTH + 1),
+            ..SolveOptions::default()
+        };
+        assert!(
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).is_err()
+        );
+    }
+
+    /// Rebuilds the preimage the way the server does, from the submitted
+    /// `nonce` parameter, and checks it hashes to the submitted response.
+    fn submission_for(challenge: &AnubisChallenge, result: &SolverResult) -> String {
+        build_submission_url(
+            "https",
+            "example.com",
+            challenge,
+            result,
+            "https://example.com/",
+            10,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn submitted_nonce_rebuilds_hashed_preimage_for_each_format() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        for nonce_format in [
+            NonceFormat::Decimal,
+            NonceFormat::Hex,
+            NonceFormat::Padded(12),
+        ] {
+            let options = SolveOptions {
+                nonce_format,
+                ..SolveOptions::default()
+            };
+            let result =
+                solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+            assert_eq!(result.nonce_format, nonce_format);
+            assert!(simulate_server_verification(
+                &challenge,
+                &submission_for(&challenge, &result)
+            ));
+        }
+    }
+
+    #[test]
+    fn compare_submissions_reports_each_kind_of_difference() {
+        let theirs = "https://example.com/.within.website/x/cmd/anubis/api/pass-challenge?response=ab&nonce=7&redir=%2Fpage&elapsedTime=1200&id=c1";
+        let ours = "https://example.com/.within.website/x/cmd/anubis/api/pass-challenge?response=ab&nonce=7&redir=/page&elapsedTime=1.2&extra=1";
+        assert_eq!(
+            compare_submissions(ours, theirs).unwrap(),
+            [
+                SubmissionMismatch::Encoding {
+                    name: "redir".to_string(),
+                    ours: "/page".to_string(),
+                    theirs: "%2Fpage".to_string(),
+                },
+                SubmissionMismatch::Value {
+                    name: "elapsedTime".to_string(),
+                    ours: "1.2".to_string(),
+                    theirs: "1200".to_string(),
+                },
+                SubmissionMismatch::Missing {
+                    name: "id".to_string(),
+                    theirs: "c1".to_string(),
+                },
+                SubmissionMismatch::Unexpected {
+                    name: "extra".to_string(),
+                    ours: "1".to_string(),
+                },
+            ]
+        );
+
+        assert!(compare_submissions(theirs, theirs).unwrap().is_empty());
+        assert_eq!(
+            compare_submissions(
+                "https://example.com/api/pass?",
+                "https://example.com/other?"
+            )
+            .unwrap(),
+            [SubmissionMismatch::Endpoint {
+                ours: "https://example.com/api/pass".to_string(),
+                theirs: "https://example.com/other".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn simulated_server_accepts_every_supported_algorithm() {
+        for &algorithm in supported_algorithms() {
+            let mut challenge = challenge_with_algorithm(algorithm);
+            challenge.rules.difficulty = 2;
+            challenge.challenge.id = Some("c1".to_string());
+            let result = solve_challenge::<fn(u64)>(&challenge, None).unwrap();
+            assert!(
+                simulate_server_verification(&challenge, &submission_for(&challenge, &result)),
+                "{}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn simulated_server_rejects_mismatched_submissions() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 2;
+        let result = solve_challenge::<fn(u64)>(&challenge, None).unwrap();
+
+        // The hash is right, but a different nonce is sent with it.
+        let wrong_nonce = SolverResult {
+            nonce: result.nonce.map(|nonce| nonce + 1),
+            ..result.clone()
+        };
+        assert!(!simulate_server_verification(
+            &challenge,
+            &submission_for(&challenge, &wrong_nonce)
+        ));
+
+        let mut other_id = challenge.clone();
+        other_id.challenge.id = Some("c2".to_string());
+        assert!(!simulate_server_verification(
+            &other_id,
+            &submission_for(&challenge, &result)
+        ));
+        assert!(!simulate_server_verification(&challenge, "not a url"));
+    }
+
+    #[test]
+    fn detects_duplicate_submission_rejections() {
+        for body in [
+            "Challenge already used",
+            "error: this nonce has ALREADY BEEN USED",
+            "<p>challenge already solved, please reload</p>",
+            "{\"error\":\"challenge already spent\"}",
+        ] {
+            assert!(is_duplicate_submission(body), "{}", body);
+        }
+    }
+
+    #[test]
+    fn ignores_other_submission_rejections() {
+        for body in [
+            "",
+            "invalid response",
+            "Oh noes! wrong nonce",
+            "<html>Making sure you're not a bot!</html>",
+            "used already, maybe",
+        ] {
+            assert!(!is_duplicate_submission(body), "{}", body);
+        }
+    }
+
+    #[test]
+    fn nonce_searcher_finds_same_nonce_as_single_threaded_native_solver() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+
+        let mut searcher = NonceSearcher::new(&challenge, &SolveOptions::default()).unwrap();
+        let found = loop {
+            match searcher.next_chunk(256) {
+                SearchStep::Found(result) => break result,
+                SearchStep::Progress(_) => continue,
+                SearchStep::Exhausted => panic!("search space exhausted"),
+            }
+        };
+
+        // With one worker the native solver also walks nonces from 0 upwards.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let native = pool
+            .install(|| solve_challenge_native::<fn(u64)>(&challenge, None))
+            .unwrap();
+
+        assert_eq!(found.nonce, native.nonce);
+        assert_eq!(found.hash, native.hash);
+        assert_eq!(searcher.attempts(), found.nonce.unwrap() + 1);
+        assert!(simulate_server_verification(
+            &challenge,
+            &submission_for(&challenge, &found)
+        ));
+    }
+
+    #[test]
+    fn nonce_searcher_progress_accumulates_across_chunks() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+
+        let mut searcher = NonceSearcher::new(&challenge, &SolveOptions::default()).unwrap();
+        assert!(matches!(searcher.next_chunk(0), SearchStep::Progress(0)));
+        assert!(matches!(searcher.next_chunk(10), SearchStep::Progress(10)));
+        assert!(matches!(searcher.next_chunk(0), SearchStep::Progress(10)));
+        assert!(matches!(searcher.next_chunk(25), SearchStep::Progress(35)));
+        assert_eq!(searcher.attempts(), 35);
+    }
+
+    #[test]
+    fn nonce_searcher_rejects_non_pow_algorithms() {
+        for algorithm in ["preact", "metarefresh"] {
+            let challenge = challenge_with_algorithm(algorithm);
+            assert!(NonceSearcher::new(&challenge, &SolveOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn normalization_trims_before_stripping_quotes_then_lowercases() {
+        let normalization: ChallengeNormalization = "lowercase,quotes,trim".parse().unwrap();
+        assert_eq!(normalization.apply("  \"AbC\"  "), "abc");
+        assert_eq!(normalization.apply("  'AbC'\n"), "abc");
+
+        // Without trim the quotes are not at the edges, so they stay.
+        let quotes_only: ChallengeNormalization = "quotes".parse().unwrap();
+        assert_eq!(quotes_only.apply("  \"AbC\"  "), "  \"AbC\"  ");
+    }
+
+    #[test]
+    fn normalization_leaves_unmatched_quotes_alone() {
+        let normalization: ChallengeNormalization = "quotes".parse().unwrap();
+        assert_eq!(normalization.apply("\""), "\"");
+        assert_eq!(normalization.apply("\"abc'"), "\"abc'");
+        assert_eq!(normalization.apply("\"\""), "");
+    }
+
+    #[test]
+    fn normalization_parse_rejects_unknown_steps() {
+        assert!("trim,upper".parse::<ChallengeNormalization>().is_err());
+    }
+
+    #[test]
+    fn empty_normalization_list_is_a_no_op() {
+        let normalization: ChallengeNormalization = "".parse().unwrap();
+        assert_eq!(normalization, ChallengeNormalization::default());
+        assert_eq!(normalization.apply("  \"AbC\"  "), "  \"AbC\"  ");
+    }
+
+    #[test]
+    fn difficulty_history_reports_increases_only() {
+        let mut history = DifficultyHistory::default();
+        assert_eq!(history.record(4), None);
+        assert_eq!(history.record(4), None);
+        assert_eq!(history.record(6), Some(4));
+        assert_eq!(history.record(5), None);
+        assert_eq!(history.observed(), &[4, 4, 6, 5]);
+        assert_eq!(serde_json::to_string(&history).unwrap(), "[4,4,6,5]");
+    }
+
+    #[test]
+    fn difficulty_history_keeps_recent_observations() {
+        let mut history = DifficultyHistory::default();
+        for difficulty in 0..DIFFICULTY_HISTORY_LEN + 3 {
+            history.record(difficulty);
+        }
+        assert_eq!(history.observed().len(), DIFFICULTY_HISTORY_LEN);
+        assert_eq!(history.observed()[0], 3);
+    }
+
+    #[test]
+    fn explicit_algorithm_wins_over_configured_default() {
+        let mut missing = challenge_with_algorithm("");
+        assert_eq!(missing.algorithm(), DEFAULT_ALGORITHM);
+        missing.set_default_algorithm("slow");
+        assert_eq!(missing.algorithm(), "slow");
+
+        let mut explicit = challenge_with_algorithm("preact");
+        explicit.set_default_algorithm("slow");
+        assert_eq!(explicit.algorithm(), "preact");
+    }
+
+    fn page_with_challenge(script: &str) -> String {
+        format!(
+            r#"<html><body><script id="anubis_challenge" type="application/json">{}</script></body></html>"#,
+            script
+        )
+    }
+
+    #[test]
+    fn parses_inline_challenge_format() {
+        for script in [
+            r#"difficulty:5|data:abc123"#,
+            r#""difficulty:5|data:abc123""#,
+            r#"{"challenge":"difficulty:5|data:abc123","rules":{"algorithm":"slow"}}"#,
+        ] {
+            let parsed = try_parse_challenge_from_html(&page_with_challenge(script))
+                .unwrap()
+                .unwrap();
+            assert_eq!(parsed.format, ChallengeFormat::Inline, "{}", script);
+            assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+            assert_eq!(parsed.challenge.rules.difficulty, 5);
+        }
+
+        let parsed = parse_challenge_from_html(&page_with_challenge(
+            r#"{"challenge":"difficulty:5|data:abc123","rules":{"algorithm":"slow"}}"#,
+        ))
+        .unwrap();
+        assert_eq!(parsed.challenge.algorithm(), "slow");
+        let parsed = parse_challenge_from_html(&page_with_challenge(
+            r#"{"challenge":"abc123","rules":{"difficulty":4}}"#,
+        ))
+        .unwrap();
+        assert_eq!(parsed.format, ChallengeFormat::Json);
+    }
+
+    #[test]
+    fn parses_challenge_from_data_attribute() {
+        let html = r#"<html><body><div id="pow" data-anubis-challenge='{"challenge":"abc123","rules":{"difficulty":4}}'></div></body></html>"#;
+        let parsed = try_parse_challenge_from_html(html).unwrap().unwrap();
+        assert_eq!(
+            parsed.source,
+            ChallengeSource::Attribute("data-anubis-challenge")
+        );
+        assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+        assert_eq!(parsed.challenge.rules.difficulty, 4);
+
+        let html = r#"<div data-challenge="difficulty:3|data:xyz"></div>"#;
+        let parsed = try_parse_challenge_from_html(html).unwrap().unwrap();
+        assert_eq!(parsed.source, ChallengeSource::Attribute("data-challenge"));
+        assert_eq!(parsed.format, ChallengeFormat::Inline);
+    }
+
+    #[test]
+    fn parses_challenge_from_json_ld() {
+        let html = r#"<html><head><script type="application/ld+json">{"@context":"https://schema.org","@type":"WebPage","name":"Checking"}</script>
+            <script type="application/ld+json">{"@context":"https://schema.org","@type":"WebPage","anubisChallenge":{"challenge":{"id":"c1","randomData":"abc123"},"rules":{"difficulty":4,"algorithm":"fast"}}}</script></head></html>"#;
+        let parsed = try_parse_challenge_from_html(html).unwrap().unwrap();
+        assert_eq!(parsed.source, ChallengeSource::JsonLd);
+        assert_eq!(parsed.challenge.challenge.id.as_deref(), Some("c1"));
+        assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+    }
+
+    #[test]
+    fn every_embedding_parses_to_the_same_challenge() {
+        let json = r#"{"challenge":"abc123","rules":{"difficulty":4,"algorithm":"fast"}}"#;
+        let quoted = json.replace('"', "&quot;");
+        let layouts = [
+            (
+                format!(
+                    r#"<script id="anubis_challenge" type="application/json">{}</script>"#,
+                    json
+                ),
+                ChallengeSource::Element,
+            ),
+            (
+                format!(r#"<div id="anubis_challenge">{}</div>"#, json),
+                ChallengeSource::Element,
+            ),
+            (
+                format!(
+                    r#"<input type="hidden" id="anubis_challenge" value="{}">"#,
+                    quoted
+                ),
+                ChallengeSource::Value,
+            ),
+            (
+                format!(
+                    r#"<body data-challenge="{}"><p>Checking</p></body>"#,
+                    quoted
+                ),
+                ChallengeSource::Attribute("data-challenge"),
+            ),
+            (
+                format!(
+                    r#"<script type="application/ld+json">{{"anubisChallenge":{}}}</script>"#,
+                    json
+                ),
+                ChallengeSource::JsonLd,
+            ),
+        ];
+        for (html, source) in layouts {
+            let html = format!("<html><head></head><body>{}</body></html>", html);
+            let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+            assert_eq!(parsed.source, source, "{}", html);
+            assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+            assert_eq!(parsed.challenge.rules.difficulty, 4);
+            assert_eq!(parsed.challenge.algorithm(), "fast");
+        }
+    }
+
+    #[test]
+    fn challenge_element_takes_precedence_over_other_sources() {
+        let html = format!(
+            r#"<div data-anubis-challenge='{{"challenge":"fromattr","rules":{{"difficulty":4}}}}'></div>{}"#,
+            page_with_challenge(r#"{"challenge":"fromelement","rules":{"difficulty":4}}"#)
+        );
+        let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+        assert_eq!(parsed.source, ChallengeSource::Element);
+        assert_eq!(parsed.challenge.challenge.random_data, "fromelement");
+
+        let html = format!(
+            r#"<div data-anubis-challenge='{{"challenge":"fromattr","rules":{{"difficulty":4}}}}'></div>{}"#,
+            page_with_challenge("{not json")
+        );
+        let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+        assert_eq!(
+            parsed.source,
+            ChallengeSource::Attribute("data-anubis-challenge")
+        );
+        assert_eq!(parsed.challenge.challenge.random_data, "fromattr");
+    }
+
+    #[test]
+    fn inline_format_needs_difficulty_and_data() {
+        for script in [
+            "data:abc123",
+            "difficulty:5",
+            "difficulty:five|data:abc123",
+            "difficulty:5|data:abc123|salt:x",
+        ] {
+            assert!(
+                try_parse_challenge_from_html(&page_with_challenge(script)).is_err(),
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn parses_challenge_with_trailing_semicolon() {
+        let html = page_with_challenge(r#"  {"challenge":"abc123","rules":{"difficulty":4}};  "#);
+        let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+        assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+        assert_eq!(parsed.challenge.rules.difficulty, 4);
+    }
+
+    #[test]
+    fn parses_challenge_wrapped_in_assignment_and_comments() {
+        let html = page_with_challenge(
+            r#"/* {not json} */ var challenge = {"challenge":"ab}c{","rules":{"difficulty":4,"note":"\"}\""}}; // trailing {"#,
+        );
+        let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+        assert_eq!(parsed.challenge.challenge.random_data, "ab}c{");
+        assert_eq!(parsed.challenge.rules.extra["note"], "\"}\"");
+    }
+
+    #[test]
+    fn non_ascii_text_around_the_challenge_is_skipped() {
+        let html = page_with_challenge(
+            r#"var défi = /* é */ {"challenge":"abc123","rules":{"difficulty":4}}; // ü"#,
+        );
+        let parsed = try_parse_challenge_from_html(&html).unwrap().unwrap();
+        assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+        assert_eq!(
+            extract_json_object("é ünïcode {\"a\":\"ß\"} ✓"),
+            Some("{\"a\":\"ß\"}")
+        );
+    }
+
+    #[test]
+    fn unbalanced_challenge_is_still_malformed() {
+        let html = page_with_challenge(r#"{"challenge":"abc123","rules":{"difficulty":4}"#);
+        let err = try_parse_challenge_from_html(&html).unwrap_err();
+        assert!(err.starts_with("Malformed Anubis challenge"));
+    }
+
+    #[test]
+    fn midstate_matches_full_hash() {
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(256).collect();
+        for prefix_len in 0..=200 {
+            let prefix = &bytes[..prefix_len];
+            let midstate = Sha256Midstate::new(prefix);
+            for suffix_len in 0..=Sha256Midstate::MAX_SUFFIX_LEN {
+                let suffix = &bytes[200..200 + suffix_len];
+                let mut full = Sha256::new();
+                full.update(prefix);
+                full.update(suffix);
+                assert_eq!(
+                    midstate.hash(suffix)[..],
+                    full.finalize()[..],
+                    "prefix {} suffix {}",
+                    prefix_len,
+                    suffix_len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_solution_accepts_solver_output() {
+        for &algorithm in supported_algorithms() {
+            let mut challenge = challenge_with_algorithm(algorithm);
+            challenge.rules.difficulty = 2;
+            let options = SolveOptions::default();
+            let result =
+                solve_challenge_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+            assert_eq!(
+                verify_solution_with_options(&challenge, &result, &options),
+                Ok(()),
+                "{}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn concat_orders_and_salts_round_trip_through_verification() {
+        let layouts = [
+            (ConcatOrder::ChallengeThenNonce, None),
+            (ConcatOrder::ChallengeThenNonce, Some("s4lt")),
+            (ConcatOrder::NonceThenChallenge, None),
+            (ConcatOrder::NonceThenChallenge, Some("s4lt")),
+        ];
+        let preimage_answer = SubmissionOptions {
+            answer_format: AnswerFormat::Preimage,
+            ..SubmissionOptions::default()
+        };
+        for (concat_order, salt) in layouts {
+            for algorithm in ["fast", "double_sha256"] {
+                let label = format!("{:?} {:?} {}", concat_order, salt, algorithm);
+                let mut challenge = challenge_with_algorithm(algorithm);
+                challenge.rules.difficulty = 2;
+                let options = SolveOptions {
+                    concat_order,
+                    salt: salt.map(str::to_string),
+                    ..SolveOptions::default()
+                };
+                let result =
+                    solve_challenge_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+                assert_eq!(
+                    verify_solution_with_options(&challenge, &result, &options),
+                    Ok(()),
+                    "{}",
+                    label
+                );
+
+                let nonce = result.nonce_string().unwrap();
+                let preimage = result.preimage().unwrap();
+                let expected = match concat_order {
+                    ConcatOrder::ChallengeThenNonce => format!("abc123{}", nonce),
+                    ConcatOrder::NonceThenChallenge => format!("{}abc123", nonce),
+                } + salt.unwrap_or_default();
+                assert_eq!(preimage, expected, "{}", label);
+                assert_eq!(
+                    response_param(&challenge, &result, &preimage_answer),
+                    hex::encode(&preimage)
+                );
+
+                // Every other solver lays the preimage out the same way.
+                let hashed = solve_challenge_native_with_hasher::<Sha2Hasher, fn(u64)>(
+                    &challenge, None, &options,
+                )
+                .unwrap();
+                let checked =
+                    check_candidates_with_options(&challenge, &[result.nonce.unwrap()], &options)
+                        .unwrap();
+                assert_eq!(checked.hash, result.hash, "{}", label);
+                let many = solve_challenge_many(&challenge, 2, &options).unwrap();
+                for other in many.iter().chain([&hashed]) {
+                    assert_eq!(
+                        verify_solution_with_options(&challenge, other, &options),
+                        Ok(()),
+                        "{}",
+                        label
+                    );
+                }
+            }
+        }
+
+        let long_salt = SolveOptions {
+            salt: Some("x".repeat(MAX_SALT_LEN + 1)),
+            ..SolveOptions::default()
+        };
+        let challenge = challenge_with_algorithm("fast");
+        assert_eq!(
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &long_salt)
+                .unwrap_err(),
+            format!("salt of 32 bytes exceeds the maximum of {}", MAX_SALT_LEN)
+        );
+        // With the nonce first, the salt is part of the fixed data instead.
+        let long_salt = SolveOptions {
+            concat_order: ConcatOrder::NonceThenChallenge,
+            ..long_salt
+        };
+        let result =
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &long_salt).unwrap();
+        assert_eq!(
+            verify_solution_with_options(&challenge, &result, &long_salt),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn stats_count_every_attempt_per_thread() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 4;
+
+        // With one thread the search is sequential from nonce 0.
+        let single = SolveOptions {
+            thread_pool: Some(Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(1)
+                    .build()
+                    .unwrap(),
+            )),
+            ..SolveOptions::default()
+        };
+        let (result, stats) =
+            solve_challenge_native_with_stats::<fn(u64)>(&challenge, None, &single);
+        assert_eq!(stats.attempts, result.unwrap().nonce.unwrap() + 1);
+        assert_eq!(stats.per_thread_attempts, [stats.attempts]);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        challenge.rules.difficulty = 64;
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            max_time: Some(Duration::from_millis(50)),
+            ..SolveOptions::default()
+        };
+        let (result, stats) =
+            solve_challenge_native_with_stats::<fn(u64)>(&challenge, None, &options);
+        assert_eq!(stats.per_thread_attempts.len(), 4);
+        assert_eq!(
+            stats.attempts,
+            stats.per_thread_attempts.iter().sum::<u64>()
+        );
+        assert!(result
+            .unwrap_err()
+            .ends_with(&format!("after {} attempts", stats.attempts)));
+    }
+
+    #[test]
+    fn pinned_threads_still_solve() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            pin_threads: true,
+            ..SolveOptions::default()
+        };
+        let result =
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+        assert_eq!(
+            verify_solution_with_options(&challenge, &result, &options),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn check_candidates_returns_the_first_valid_nonce() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+        let solved = solve_challenge::<fn(u64)>(&challenge, None).unwrap();
+        let valid = solved.nonce.unwrap();
+        let invalid: Vec<u64> = (0..valid)
+            .filter(|&nonce| check_candidates(&challenge, &[nonce]).is_none())
+            .take(3)
+            .collect();
+
+        let mut candidates = invalid.clone();
+        candidates.push(valid);
+        candidates.push(valid + 1);
+        let found = check_candidates(&challenge, &candidates).unwrap();
+        assert_eq!(found.nonce, Some(valid));
+        assert_eq!(found.hash, solved.hash);
+
+        assert!(check_candidates(&challenge, &invalid).is_none());
+        assert!(check_candidates(&challenge, &[]).is_none());
+        assert!(check_candidates(&challenge_with_algorithm("preact"), &[valid]).is_none());
+    }
+
+    #[test]
+    fn hash_twice_matches_double_sha256_vector() {
+        // The Bitcoin wiki's SHA256(SHA256("hello")).
+        assert_eq!(
+            hex::encode(Sha256Midstate::new(b"hel").hash_twice(b"lo")),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+        let prefix = "ab".repeat(70);
+        assert_eq!(
+            Sha256Midstate::new(prefix.as_bytes()).hash_twice(b"12345"),
+            <[u8; 32]>::from(Sha256::digest(Sha256::digest(format!("{}12345", prefix))))
+        );
+    }
+
+    #[test]
+    fn solves_double_sha256_challenges() {
+        for algorithm in ["double_sha256", "sha256d"] {
+            let mut challenge = challenge_with_algorithm(algorithm);
+            challenge.rules.difficulty = 3;
+            let options = SolveOptions::default();
+            let result =
+                solve_challenge_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+
+            let preimage = format!("abc123{}", result.nonce.unwrap());
+            let outer = Sha256::digest(Sha256::digest(preimage.as_bytes()));
+            assert!(check_difficulty_fast(&outer, 3));
+            assert_eq!(result.hash, hex::encode(outer));
+            assert_eq!(
+                verify_solution_with_options(&challenge, &result, &options),
+                Ok(())
+            );
+            assert!(simulate_server_verification(
+                &challenge,
+                &submission_for(&challenge, &result)
+            ));
+
+            // A single SHA-256 solution doesn't pass as a double one.
+            let single = solve_challenge_with_options::<fn(u64)>(
+                &challenge_with_algorithm("fast"),
+                None,
+                &options,
+            )
+            .unwrap();
+            assert!(verify_solution_with_options(&challenge, &single, &options).is_err());
+        }
+    }
+
+    #[test]
+    fn nonce_start_offsets_the_search() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 2;
+        let seeded = SolveOptions {
+            start: "seed:42".parse().unwrap(),
+            ..SolveOptions::default()
+        };
+        let offset = NonceStart::Seeded(42).offset();
+        assert!(offset > 0 && offset < MAX_START_OFFSET);
+        assert_eq!(NonceStart::Seeded(42).offset(), offset);
+        assert_ne!(NonceStart::Seeded(43).offset(), offset);
+        assert_eq!(NonceStart::Zero.offset(), 0);
+
+        let result =
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &seeded).unwrap();
+        assert!(result.nonce.unwrap() >= offset);
+        assert_eq!(
+            verify_solution_with_options(&challenge, &result, &seeded),
+            Ok(())
+        );
+        assert!(simulate_server_verification(
+            &challenge,
+            &submission_for(&challenge, &result)
+        ));
+
+        let mut searcher = NonceSearcher::new(&challenge, &seeded).unwrap();
+        match searcher.next_chunk(1_000_000) {
+            SearchStep::Found(found) => assert!(found.nonce.unwrap() >= offset),
+            other => panic!("expected a solution, got {:?}", other),
+        }
+
+        assert_eq!("random".parse(), Ok(NonceStart::Random));
+        assert!("seed:x".parse::<NonceStart>().is_err());
+    }
+
+    #[test]
+    fn solve_many_finds_distinct_valid_nonces() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 2;
+        let options = SolveOptions::default();
+        let results = solve_challenge_many(&challenge, 5, &options).unwrap();
+        assert_eq!(results.len(), 5);
+        for result in &results {
+            assert_eq!(
+                verify_solution_with_options(&challenge, result, &options),
+                Ok(())
+            );
+        }
+        let mut nonces: Vec<_> = results.iter().map(|result| result.nonce).collect();
+        nonces.dedup();
+        assert_eq!(nonces.len(), 5);
+
+        assert!(solve_challenge_many(&challenge, 0, &options)
+            .unwrap()
+            .is_empty());
+        assert!(solve_challenge_many(&challenge_with_algorithm("preact"), 2, &options).is_err());
+    }
+
+    #[test]
+    fn solve_many_returns_partial_results_on_timeout() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 16;
+        let options = SolveOptions {
+            max_time: Some(Duration::from_millis(50)),
+            ..SolveOptions::default()
+        };
+        let results = solve_challenge_many(&challenge, 3, &options).unwrap();
+        assert!(results.len() < 3);
+    }
+
+    #[test]
+    fn verify_solution_rejects_tampered_results() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 2;
+        let options = SolveOptions::default();
+        let result = solve_challenge_native::<fn(u64)>(&challenge, None).unwrap();
+
+        let mut wrong_nonce = result.clone();
+        wrong_nonce.nonce = wrong_nonce.nonce.map(|nonce| nonce + 1);
+        assert!(verify_solution_with_options(&challenge, &wrong_nonce, &options).is_err());
+
+        let mut no_nonce = result.clone();
+        no_nonce.nonce = None;
+        assert!(verify_solution_with_options(&challenge, &no_nonce, &options).is_err());
+
+        let preact = challenge_with_algorithm("preact");
+        let mut wrong_hash = solve_preact_challenge(&preact);
+        wrong_hash.hash = "0".repeat(64);
+        assert!(verify_solution_with_options(&preact, &wrong_hash, &options).is_err());
+
+        let unknown = challenge_with_algorithm("blake3");
+        assert!(verify_solution_with_options(&unknown, &result, &options).is_err());
+    }
+
+    #[test]
+    fn verify_solution_checks_half_nibbles_and_hash_nonce_pairs() {
+        let mut challenge = challenge_with_algorithm("fast");
+        let options = SolveOptions::default();
+
+        // A nonce whose hash starts with exactly two zero nibbles meets an
+        // even difficulty of 2 but not the odd difficulty 3.
+        let nonce = (0u64..)
+            .find(|nonce| {
+                let hash = hex::encode(Sha256::digest(format!("abc123{}", nonce)));
+                hash.starts_with("00") && !hash.starts_with("000")
+            })
+            .unwrap();
+        let mut result = result_with_hash(&hex::encode(Sha256::digest(format!("abc123{}", nonce))));
+        result.nonce = Some(nonce);
+        challenge.rules.difficulty = 2;
+        assert_eq!(
+            verify_solution_with_options(&challenge, &result, &options),
+            Ok(())
+        );
+        challenge.rules.difficulty = 3;
+        let err = verify_solution_with_options(&challenge, &result, &options).unwrap_err();
+        assert!(err.contains("doesn't meet difficulty 3"), "{}", err);
+
+        // Both nonces meet the difficulty, but each hash belongs to the other.
+        challenge.rules.difficulty = 1;
+        let found = solve_challenge_many(&challenge, 2, &options).unwrap();
+        let mut swapped = found[0].clone();
+        swapped.hash = found[1].hash.clone();
+        let err = verify_solution_with_options(&challenge, &swapped, &options).unwrap_err();
+        assert!(err.contains("doesn't match"), "{}", err);
+
+        let mut malformed = found[0].clone();
+        malformed.hash = "zz".repeat(32);
+        assert!(verify_solution_with_options(&challenge, &malformed, &options).is_err());
+        malformed.hash = "0".to_string();
+        assert!(verify_solution_with_options(&challenge, &malformed, &options).is_err());
+    }
+
+    #[test]
+    fn verify_solution_is_a_yes_or_no_with_default_options() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 2;
+        let result = solve_challenge_native::<fn(u64)>(&challenge, None).unwrap();
+        assert!(verify_solution(&challenge, &result));
+
+        let mut malformed = result.clone();
+        malformed.hash = "zz".repeat(32);
+        assert!(!verify_solution(&challenge, &malformed));
+        malformed.hash = "0".to_string();
+        assert!(!verify_solution(&challenge, &malformed));
+    }
+
+    #[test]
+    fn submission_url_round_trips_through_parse() {
+        let challenge = challenge_with_algorithm("fast");
+        let result = result_with_hash(&"0".repeat(64));
+        let redir = "https://example.com/search?a=b&c=d#top";
+        let url =
+            build_submission_url("https", "example.com", &challenge, &result, redir, 10).unwrap();
+
+        let parsed = parse_submission_url(&url).unwrap();
+        assert_eq!(parsed.scheme, "https");
+        assert_eq!(parsed.host, "example.com");
+        assert_eq!(parsed.path, SUBMISSION_PATH);
+        let names: Vec<&str> = parsed
+            .params
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["response", "nonce", "redir", "elapsedTime", "id"]);
+        assert_eq!(parsed.get("redir"), Some(redir));
+        assert_eq!(parsed.get("nonce"), Some("7"));
+        assert_eq!(parsed.get("id"), Some("x1"));
+        assert!(!url.contains("a=b&c=d"));
+    }
+
+    #[test]
+    fn submission_options_change_path_and_append_params() {
+        let challenge = challenge_with_algorithm("preact");
+        let result = solve_preact_challenge(&challenge);
+        let options = SubmissionOptions {
+            path: "api/pass".to_string(),
+            extra_params: vec![("client".to_string(), "a b&c".to_string())],
+            ..SubmissionOptions::default()
+        };
+        let url = build_submission_url_with_options(
+            "http",
+            "localhost:8080",
+            &challenge,
+            &result,
+            "/",
+            5,
+            &options,
+        )
+        .unwrap();
+
+        let parsed = parse_submission_url(&url).unwrap();
+        assert_eq!(parsed.host, "localhost:8080");
+        assert_eq!(parsed.path, "api/pass");
+        assert_eq!(parsed.get("result"), Some(result.hash.as_str()));
+        assert_eq!(
+            parsed.params.last().unwrap(),
+            &("client".to_string(), "a b&c".to_string())
+        );
+    }
+
+    #[test]
+    fn extra_params_are_encoded_and_do_not_replace_standard_ones() {
+        let challenge = challenge_with_algorithm("fast");
+        let result = solve_challenge::<fn(u64)>(&challenge, None).unwrap();
+        let build = |options: &SubmissionOptions| {
+            build_submission_url_with_options(
+                "https",
+                "example.com",
+                &challenge,
+                &result,
+                "/",
+                5,
+                options,
+            )
+        };
+
+        let mut options = SubmissionOptions {
+            extra_params: vec![
+                ("site key".to_string(), "a&b=c".to_string()),
+                ("v".to_string(), "2".to_string()),
+            ],
+            ..SubmissionOptions::default()
+        };
+        let url = build(&options).unwrap();
+        assert!(url.ends_with("&site%20key=a%26b%3Dc&v=2"), "{}", url);
+
+        options.extra_params = vec![("redir".to_string(), "/other".to_string())];
+        let err = build(&options).unwrap_err();
+        assert!(err.contains("\"redir\""), "{}", err);
+
+        options.override_params = true;
+        let parsed = parse_submission_url(&build(&options).unwrap()).unwrap();
+        assert_eq!(parsed.get("redir"), Some("/other"));
+        assert_eq!(
+            parsed
+                .params
+                .iter()
+                .filter(|(name, _)| name == "redir")
+                .count(),
+            1
+        );
+        assert_eq!(parsed.params[2].0, "redir");
+    }
+
+    #[test]
+    fn redir_and_embedded_values_are_percent_encoded() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.challenge.id = Some("a&b".to_string());
+        let result = result_with_hash(&"0".repeat(64));
+        let url = build_submission_url(
+            "https",
+            "example.com",
+            &challenge,
+            &result,
+            "https://example.com/page?a=b&c=d",
+            10,
+        )
+        .unwrap();
+        assert!(url.contains("&redir=https%3A%2F%2Fexample.com%2Fpage%3Fa%3Db%26c%3Dd&"));
+        assert!(url.ends_with("&id=a%26b"));
+
+        let mut metarefresh = challenge_with_algorithm("metarefresh");
+        metarefresh.challenge.random_data = "x y&z".to_string();
+        let result = solve_metarefresh_challenge(&metarefresh);
+        let url = submission_url(&metarefresh, &result.hash).unwrap();
+        assert_eq!(
+            parse_submission_url(&url).unwrap().get("challenge"),
+            Some("x y&z")
+        );
+    }
+
+    #[test]
+    fn sha_detection_is_consistent() {
+        // Must not panic on any architecture, and the backend can only use
+        // instructions the CPU has.
+        let cpu = cpu_has_sha_extensions();
+        assert!(!sha_hardware_accelerated() || cpu);
+    }
+
+    #[test]
+    fn solves_inside_a_custom_thread_pool() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("test-solver-{}", i))
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            ..SolveOptions::default()
+        };
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 3;
+
+        // Threads 0 and 1 report progress on their first nonce.
+        let threads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = threads.clone();
+        let callback = move |_nonce: u64| {
+            let name = std::thread::current().name().map(str::to_string);
+            seen.lock().unwrap().push(name);
+        };
+        let result =
+            solve_challenge_native_with_options(&challenge, Some(callback), &options).unwrap();
+
+        assert_eq!(
+            verify_solution_with_options(&challenge, &result, &options),
+            Ok(())
+        );
+        let threads = threads.lock().unwrap();
+        assert!(!threads.is_empty());
+        assert!(threads.iter().all(|name| name
+            .as_deref()
+            .is_some_and(|n| n.starts_with("test-solver-"))));
+    }
+
+    #[test]
+    fn malformed_selector_is_a_clean_error() {
+        let err = ChallengeSelectors::new("#challenge[", "#anubis_version").unwrap_err();
+        assert!(
+            err.starts_with("invalid selector \"#challenge[\": "),
+            "{}",
+            err
+        );
+        assert!(!err.contains("SelectorErrorKind"), "{}", err);
+    }
+
+    #[test]
+    fn custom_selectors_find_the_challenge() {
+        let html = r#"<script class="pow">{"challenge":"abc123","rules":{"difficulty":4}}</script>
+            <script class="pow-version">"1.2.3"</script>"#;
+        let selectors = ChallengeSelectors::new("script.pow", "script.pow-version").unwrap();
+        let parsed = try_parse_challenge_from_html_with_selectors(html, &selectors)
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.challenge.challenge.random_data, "abc123");
+        assert_eq!(parsed.version, "1.2.3");
+        assert!(try_parse_challenge_from_html(html).unwrap().is_none());
+    }
+
+    #[test]
+    fn max_time_stops_an_unsolvable_search() {
+        let mut challenge = challenge_with_algorithm("fast");
+        challenge.rules.difficulty = 64;
+        let options = SolveOptions {
+            max_time: Some(Duration::from_millis(50)),
+            ..SolveOptions::default()
+        };
+
+        let start = Instant::now();
+        let err =
+            solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.starts_with("Time limit of 50ms exceeded after "),
+            "{}",
+            err
+        );
+        let attempts: u64 = err
+            .trim_end_matches(" attempts")
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(attempts >= CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn simultaneous_solutions_return_a_matching_pair() {
+        // At difficulty 1 about one hash in 16 passes, so several threads
+        // regularly find a solution in the same instant.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap();
+        let options = SolveOptions {
+            thread_pool: Some(Arc::new(pool)),
+            ..SolveOptions::default()
+        };
+        let mut challenge = challenge_with_algorithm("fast");
+        for round in 0..500 {
+            challenge.challenge.random_data = format!("stress-{}", round);
+            let result =
+                solve_challenge_native_with_options::<fn(u64)>(&challenge, None, &options).unwrap();
+            assert_eq!(
+                verify_solution_with_options(&challenge, &result, &options),
+                Ok(())
+            );
+        }
+    }
+
+    fn response_param(
+        challenge: &AnubisChallenge,
+        result: &SolverResult,
+        options: &SubmissionOptions,
+    ) -> String {
+        let url = build_submission_url_with_options(
+            "https",
+            "example.com",
+            challenge,
+            result,
+            "/",
+            10,
+            options,
+        )
+        .unwrap();
+        parse_submission_url(&url)
+            .unwrap()
+            .get("response")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn answer_formats_shape_the_response_param() {
+        let challenge = challenge_with_algorithm("fast");
+        let result = result_with_hash(&"ab".repeat(32));
+        let with_format = |answer_format| SubmissionOptions {
+            answer_format,
+            ..SubmissionOptions::default()
+        };
+
+        assert_eq!(
+            response_param(&challenge, &result, &with_format(AnswerFormat::Hash)),
+            "ab".repeat(32)
+        );
+        assert_eq!(
+            response_param(
+                &challenge,
+                &result,
+                &with_format(AnswerFormat::HashAndNonce)
+            ),
+            format!("{}:7", "ab".repeat(32))
+        );
+        // "abc123" followed by nonce "7".
+        assert_eq!(
+            response_param(&challenge, &result, &with_format(AnswerFormat::Preimage)),
+            "61626331323337"
+        );
+        assert!("hash+nonce".parse::<AnswerFormat>().is_err());
+        assert_eq!("hash:nonce".parse(), Ok(AnswerFormat::HashAndNonce));
+    }
+
+    #[test]
+    fn answer_format_follows_server_version() {
+        let options = SubmissionOptions {
+            version_answer_formats: vec![
+                ("v1.2".to_string(), AnswerFormat::HashAndNonce),
+                ("v1".to_string(), AnswerFormat::Preimage),
+            ],
+            ..SubmissionOptions::default()
+        };
+        assert_eq!(
+            options.for_version("v1.2.0").answer_format,
+            AnswerFormat::HashAndNonce
+        );
+        assert_eq!(
+            options.for_version("v1.9.1").answer_format,
+            AnswerFormat::Preimage
+        );
+        assert_eq!(
+            options.for_version("unknown").answer_format,
+            AnswerFormat::Hash
+        );
+    }
+
+    #[test]
+    fn rejects_challenge_missing_required_fields() {
+        assert!(serde_json::from_str::<AnubisChallenge>(r#"{"challenge":"abc"}"#).is_err());
+        assert!(
+            serde_json::from_str::<AnubisChallenge>(r#"{"challenge":"abc","rules":{}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn expected_solve_time_scales_with_difficulty() {
+        assert_eq!(expected_solve_time(4, 65536.0), Duration::from_secs(1));
+        assert_eq!(expected_solve_time(5, 65536.0), Duration::from_secs(16));
+        assert_eq!(expected_solve_time(64, 1.0), Duration::MAX);
+        assert_eq!(expected_solve_time(1, 0.0), Duration::MAX);
+        assert!(measure_hashrate(Duration::from_millis(5)) > 0.0);
+    }
+
+    #[test]
+    fn remaining_probability_matches_hand_computed_values() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+        assert_eq!(remaining_probability(4, 0), 1.0);
+        assert_eq!(remaining_probability(0, 1), 0.0);
+        // (15/16)^16
+        assert!(close(remaining_probability(1, 16), 0.356_074_130_451_792_8));
+        // (255/256)^256
+        assert!(close(
+            remaining_probability(2, 256),
+            0.367_159_754_891_536_2
+        ));
+        // (1 - 16^-4)^(4 * 16^4), close to e^-4
+        assert!(close(
+            remaining_probability(4, 4 << 16),
+            0.018_315_079_942_636_5
+        ));
+        assert!(remaining_probability(64, u64::MAX) > 0.999_999);
+    }
 }
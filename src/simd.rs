@@ -0,0 +1,451 @@
+//! Eight nonces per SHA-256 compression with AVX2, for the `simd` feature.
+//!
+//! Each 32-bit lane of a `__m256i` holds the same word of a different
+//! message, so one pass through the rounds hashes eight `prefix + nonce`
+//! candidates. All lanes start from the shared [`Sha256Midstate`] state, so
+//! only the final block(s) are hashed eight wide.
+//!
+//! AVX2 is detected at runtime; without it everything here falls back to the
+//! scalar [`Sha256Midstate::hash`].
+
+use super::{check_difficulty_fast, Batch, NonceEncoder, NonceFormat, Sha256Midstate};
+use std::arch::x86_64::*;
+
+/// Messages hashed per compression.
+pub const LANES: usize = 8;
+
+/// One word of every lane's message block, `[word][lane]`.
+type LaneBlock = [[u32; LANES]; 16];
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Whether this CPU can run the eight-lane path.
+pub fn available() -> bool {
+    is_x86_feature_detected!("avx2")
+}
+
+/// Whether PoW searches should use the eight-lane path. On CPUs with SHA
+/// extensions the scalar path runs on them and is faster; see
+/// benches/hasher.rs.
+pub(crate) fn preferred() -> bool {
+    available() && !super::sha_hardware_accelerated()
+}
+
+impl Sha256Midstate {
+    /// [`hash`](Self::hash) of eight suffixes at once, eight wide when AVX2
+    /// is available. Each suffix must be at most
+    /// [`MAX_SUFFIX_LEN`](Self::MAX_SUFFIX_LEN) bytes.
+    pub fn hash_x8(&self, suffixes: [&[u8]; LANES]) -> [[u8; 32]; LANES] {
+        let mut blocks = [[[0u32; LANES]; 16]; 2];
+        let mut block_counts = [0; LANES];
+        for (lane, suffix) in suffixes.iter().enumerate() {
+            block_counts[lane] = self.fill_lane(&mut blocks, lane, suffix);
+        }
+        if available() && block_counts.iter().all(|&count| count == block_counts[0]) {
+            // SAFETY: AVX2 is available.
+            unsafe { self.hash_lanes::<false>(&blocks[..block_counts[0]]) }
+        } else {
+            suffixes.map(|suffix| self.hash(suffix))
+        }
+    }
+
+    /// Writes the final block(s) of `prefix + suffix` into `lane` of
+    /// `blocks`, returning how many there are.
+    #[inline]
+    fn fill_lane(&self, blocks: &mut [LaneBlock; 2], lane: usize, suffix: &[u8]) -> usize {
+        assert!(suffix.len() <= Self::MAX_SUFFIX_LEN, "suffix too long");
+
+        let mut bytes = [0u8; 128];
+        bytes[..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+        let end = self.tail_len + suffix.len();
+        bytes[self.tail_len..end].copy_from_slice(suffix);
+        bytes[end] = 0x80;
+        let block_count = if end + 9 <= 64 { 1 } else { 2 };
+        let bit_len = (self.prefix_len + suffix.len() as u64) * 8;
+        bytes[block_count * 64 - 8..block_count * 64].copy_from_slice(&bit_len.to_be_bytes());
+
+        for (i, word) in bytes[..block_count * 64].chunks_exact(4).enumerate() {
+            blocks[i / 16][i % 16][lane] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        block_count
+    }
+
+    /// Digests of the lanes of `blocks`, hashed again when `DOUBLE`.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support AVX2.
+    #[target_feature(enable = "avx2")]
+    unsafe fn hash_lanes<const DOUBLE: bool>(&self, blocks: &[LaneBlock]) -> [[u8; 32]; LANES] {
+        let mut state = self.state.map(|word| _mm256_set1_epi32(word as i32));
+        for block in blocks {
+            let words = block.map(|word| _mm256_loadu_si256(word.as_ptr().cast()));
+            compress(&mut state, &words);
+        }
+
+        if DOUBLE {
+            // The inner digest's words are the outer block's first eight.
+            let mut words = [_mm256_setzero_si256(); 16];
+            words[..8].copy_from_slice(&state);
+            words[8] = _mm256_set1_epi32(0x8000_0000u32 as i32);
+            words[15] = _mm256_set1_epi32(256);
+            state = Sha256Midstate::INITIAL_STATE.map(|word| _mm256_set1_epi32(word as i32));
+            compress(&mut state, &words);
+        }
+
+        let mut words = [[0u32; LANES]; 8];
+        for (word, vector) in words.iter_mut().zip(state) {
+            _mm256_storeu_si256(word.as_mut_ptr().cast(), vector);
+        }
+        let mut digests = [[0u8; 32]; LANES];
+        for (lane, digest) in digests.iter_mut().enumerate() {
+            for (chunk, word) in digest.chunks_exact_mut(4).zip(&words) {
+                chunk.copy_from_slice(&word[lane].to_be_bytes());
+            }
+        }
+        digests
+    }
+}
+
+macro_rules! rotr {
+    ($x:expr, $n:literal) => {
+        _mm256_or_si256(
+            _mm256_srli_epi32::<$n>($x),
+            _mm256_slli_epi32::<{ 32 - $n }>($x),
+        )
+    };
+}
+
+/// The SHA-256 compression function on eight states at once.
+#[target_feature(enable = "avx2")]
+unsafe fn compress(state: &mut [__m256i; 8], block: &[__m256i; 16]) {
+    let mut w = [_mm256_setzero_si256(); 64];
+    w[..16].copy_from_slice(block);
+    for i in 16..64 {
+        let s0 = _mm256_xor_si256(
+            _mm256_xor_si256(rotr!(w[i - 15], 7), rotr!(w[i - 15], 18)),
+            _mm256_srli_epi32::<3>(w[i - 15]),
+        );
+        let s1 = _mm256_xor_si256(
+            _mm256_xor_si256(rotr!(w[i - 2], 17), rotr!(w[i - 2], 19)),
+            _mm256_srli_epi32::<10>(w[i - 2]),
+        );
+        w[i] = _mm256_add_epi32(
+            _mm256_add_epi32(w[i - 16], s0),
+            _mm256_add_epi32(w[i - 7], s1),
+        );
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = _mm256_xor_si256(_mm256_xor_si256(rotr!(e, 6), rotr!(e, 11)), rotr!(e, 25));
+        let ch = _mm256_xor_si256(_mm256_and_si256(e, f), _mm256_andnot_si256(e, g));
+        let t1 = _mm256_add_epi32(
+            _mm256_add_epi32(_mm256_add_epi32(h, s1), _mm256_add_epi32(ch, w[i])),
+            _mm256_set1_epi32(K[i] as i32),
+        );
+        let s0 = _mm256_xor_si256(_mm256_xor_si256(rotr!(a, 2), rotr!(a, 13)), rotr!(a, 22));
+        let maj = _mm256_or_si256(
+            _mm256_and_si256(a, b),
+            _mm256_and_si256(c, _mm256_or_si256(a, b)),
+        );
+        let t2 = _mm256_add_epi32(s0, maj);
+        h = g;
+        g = f;
+        f = e;
+        e = _mm256_add_epi32(d, t1);
+        d = c;
+        c = b;
+        b = a;
+        a = _mm256_add_epi32(t1, t2);
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = _mm256_add_epi32(*word, value);
+    }
+}
+
+/// [`super::search_batch`] eight nonces at a time. Groups whose lanes need
+/// different block counts (where the nonce grows a digit across a block
+/// boundary), the last few nonces of a batch and CPUs without AVX2 go
+/// through the scalar loop, so results and attempt counts match it exactly.
+pub(crate) fn search_batch<const DOUBLE: bool>(
+    hasher: &Sha256Midstate,
+    encoder: &mut NonceEncoder,
+    nonce_format: NonceFormat,
+    difficulty: usize,
+    nonce: &mut u64,
+    step: u64,
+    limit: u64,
+) -> Batch {
+    let scalar = super::search_batch::<DOUBLE>;
+    if !available() {
+        return scalar(
+            hasher,
+            encoder,
+            nonce_format,
+            difficulty,
+            nonce,
+            step,
+            limit,
+        );
+    }
+
+    let lanes = LANES as u64;
+    let mut tried = 0;
+    while limit - tried >= lanes {
+        let Some(last) = step
+            .checked_mul(lanes - 1)
+            .and_then(|span| nonce.checked_add(span))
+        else {
+            break;
+        };
+
+        let mut blocks = [[[0u32; LANES]; 16]; 2];
+        let mut block_counts = [0; LANES];
+        for (lane, block_count) in block_counts.iter_mut().enumerate() {
+            let suffix = encoder.encode(nonce_format, *nonce + lane as u64 * step);
+            *block_count = hasher.fill_lane(&mut blocks, lane, suffix);
+        }
+
+        if block_counts.iter().any(|&count| count != block_counts[0]) {
+            match scalar(
+                hasher,
+                encoder,
+                nonce_format,
+                difficulty,
+                nonce,
+                step,
+                lanes,
+            ) {
+                Batch::Exhausted => {}
+                Batch::Found { tried: t, hash } => {
+                    return Batch::Found {
+                        tried: tried + t,
+                        hash,
+                    }
+                }
+                Batch::Overflow { tried: t } => return Batch::Overflow { tried: tried + t },
+            }
+            tried += lanes;
+            continue;
+        }
+
+        // SAFETY: AVX2 is available.
+        let digests = unsafe { hasher.hash_lanes::<DOUBLE>(&blocks[..block_counts[0]]) };
+        for (lane, hash) in digests.into_iter().enumerate() {
+            if check_difficulty_fast(&hash, difficulty) {
+                *nonce += lane as u64 * step;
+                return Batch::Found {
+                    tried: tried + lane as u64 + 1,
+                    hash,
+                };
+            }
+        }
+
+        tried += lanes;
+        match last.checked_add(step) {
+            Some(next_nonce) => *nonce = next_nonce,
+            None => return Batch::Overflow { tried },
+        }
+    }
+
+    match scalar(
+        hasher,
+        encoder,
+        nonce_format,
+        difficulty,
+        nonce,
+        step,
+        limit - tried,
+    ) {
+        Batch::Exhausted => Batch::Exhausted,
+        Batch::Found { tried: t, hash } => Batch::Found {
+            tried: tried + t,
+            hash,
+        },
+        Batch::Overflow { tried: t } => Batch::Overflow { tried: tried + t },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{solve_challenge_sequential, AnubisChallenge};
+
+    #[test]
+    fn lanes_match_scalar_hashes() {
+        // Prefix tails of every length, with suffix lengths on both sides of
+        // the one/two block boundary.
+        for prefix_len in [0, 1, 31, 55, 56, 63, 64, 100, 128] {
+            let prefix = vec![b'p'; prefix_len];
+            let midstate = Sha256Midstate::new(&prefix);
+            for first_len in 0..=Sha256Midstate::MAX_SUFFIX_LEN - LANES {
+                let suffixes: Vec<Vec<u8>> = (0..LANES)
+                    .map(|lane| vec![b'0' + lane as u8; first_len])
+                    .collect();
+                let suffixes: [&[u8]; LANES] =
+                    std::array::from_fn(|lane| suffixes[lane].as_slice());
+                let expected = suffixes.map(|suffix| midstate.hash(suffix));
+                assert_eq!(
+                    midstate.hash_x8(suffixes),
+                    expected,
+                    "{} {}",
+                    prefix_len,
+                    first_len
+                );
+
+                let suffixes: Vec<Vec<u8>> = (0..LANES)
+                    .map(|lane| vec![b'a' + lane as u8; first_len + lane])
+                    .collect();
+                let suffixes: [&[u8]; LANES] =
+                    std::array::from_fn(|lane| suffixes[lane].as_slice());
+                let expected = suffixes.map(|suffix| midstate.hash(suffix));
+                assert_eq!(
+                    midstate.hash_x8(suffixes),
+                    expected,
+                    "{} {}",
+                    prefix_len,
+                    first_len
+                );
+            }
+        }
+    }
+
+    fn challenge(data: &str, difficulty: usize, algorithm: &str) -> AnubisChallenge {
+        let json = format!(
+            r#"{{"challenge":"{}","rules":{{"difficulty":{},"algorithm":"{}"}}}}"#,
+            data, difficulty, algorithm
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    /// Runs `search` from nonce 0 in steps of 1 until it finds a nonce.
+    fn smallest_nonce(
+        search: crate::BatchSearcher,
+        challenge: &AnubisChallenge,
+        limit: u64,
+    ) -> (u64, u64) {
+        let hasher = Sha256Midstate::new(challenge.challenge.random_data.as_bytes());
+        let mut encoder = NonceEncoder::default();
+        let mut nonce = 0;
+        let mut attempts = 0;
+        loop {
+            match search(
+                &hasher,
+                &mut encoder,
+                NonceFormat::Decimal,
+                challenge.rules.difficulty,
+                &mut nonce,
+                1,
+                limit,
+            ) {
+                Batch::Exhausted => attempts += limit,
+                Batch::Found { tried, .. } => return (nonce, attempts + tried),
+                Batch::Overflow { .. } => panic!("nonce space exhausted"),
+            }
+        }
+    }
+
+    #[test]
+    fn batched_search_finds_the_sequential_solvers_nonce() {
+        if !available() {
+            return;
+        }
+        // A 51 byte prefix moves to two blocks when the nonce reaches 5
+        // digits, so groups straddling 9999 mix block counts. Odd batch
+        // limits leave lanes over for the scalar tail.
+        let prefix = "0123456789".repeat(6)[..51].to_string();
+        for (data, difficulty) in [("abc123", 3), ("abc123", 4), (prefix.as_str(), 4)] {
+            let challenge = challenge(data, difficulty, "fast");
+            let sequential = solve_challenge_sequential(&challenge).unwrap();
+            let nonce = sequential.nonce.unwrap();
+            for limit in [1, 7, 8, 13, 1 << 14] {
+                assert_eq!(
+                    smallest_nonce(search_batch::<false>, &challenge, limit),
+                    (nonce, nonce + 1),
+                    "{} {} {}",
+                    data,
+                    difficulty,
+                    limit
+                );
+            }
+        }
+
+        let challenge = challenge("abc123", 3, "double_sha256");
+        let nonce = solve_challenge_sequential(&challenge)
+            .unwrap()
+            .nonce
+            .unwrap();
+        assert_eq!(
+            smallest_nonce(search_batch::<true>, &challenge, 1 << 14),
+            (nonce, nonce + 1)
+        );
+    }
+
+    #[test]
+    fn batched_search_matches_scalar_across_digit_boundaries() {
+        if !available() {
+            return;
+        }
+        // With a 50 byte prefix, 6 digit nonces need a second block.
+        let data = "0123456789".repeat(6)[..50].to_string();
+        let hasher = Sha256Midstate::new(data.as_bytes());
+        let mut encoder = NonceEncoder::default();
+        // About one hash in 16 meets difficulty 1, so a run of 64 nonces
+        // usually stops at one.
+        for start in 999_990..1_000_010 {
+            let (mut simd_nonce, mut scalar_nonce) = (start, start);
+            let batched = search_batch::<false>(
+                &hasher,
+                &mut encoder,
+                NonceFormat::Decimal,
+                1,
+                &mut simd_nonce,
+                3,
+                64,
+            );
+            let scalar = crate::search_batch::<false>(
+                &hasher,
+                &mut encoder,
+                NonceFormat::Decimal,
+                1,
+                &mut scalar_nonce,
+                3,
+                64,
+            );
+            assert_eq!(simd_nonce, scalar_nonce, "{}", start);
+            match (batched, scalar) {
+                (Batch::Found { tried: a, hash: x }, Batch::Found { tried: b, hash: y }) => {
+                    assert_eq!((a, x), (b, y))
+                }
+                (Batch::Exhausted, Batch::Exhausted) => {}
+                _ => panic!("batched and scalar searches disagree from {}", start),
+            }
+        }
+
+        // Near the end of the nonce space the search stops where scalar does.
+        let mut nonce = u64::MAX - 20;
+        assert!(matches!(
+            search_batch::<false>(
+                &hasher,
+                &mut encoder,
+                NonceFormat::Decimal,
+                64,
+                &mut nonce,
+                1,
+                1 << 14,
+            ),
+            Batch::Overflow { tried: 21 }
+        ));
+    }
+}